@@ -0,0 +1,78 @@
+use serde_json::{Map, Value};
+
+use crate::api::{Aggregate, AggregateSpec, MergeMode};
+
+// parts are keyed by request id, or position, and only there for requests which succeeded
+pub fn aggregate<'a>(spec: &AggregateSpec, parts: impl Iterator<Item = (String, Option<&'a str>)>) -> Aggregate {
+    let mut document = match spec.merge {
+        MergeMode::ByKey | MergeMode::DeepMerge => Value::Object(Map::new()),
+        MergeMode::Concat => Value::Array(vec![]),
+    };
+    let mut missing = vec![];
+
+    for (key, body) in parts {
+        let part = body
+            .and_then(|body| serde_json::from_str::<Value>(body).ok())
+            .and_then(|mut doc| doc.pointer_mut(&spec.pointer).map(Value::take));
+
+        match (spec.merge, part, &mut document) {
+            (MergeMode::ByKey, Some(part), Value::Object(map)) => {
+                map.insert(key, part);
+            }
+            (MergeMode::DeepMerge, Some(part @ Value::Object(_)), document) => deep_merge(document, part),
+            (MergeMode::Concat, Some(Value::Array(items)), Value::Array(all)) => all.extend(items),
+            _ => missing.push(key),
+        }
+    }
+
+    Aggregate { document, missing }
+}
+
+// objects are merged recursively, anything else is replaced by the later value
+fn deep_merge(target: &mut Value, source: Value) {
+    match (target, source) {
+        (Value::Object(target), Value::Object(source)) => {
+            for (name, value) in source {
+                deep_merge(target.entry(name).or_insert(Value::Null), value);
+            }
+        }
+        (target, source) => *target = source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::aggregate::aggregate;
+    use crate::api::{AggregateSpec, MergeMode};
+
+    fn parts<'a>(bodies: &[(&str, Option<&'a str>)]) -> impl Iterator<Item = (String, Option<&'a str>)> {
+        bodies.iter().map(|(key, body)| (key.to_string(), *body)).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn merges_responses() {
+        let bodies = [
+            ("user", Some(r#"{"data": {"name": "octo", "roles": ["a"], "meta": {"v": 1}}}"#)),
+            ("1", Some(r#"{"data": {"roles": ["b"], "meta": {"w": 2}}}"#)),
+            ("orders", None),
+            ("3", Some("<html>")),
+        ];
+        let spec = |merge, pointer: &str| AggregateSpec { merge, pointer: pointer.to_string() };
+
+        let by_key = aggregate(&spec(MergeMode::ByKey, "/data/roles"), parts(&bodies));
+        assert_eq!(by_key.document, json!({"user": ["a"], "1": ["b"]}));
+        assert_eq!(by_key.missing, vec!["orders", "3"]);
+
+        let merged = aggregate(&spec(MergeMode::DeepMerge, ""), parts(&bodies));
+        assert_eq!(merged.document, json!({"data": {"name": "octo", "roles": ["b"], "meta": {"v": 1, "w": 2}}}));
+
+        let concatenated = aggregate(&spec(MergeMode::Concat, "/data/roles"), parts(&bodies));
+        assert_eq!(concatenated.document, json!(["a", "b"]));
+
+        let not_arrays = aggregate(&spec(MergeMode::Concat, "/data"), parts(&bodies));
+        assert_eq!(not_arrays.document, json!([]));
+        assert_eq!(not_arrays.missing, vec!["user", "1", "orders", "3"]);
+    }
+}
@@ -1,36 +1,263 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::time::Duration;
 
 use http::{HeaderMap, HeaderValue};
 use serde::{Serialize, Serializer};
+use serde_json::{Map, Value};
+use serde_json::value::RawValue;
 use serde::ser::SerializeMap;
 use strum_macros::AsRefStr;
 
 // XXX using String probably causes a copy, use Cow or &str
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct OctoplexRequest {
     #[serde(with = "serde_millis")]
     pub timeout_msec: Duration,
+    #[serde(default)]
+    pub execution: ExecutionMode,
+    pub max_concurrency: Option<usize>, // unlimited if not set
+    #[serde(default)]
+    pub parse_json: bool, // JSON responses are embedded as json instead of content
+    pub aggregate: Option<AggregateSpec>, // combines the JSON responses into one document
+    pub template: Option<RequestTemplate>,
+    pub callback: Option<Callback>, // receives the response once the batch has finished
+    #[serde(default)]
     pub requests: Vec<SingleHttpRequest>,
+    pub for_each: Option<ForEach>, // expanded into requests after the listed ones
+    pub cookie_jar: Option<CookieJarSpec>, // cookies set by responses are sent with later requests of the batch
 }
 
-#[derive(Debug, Deserialize)]
+impl OctoplexRequest {
+    // as it will be once for_each has been expanded
+    pub fn request_count(&self) -> usize {
+        self.requests.len() + self.for_each.as_ref().map_or(0, |for_each| for_each.params.len())
+    }
+
+    // what the batch counts against the request quota of its client, with every page a request may follow,
+    // and every redirect each page may follow
+    pub fn quota_cost(&self) -> usize {
+        let listed = self.requests.iter()
+            .map(|request| {
+                let pages = request.paginate.as_ref().map_or(1, |spec| spec.max_pages);
+                let hops = request.follow_redirects.as_ref().map_or(0, |policy| policy.max_hops);
+                pages.saturating_mul(hops.saturating_add(1))
+            })
+            .fold(0, usize::saturating_add);
+        let expanded = self.for_each.as_ref().map_or(0, |for_each| {
+            // invalid limits fail the request, which is only expanded later
+            let limit = |spec: &str, name: &str, default: usize| for_each.request.get(spec)
+                .map(|spec| match spec.get(name) {
+                    Some(limit) => limit.as_u64()
+                        .and_then(|limit| usize::try_from(limit).ok())
+                        .unwrap_or(usize::MAX),
+                    None => default,
+                });
+            let pages = limit("paginate", "max_pages", default_max_pages()).unwrap_or(1);
+            let hops = limit("follow_redirects", "max_hops", default_max_hops()).unwrap_or(0);
+            for_each.params.len().saturating_mul(pages).saturating_mul(hops.saturating_add(1))
+        });
+
+        listed.saturating_add(expanded)
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CookieJarSpec {
+    #[serde(default)]
+    pub return_jar: bool, // the cookies are in the response, as they are after the last request
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForEach {
+    pub request: Value, // a request with {{name}} placeholders for the parameters in its strings
+    pub params: Vec<Map<String, Value>>, // one request each, in this order
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Callback {
+    pub uri: String,
+    pub secret: Option<String>, // for signing the payload with HMAC-SHA256
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateSpec {
+    pub merge: MergeMode,
+    #[serde(default)]
+    pub pointer: String, // JSON Pointer to the part of every response, the whole body if empty
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeMode {
+    ByKey, // under the request id, or the position of requests without one
+    DeepMerge, // objects only
+    Concat, // arrays only
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionMode {
+    #[default]
+    Parallel,
+    Sequential, // in batch order, as far as dependencies allow
+    SequentialStopOnFailure,
+}
+
+// defaults shared by all requests of a batch, so that each request only has to carry the diff
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestTemplate {
+    pub method: Option<HttpMethod>,
+    pub uri_base: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct SingleHttpRequest {
+    pub id: Option<String>, // for other requests to depend on
     #[serde(default)]
-    pub method: HttpMethod,
+    pub depends_on: Vec<String>, // ids of requests whose responses have to be there first
+    pub method: Option<HttpMethod>,
     pub uri: String,
     #[serde(default)]
     pub headers: HashMap<String, String>,
     pub body: Option<String>,
+    pub body_patch: Option<Value>, // JSON merge patch (RFC 7386) applied to the template body
+    pub json: Option<Value>, // serialized as the body
+    pub form: Option<BTreeMap<String, String>>, // url-encoded as the body
+    pub multipart: Option<Vec<MultipartPart>>, // sent as multipart/form-data
+    #[serde(default)]
+    pub query: BTreeMap<String, String>, // merged into the query of the uri
+    pub parse_json: Option<bool>, // overrides parse_json of the batch
+    pub extract: Option<ExtractSpec>, // the response carries only these instead of the whole body
+    pub expect: Option<ExpectSpec>, // a response which does not meet these is a failure
+    pub paginate: Option<PaginateSpec>, // all pages are fetched into one response
+    pub follow_redirects: Option<RedirectPolicy>, // redirects are returned as they are, if not set
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtractSpec {
+    #[serde(default)]
+    pub values: BTreeMap<String, String>, // JSON Pointer or JSONPath, by name
+    pub headers: Option<Vec<String>>, // all headers are kept, if not set
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectSpec {
+    #[serde(default)]
+    pub status: Vec<StatusSpec>, // below 400, if empty
+    #[serde(default)]
+    pub headers: Vec<String>, // have to be present
+    pub body_regex: Option<String>,
+    #[serde(default)]
+    pub json: BTreeMap<String, Value>, // the values at these JSON Pointers
+    pub schema: Option<Value>, // JSON Schema the body has to be valid against
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PaginateSpec {
+    pub follow: PageFollowing,
+    #[serde(default = "default_max_pages")]
+    pub max_pages: usize,
+    pub items: Option<String>, // JSON Pointer to the items of every page, which are merged instead of the pages
+}
+
+fn default_max_pages() -> usize {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectPolicy {
+    #[serde(default = "default_max_hops")]
+    pub max_hops: usize,
+    #[serde(default)]
+    pub same_origin: bool, // a redirect to another origin is returned as it is
+}
+
+fn default_max_hops() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PageFollowing {
+    Link, // the rel="next" uri of the Link header
+    NextUri(String), // JSON Pointer to the uri of the next page
+    Param {
+        name: String, // set in the query, e.g. page or offset
+        #[serde(default = "default_page_start")]
+        start: u64,
+        #[serde(default = "default_page_step")]
+        step: u64,
+    },
+}
+
+fn default_page_start() -> u64 {
+    1
+}
+
+fn default_page_step() -> u64 {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StatusSpec {
+    Code(u16),
+    Range(String), // 2xx or 200-204
+}
+
+// exactly one of text and base64 is the content of the part
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MultipartPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>, // application/octet-stream for files, if not set
+    pub text: Option<String>,
+    pub base64: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct OctoplexResponse {
     pub responses: Vec<SingleOutcome>, // same order and count as requests!
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<Aggregate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cookies: Option<Vec<Cookie>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub path: String,
+    pub host_only: bool, // not sent to subdomains
+    pub secure: bool, // https only
+    pub http_only: bool,
+    pub expires: Option<u64>, // unix seconds, at the end of the batch if not set
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub document: Value,
+    pub missing: Vec<String>, // keys of the requests which failed or had no matching JSON
 }
 
 #[derive(Debug, Serialize)]
@@ -43,6 +270,107 @@ pub struct HealthResponse {
     pub healthy: bool,
 }
 
+#[derive(Debug, Serialize)]
+pub struct StatsResponse {
+    pub circuits: Vec<CircuitStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CircuitStats {
+    pub origin: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub window_requests: u32,
+    pub window_failures: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, AsRefStr, Serialize)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamHealthResponse {
+    pub upstreams: Vec<UpstreamHealth>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamHealth {
+    pub name: String,
+    pub members: Vec<MemberHealth>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemberHealth {
+    pub uri: String,
+    pub healthy: bool,
+    pub in_flight: usize,
+    pub consecutive_failures: u32,
+    #[serde(with = "serde_millis")]
+    pub latency_msec: Option<Duration>, // of the last successful probe
+    pub error: Option<String>, // of the last failed probe
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChecksResponse {
+    pub checks: Vec<CheckStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckStatus {
+    pub name: String,
+    pub schedule: String,
+    pub runs: u64,
+    pub failures: u64,
+    pub results: Vec<CheckResult>, // the most recent last
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub started_unix_msec: u64,
+    pub success: bool,
+    #[serde(with = "serde_millis")]
+    pub duration_msec: Duration,
+    pub errors: Vec<String>, // why the check failed
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecipesResponse {
+    pub recipes: Vec<RecipeInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecipeInfo {
+    pub name: String,
+    pub params: Vec<String>,
+    pub template: Value,
+}
+
+// SingleOutcome is kept as JSON, the same way for running, finished and restored jobs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobResponse {
+    pub id: String,
+    pub state: JobState,
+    pub completed: usize,
+    pub total: usize,
+    pub responses: Vec<Option<Value>>, // same order and count as requests, null until done
+    pub error: Option<String>, // when the batch as a whole failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<Aggregate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cookies: Option<Vec<Cookie>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, AsRefStr, Serialize, Deserialize)]
+pub enum JobState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
 #[derive(Debug, AsRefStr, Serialize)]
 pub enum SingleOutcome {
     Failure(SingleHttpFailure),
@@ -63,6 +391,7 @@ impl PartialEq for SingleOutcome {
 
 #[derive(Debug, Serialize)]
 pub struct SingleHttpFailure {
+    pub kind: FailureKind,
     pub error: String,
     #[serde(with = "serde_millis")]
     pub duration_msec: Duration,
@@ -72,12 +401,113 @@ pub struct SingleHttpFailure {
 pub struct SingleHttpResponse {
     pub headers: Headers,
     pub status: u16,
-    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>, // left out when the body is passed as json
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json: Option<Box<RawValue>>, // instead of content, passed through as it was received
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pagination: Option<Pagination>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirects: Option<Redirects>,
     #[serde(with = "serde_millis")]
     pub duration_msec: Duration,
 }
 
-#[derive(Debug, AsRefStr, Deserialize)]
+impl SingleHttpResponse {
+    pub fn body(&self) -> &str {
+        match &self.json {
+            Some(json) => json.get(),
+            None => self.content.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Pagination {
+    pub pages: usize,
+    pub next_uri: Option<String>, // when max_pages or the deadline stopped before the last page
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Redirects {
+    pub final_uri: String,
+    pub chain: Vec<RedirectHop>, // the redirect responses, in the order they were followed
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RedirectHop {
+    pub uri: String,
+    pub status: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, AsRefStr, Serialize)]
+pub enum FailureKind {
+    Invalid,
+    Request,
+    Response,
+    Timeout,
+    Blocked,
+    RateLimited,
+    CircuitOpen,
+    Skipped,
+    Extraction,
+    Expectation,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuctionRequest {
+    #[serde(with = "serde_millis")]
+    pub timeout_msec: Duration,
+    pub bid_request: Value, // OpenRTB BidRequest, sent as-is to every bidder
+    pub bidders: Vec<Bidder>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bidder {
+    pub name: String,
+    pub uri: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuctionResponse {
+    pub bids: Vec<RankedBid>, // highest price first
+    pub bidders: Vec<BidderStats>, // same order and count as bidders!
+}
+
+#[derive(Debug, Serialize)]
+pub struct RankedBid {
+    pub bidder: String,
+    pub seat: Option<String>,
+    pub imp_id: String,
+    pub price: f64,
+    pub currency: String,
+    pub bid: Value, // the complete OpenRTB Bid object
+}
+
+#[derive(Debug, Serialize)]
+pub struct BidderStats {
+    pub name: String,
+    pub status: BidderStatus,
+    pub bid_count: usize,
+    pub error: Option<String>,
+    #[serde(with = "serde_millis")]
+    pub latency_msec: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, AsRefStr, Serialize)]
+pub enum BidderStatus {
+    Bid,
+    NoBid,
+    Invalid,
+    Error,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, AsRefStr, Deserialize)]
 pub enum HttpMethod {
     GET,
     POST,
@@ -99,6 +529,12 @@ impl From<HeaderMap<HeaderValue>> for Headers {
     }
 }
 
+impl Headers {
+    pub fn as_map(&self) -> &HeaderMap {
+        &self.0
+    }
+}
+
 impl Debug for Headers {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         write!(f, "(")?;
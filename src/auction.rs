@@ -0,0 +1,311 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::api::{AuctionRequest, AuctionResponse, BidderStats, BidderStatus, ExecutionMode, FailureKind,
+                 HttpMethod, OctoplexRequest, RankedBid, RequestTemplate, SingleHttpRequest, SingleOutcome};
+use crate::http_client::HttpClient;
+use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+
+const OPENRTB_VERSION: &str = "2.5";
+const DEFAULT_CURRENCY: &str = "USD";
+
+#[derive(Error, Debug)]
+enum BidError {
+    #[error("malformed BidResponse: {0}")]
+    MalformedResponse(serde_json::Error),
+    #[error("BidResponse id {0:?} does not match the BidRequest id")]
+    ResponseIdMismatch(String),
+    #[error("bid without a valid impid")]
+    MissingImpId,
+    #[error("bid for unknown impression {0:?}")]
+    UnknownImpId(String),
+    #[error("bid without a positive price")]
+    InvalidPrice,
+}
+
+// only the parts of the OpenRTB BidResponse needed for ranking, bids are passed on as they are
+#[derive(Debug, Deserialize)]
+struct BidResponse {
+    id: String,
+    #[serde(default)]
+    seatbid: Vec<SeatBid>,
+    cur: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SeatBid {
+    #[serde(default)]
+    bid: Vec<Value>,
+    seat: Option<String>,
+}
+
+impl<C> GenericMultiplexer<C>
+    where C: HttpClient + Clone + Send + Sync
+{
+    pub async fn auction(&self, auction: AuctionRequest, limits: &BatchLimits) -> Result<AuctionResponse> {
+        let batch = build_batch(&auction)?;
+        let batch_resp = self.handle(batch, limits).await?;
+
+        let request_id = auction.bid_request.get("id").and_then(Value::as_str);
+        let imp_ids = auction.bid_request.get("imp")
+            .and_then(Value::as_array)
+            .map(|imps| imps.iter()
+                .filter_map(|imp| imp.get("id").and_then(Value::as_str))
+                .collect::<HashSet<_>>());
+
+        let mut bids = vec![];
+        let mut bidders = vec![];
+        for (bidder, outcome) in auction.bidders.iter().zip(batch_resp.responses) {
+            let (stats, mut bidder_bids) = evaluate_bidder(&bidder.name, outcome, request_id, imp_ids.as_ref());
+
+            bids.append(&mut bidder_bids);
+            bidders.push(stats);
+        }
+
+        // XXX bids in different currencies are ranked by their nominal price
+        bids.sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap_or(Ordering::Equal));
+
+        Ok(AuctionResponse {
+            bids,
+            bidders,
+        })
+    }
+}
+
+fn build_batch(auction: &AuctionRequest) -> Result<OctoplexRequest> {
+    let body = serde_json::to_string(&auction.bid_request).context("cannot serialize")?;
+
+    let template = RequestTemplate {
+        method: Some(HttpMethod::POST),
+        uri_base: None,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("X-OpenRTB-Version".to_string(), OPENRTB_VERSION.to_string()),
+        ].into_iter().collect(),
+        body: Some(body),
+    };
+
+    let requests = auction.bidders.iter()
+        .map(|bidder| SingleHttpRequest {
+            uri: bidder.uri.clone(),
+            headers: bidder.headers.clone(),
+            ..Default::default()
+        })
+        .collect();
+
+    Ok(OctoplexRequest {
+        timeout_msec: auction.timeout_msec,
+        execution: ExecutionMode::Parallel,
+        max_concurrency: None,
+        parse_json: false,
+        aggregate: None,
+        for_each: None,
+        cookie_jar: None,
+        callback: None,
+        template: Some(template),
+        requests,
+    })
+}
+
+fn evaluate_bidder(name: &str, outcome: SingleOutcome, request_id: Option<&str>,
+                   imp_ids: Option<&HashSet<&str>>) -> (BidderStats, Vec<RankedBid>)
+{
+    let stats = |status, bid_count, error: Option<String>, latency_msec| BidderStats {
+        name: name.to_string(),
+        status,
+        bid_count,
+        error,
+        latency_msec,
+    };
+
+    let resp = match outcome {
+        SingleOutcome::Failure(failure) => {
+            let status = match failure.kind {
+                FailureKind::Timeout => BidderStatus::Timeout,
+                _ => BidderStatus::Error,
+            };
+
+            return (stats(status, 0, Some(failure.error), failure.duration_msec), vec![]);
+        }
+        SingleOutcome::Success(resp) => resp,
+    };
+
+    let content = resp.body();
+    match resp.status {
+        204 => return (stats(BidderStatus::NoBid, 0, None, resp.duration_msec), vec![]),
+        200 if content.trim().is_empty() => return (stats(BidderStatus::NoBid, 0, None, resp.duration_msec), vec![]),
+        200 => (),
+        status => {
+            let error = format!("unexpected status {}", status);
+
+            return (stats(BidderStatus::Error, 0, Some(error), resp.duration_msec), vec![]);
+        }
+    }
+
+    match parse_bids(name, content, request_id, imp_ids) {
+        Ok(bids) if bids.is_empty() => (stats(BidderStatus::NoBid, 0, None, resp.duration_msec), bids),
+        Ok(bids) => (stats(BidderStatus::Bid, bids.len(), None, resp.duration_msec), bids),
+        Err(error) => (stats(BidderStatus::Invalid, 0, Some(error.to_string()), resp.duration_msec), vec![]),
+    }
+}
+
+// invalid bids are dropped, the bidder is only considered invalid if none of its bids were valid
+fn parse_bids(bidder: &str, content: &str, request_id: Option<&str>,
+              imp_ids: Option<&HashSet<&str>>) -> Result<Vec<RankedBid>, BidError>
+{
+    let bid_resp: BidResponse = serde_json::from_str(content)
+        .map_err(BidError::MalformedResponse)?;
+
+    if let Some(request_id) = request_id {
+        if bid_resp.id != request_id {
+            return Err(BidError::ResponseIdMismatch(bid_resp.id));
+        }
+    }
+
+    let currency = bid_resp.cur.unwrap_or_else(|| DEFAULT_CURRENCY.to_string());
+    let mut bids = vec![];
+    let mut first_error = None;
+
+    for seat_bid in bid_resp.seatbid {
+        for bid in seat_bid.bid {
+            match validate_bid(&bid, imp_ids) {
+                Ok((imp_id, price)) => bids.push(RankedBid {
+                    bidder: bidder.to_string(),
+                    seat: seat_bid.seat.clone(),
+                    imp_id,
+                    price,
+                    currency: currency.clone(),
+                    bid,
+                }),
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+    }
+
+    match first_error {
+        Some(error) if bids.is_empty() => Err(error),
+        _ => Ok(bids),
+    }
+}
+
+fn validate_bid(bid: &Value, imp_ids: Option<&HashSet<&str>>) -> Result<(String, f64), BidError> {
+    let imp_id = bid.get("impid")
+        .and_then(Value::as_str)
+        .ok_or(BidError::MissingImpId)?;
+
+    if let Some(imp_ids) = imp_ids {
+        if !imp_ids.contains(imp_id) {
+            return Err(BidError::UnknownImpId(imp_id.to_string()));
+        }
+    }
+
+    let price = bid.get("price")
+        .and_then(Value::as_f64)
+        .filter(|price| *price > 0.0)
+        .ok_or(BidError::InvalidPrice)?;
+
+    Ok((imp_id.to_string(), price))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::{Context, Result};
+    use hyper::{Body, Response};
+    use serde_json::json;
+
+    use crate::api::{AuctionRequest, Bidder, BidderStatus};
+    use crate::http_client::tests::{MOCK_REQUEST_DURATION, MockHttpClient};
+    use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+
+    fn bid_response(price: f64) -> Result<Response<Body>> {
+        let json = json!({
+            "id": "auction-1",
+            "cur": "EUR",
+            "seatbid": [{"seat": "seat-1", "bid": [{"id": "b1", "impid": "imp-1", "price": price}]}],
+        });
+
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json.to_string()))
+            .context("cannot build response")
+    }
+
+    fn status_response(status: u16, body: &'static str) -> Result<Response<Body>> {
+        Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .context("cannot build response")
+    }
+
+    fn bidder(name: &str) -> Bidder {
+        Bidder {
+            name: name.to_string(),
+            uri: format!("https://{}.example.com/bid", name),
+            headers: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn ranks_bids_and_reports_bidders() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().returning(|req| {
+            assert_eq!(req.method(), "POST");
+            assert_eq!(req.headers()["X-OpenRTB-Version"], "2.5");
+
+            match req.uri().host().unwrap() {
+                "low.example.com" => bid_response(0.5),
+                "high.example.com" => bid_response(2.25),
+                "pass.example.com" => status_response(204, ""),
+                "broken.example.com" => status_response(200, "{\"id\": \"other-auction\"}"),
+                _ => status_response(500, "oops"),
+            }
+        });
+
+        let auction = AuctionRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 2,
+            bid_request: json!({"id": "auction-1", "imp": [{"id": "imp-1"}]}),
+            bidders: vec![bidder("low"), bidder("high"), bidder("pass"), bidder("broken"), bidder("down")],
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .auction(auction, &BatchLimits::default()).await
+            .expect("auction failed");
+
+        assert_eq!(result.bids.len(), 2);
+        assert_eq!(result.bids[0].bidder, "high");
+        assert_eq!(result.bids[0].currency, "EUR");
+        assert_eq!(result.bids[1].bidder, "low");
+
+        let statuses = result.bidders.iter().map(|b| b.status).collect::<Vec<_>>();
+        assert_eq!(statuses, vec![BidderStatus::Bid, BidderStatus::Bid, BidderStatus::NoBid,
+                                  BidderStatus::Invalid, BidderStatus::Error]);
+    }
+
+    #[tokio::test]
+    async fn reports_bidder_timeout() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().returning(|_req| bid_response(1.0));
+
+        let auction = AuctionRequest {
+            timeout_msec: Duration::from_millis(10),
+            bid_request: json!({"id": "auction-1", "imp": [{"id": "imp-1"}]}),
+            bidders: vec![bidder("slow")],
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .auction(auction, &BatchLimits::default()).await
+            .expect("auction failed");
+
+        assert!(result.bids.is_empty());
+        assert_eq!(result.bidders[0].status, BidderStatus::Timeout);
+    }
+}
@@ -0,0 +1,205 @@
+use std::cmp::min;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use hyper::{header, Body, Method, Request};
+use ring::hmac;
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::api::Callback;
+use crate::http_client::HttpClient;
+
+// sha256=<hex>, the HMAC of the payload with the secret of the batch
+pub const SIGNATURE_HEADER: &str = "X-Octoplex-Signature";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CallbackConfig {
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff_msec")]
+    pub initial_backoff_msec: u64, // doubles after every failed attempt
+    #[serde(default = "default_max_backoff_msec")]
+    pub max_backoff_msec: u64,
+    #[serde(default = "default_timeout_msec")]
+    pub timeout_msec: u64, // per attempt
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        CallbackConfig {
+            max_attempts: default_max_attempts(),
+            initial_backoff_msec: default_initial_backoff_msec(),
+            max_backoff_msec: default_max_backoff_msec(),
+            timeout_msec: default_timeout_msec(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_initial_backoff_msec() -> u64 {
+    1_000
+}
+
+fn default_max_backoff_msec() -> u64 {
+    60_000
+}
+
+fn default_timeout_msec() -> u64 {
+    10_000
+}
+
+// anything but a 2xx answer is retried, until the attempts are used up
+pub async fn deliver<C>(client: &C, config: &CallbackConfig, callback: &Callback, payload: Bytes) -> Result<()>
+    where C: HttpClient + Send + Sync
+{
+    let mut backoff = Duration::from_millis(config.initial_backoff_msec);
+    let mut attempt = 1;
+
+    loop {
+        match send(client, config, callback, payload.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= config.max_attempts =>
+                return Err(e.context(format!("callback failed after {} attempts", attempt))),
+            Err(e) => debug!("callback to {} failed at attempt {}: {:#}", callback.uri, attempt, e),
+        }
+
+        sleep(backoff).await;
+        backoff = min(backoff * 2, Duration::from_millis(config.max_backoff_msec));
+        attempt += 1;
+    }
+}
+
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, payload);
+
+    format!("sha256={}", tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+async fn send<C>(client: &C, config: &CallbackConfig, callback: &Callback, payload: Bytes) -> Result<()>
+    where C: HttpClient + Send + Sync
+{
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(&callback.uri)
+        .header(header::CONTENT_TYPE, "application/json; charset=utf-8");
+    if let Some(secret) = &callback.secret {
+        req = req.header(SIGNATURE_HEADER, sign(secret, &payload));
+    }
+    let req = req.body(Body::from(payload)).context("cannot build callback request")?;
+
+    let resp = timeout(Duration::from_millis(config.timeout_msec), client.request(req)).await
+        .context("callback timed out")??;
+
+    match resp.status().is_success() {
+        true => Ok(()),
+        false => bail!("callback was answered with {}", resp.status()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::Error;
+    use bytes::Bytes;
+    use hyper::{Body, Request, Response, Server};
+    use hyper::body::to_bytes;
+    use hyper::service::{make_service_fn, service_fn};
+
+    use crate::api::Callback;
+    use crate::callback::{deliver, sign, CallbackConfig, SIGNATURE_HEADER};
+    use crate::http_client::make_hyper_client;
+    use crate::target_policy::{TargetPolicy, TargetPolicyConfig};
+
+    type Received = Arc<Mutex<Vec<(Option<String>, Bytes)>>>;
+
+    // the receiver listens on localhost
+    fn open_policy() -> TargetPolicy {
+        TargetPolicy::new(TargetPolicyConfig { block_private_ips: false, ..Default::default() })
+    }
+
+    // answers 500 to the first `failures` callbacks, and 200 after that
+    fn start_receiver(failures: usize) -> (SocketAddr, Received) {
+        let received = Received::default();
+        let server_received = received.clone();
+
+        let make_service = make_service_fn(move |_conn| {
+            let received = server_received.clone();
+
+            async move {
+                Ok::<_, Error>(service_fn(move |req: Request<Body>| {
+                    let received = received.clone();
+
+                    async move {
+                        let signature = req.headers().get(SIGNATURE_HEADER)
+                            .map(|value| value.to_str().unwrap().to_string());
+                        let body = to_bytes(req.into_body()).await?;
+
+                        let mut received = received.lock().unwrap();
+                        received.push((signature, body));
+                        let status = if received.len() > failures { 200 } else { 500 };
+
+                        Ok::<_, Error>(Response::builder().status(status).body(Body::empty())?)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, received)
+    }
+
+    fn config(max_attempts: u32) -> CallbackConfig {
+        CallbackConfig {
+            max_attempts,
+            initial_backoff_msec: 10,
+            max_backoff_msec: 20,
+            timeout_msec: 1_000,
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_and_signs_callbacks() {
+        let (addr, received) = start_receiver(2);
+        let client = make_hyper_client(open_policy()).unwrap();
+        let callback = Callback {
+            uri: format!("http://{}/done", addr),
+            secret: Some("s3cret".to_string()),
+        };
+        let payload = Bytes::from_static(br#"{"responses":[]}"#);
+
+        deliver(&client, &config(3), &callback, payload.clone()).await.expect("callback failed");
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|(_, body)| *body == payload));
+        assert_eq!(received[2].0.as_deref(), Some(sign("s3cret", &payload).as_str()));
+        assert_eq!(sign("key", b"The quick brown fox jumps over the lazy dog"),
+                   "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (addr, received) = start_receiver(5);
+        let client = make_hyper_client(open_policy()).unwrap();
+        let callback = Callback {
+            uri: format!("http://{}/done", addr),
+            secret: None,
+        };
+
+        let result = deliver(&client, &config(2), &callback, Bytes::from_static(b"{}")).await;
+
+        assert!(result.is_err());
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert!(received.lock().unwrap()[0].0.is_none(), "unsigned callback");
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use humantime::format_duration;
+use time::OffsetDateTime;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, Duration, Instant, MissedTickBehavior};
+
+use crate::api::{CheckResult, CheckStatus, OctoplexRequest, OctoplexResponse, SingleOutcome};
+use crate::cron::CronSchedule;
+use crate::http_client::{HttpClient, OctoplexHttpClient};
+use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+
+pub type CheckScheduler = GenericCheckScheduler<OctoplexHttpClient>;
+
+// a batch which is run on a schedule, and fails when any of its requests does not pass the assertions
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckConfig {
+    #[serde(default)]
+    pub interval_sec: Option<u64>,
+    #[serde(default)]
+    pub cron: Option<String>, // minute hour day-of-month month day-of-week, in UTC
+    pub batch: OctoplexRequest,
+    #[serde(default)]
+    pub expected_status: Vec<u16>, // empty allows any status below 400
+    #[serde(default)]
+    pub max_latency_msec: Option<u64>, // of every single request
+    #[serde(default)]
+    pub body_contains: Option<String>,
+    #[serde(default = "default_keep_results")]
+    pub keep_results: usize,
+}
+
+fn default_keep_results() -> usize {
+    10
+}
+
+#[derive(Debug)]
+enum Schedule {
+    Interval(Duration),
+    Cron(CronSchedule),
+}
+
+#[derive(Default)]
+struct CheckState {
+    runs: u64,
+    failures: u64,
+    results: VecDeque<CheckResult>,
+}
+
+// a replaced check keeps running until it is aborted, but records into a state nobody looks at
+struct Check {
+    schedule: String, // for humans
+    state: Arc<Mutex<CheckState>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Check {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+pub struct GenericCheckScheduler<C>
+    where C: HttpClient + Clone + Send + Sync
+{
+    multi: GenericMultiplexer<C>,
+    checks: Mutex<HashMap<String, Check>>, // by name
+}
+
+impl<C> GenericCheckScheduler<C>
+    where C: HttpClient + Clone + Send + Sync + 'static
+{
+    // starts all configured checks right away
+    pub fn new(multi: GenericMultiplexer<C>, checks: HashMap<String, CheckConfig>) -> Result<Self> {
+        let scheduler = GenericCheckScheduler {
+            multi,
+            checks: Mutex::new(HashMap::new()),
+        };
+
+        for (name, check) in checks {
+            scheduler.upsert(&name, check)?;
+        }
+
+        Ok(scheduler)
+    }
+
+    // a check with the same name is replaced, and its results are discarded
+    pub fn upsert(&self, name: &str, config: CheckConfig) -> Result<CheckStatus> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            bail!("check name {:?} may only contain letters, digits, - and _", name);
+        }
+        if config.keep_results == 0 {
+            bail!("keep_results has to be at least 1");
+        }
+        let (schedule, description) = match (config.interval_sec, &config.cron) {
+            (Some(0), None) => bail!("interval_sec has to be at least 1"),
+            (Some(interval_sec), None) => {
+                let period = Duration::from_secs(interval_sec);
+                (Schedule::Interval(period), format!("every {}", format_duration(period)))
+            }
+            (None, Some(cron)) => (Schedule::Cron(CronSchedule::parse(cron)?), cron.clone()),
+            _ => bail!("check {} needs either interval_sec or cron", name),
+        };
+
+        let state = Arc::new(Mutex::new(CheckState::default()));
+        let task = tokio::spawn(run_schedule(self.multi.clone(), config, schedule, state.clone()));
+        let check = Check { schedule: description, state, task };
+        let status = status_of(name, &check);
+
+        self.checks.lock().expect("checks poisoned").insert(name.to_string(), check);
+
+        Ok(status)
+    }
+
+    pub fn remove(&self, name: &str) -> Option<CheckStatus> {
+        self.checks.lock().expect("checks poisoned")
+            .remove(name)
+            .map(|check| status_of(name, &check))
+    }
+
+    pub fn status(&self, name: &str) -> Option<CheckStatus> {
+        self.checks.lock().expect("checks poisoned")
+            .get(name)
+            .map(|check| status_of(name, check))
+    }
+
+    pub fn statuses(&self) -> Vec<CheckStatus> {
+        let checks = self.checks.lock().expect("checks poisoned");
+        let mut statuses = checks.iter()
+            .map(|(name, check)| status_of(name, check))
+            .collect::<Vec<_>>();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+
+        statuses
+    }
+
+    // in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        let statuses = self.statuses();
+        let mut metrics = String::new();
+
+        let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&CheckStatus) -> Option<String>| {
+            let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for status in &statuses {
+                if let Some(value) = value(status) {
+                    let _ = writeln!(metrics, "{}{{check=\"{}\"}} {}", name, status.name, value);
+                }
+            }
+        };
+
+        family("octoplex_check_success", "gauge", "Whether the last run of the check succeeded.",
+               &|status| status.results.last().map(|result| u8::from(result.success).to_string()));
+        family("octoplex_check_duration_seconds", "gauge", "How long the last run of the check took.",
+               &|status| status.results.last().map(|result| result.duration_msec.as_secs_f64().to_string()));
+        family("octoplex_check_runs_total", "counter", "How often the check has run.",
+               &|status| Some(status.runs.to_string()));
+        family("octoplex_check_failures_total", "counter", "How often the check has failed.",
+               &|status| Some(status.failures.to_string()));
+
+        metrics
+    }
+}
+
+fn status_of(name: &str, check: &Check) -> CheckStatus {
+    let state = check.state.lock().expect("check state poisoned");
+
+    CheckStatus {
+        name: name.to_string(),
+        schedule: check.schedule.clone(),
+        runs: state.runs,
+        failures: state.failures,
+        results: state.results.iter().cloned().collect(),
+    }
+}
+
+// an interval check runs right away, a cron check at the next matching minute
+async fn run_schedule<C>(multi: GenericMultiplexer<C>, config: CheckConfig, schedule: Schedule,
+                         state: Arc<Mutex<CheckState>>)
+    where C: HttpClient + Clone + Send + Sync
+{
+    let mut ticks = match &schedule {
+        Schedule::Interval(period) => {
+            let mut ticks = interval(*period);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Some(ticks)
+        }
+        Schedule::Cron(_) => None,
+    };
+
+    loop {
+        match &schedule {
+            Schedule::Interval(_) => {
+                ticks.as_mut().expect("interval without ticks").tick().await;
+            }
+            Schedule::Cron(cron) => match cron.next_after(OffsetDateTime::now_utc()) {
+                Some(next) => sleep(SystemTime::from(next).duration_since(SystemTime::now()).unwrap_or_default()).await,
+                None => return,
+            },
+        }
+
+        let result = run_check(&multi, &config).await;
+
+        let mut state = state.lock().expect("check state poisoned");
+        state.runs += 1;
+        state.failures += u64::from(!result.success);
+        state.results.push_back(result);
+        while state.results.len() > config.keep_results {
+            state.results.pop_front();
+        }
+    }
+}
+
+async fn run_check<C>(multi: &GenericMultiplexer<C>, config: &CheckConfig) -> CheckResult
+    where C: HttpClient + Clone + Send + Sync
+{
+    let started_unix_msec = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default();
+    let start = Instant::now();
+
+    let errors = match multi.handle(config.batch.clone(), &BatchLimits::default()).await {
+        Ok(resp) => evaluate(config, &resp),
+        Err(e) => vec![e.to_string()],
+    };
+
+    CheckResult {
+        started_unix_msec,
+        success: errors.is_empty(),
+        duration_msec: start.elapsed(),
+        errors,
+    }
+}
+
+fn evaluate(config: &CheckConfig, resp: &OctoplexResponse) -> Vec<String> {
+    let mut errors = vec![];
+
+    for (index, outcome) in resp.responses.iter().enumerate() {
+        let label = match config.batch.requests.get(index).and_then(|req| req.id.as_deref()) {
+            Some(id) => id.to_string(),
+            None => format!("request #{}", index),
+        };
+
+        let resp = match outcome {
+            SingleOutcome::Success(resp) => resp,
+            SingleOutcome::Failure(failure) => {
+                errors.push(format!("{} failed: {}", label, failure.error));
+                continue;
+            }
+        };
+
+        let status_expected = match config.expected_status.is_empty() {
+            true => resp.status < 400,
+            false => config.expected_status.contains(&resp.status),
+        };
+        if !status_expected {
+            errors.push(format!("{} responded with unexpected status {}", label, resp.status));
+        }
+
+        if let Some(max_latency_msec) = config.max_latency_msec {
+            if resp.duration_msec > Duration::from_millis(max_latency_msec) {
+                errors.push(format!("{} took {} msec instead of at most {}", label,
+                                    resp.duration_msec.as_millis(), max_latency_msec));
+            }
+        }
+
+        if let Some(needle) = &config.body_contains {
+            if !resp.body().contains(needle.as_str()) {
+                errors.push(format!("{} body does not contain {:?}", label, needle));
+            }
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use hyper::{Body, Response};
+    use tokio::time::{pause, sleep, Duration};
+
+    use crate::api::{OctoplexRequest, SingleHttpRequest};
+    use crate::checks::{CheckConfig, GenericCheckScheduler};
+    use crate::http_client::tests::MockHttpClient;
+    use crate::multiplexer::GenericMultiplexer;
+    use crate::multiplexer::tests::batch_of;
+
+    // every check runs with a clone of the client
+    fn multiplexer() -> GenericMultiplexer<MockHttpClient> {
+        let mut client = MockHttpClient::new();
+        client.expect_clone().returning(|| {
+            let mut client = MockHttpClient::new();
+            client.expect_request().returning(|_req| ok_response());
+            client
+        });
+
+        GenericMultiplexer::new(client)
+    }
+
+    fn ok_response() -> Result<Response<Body>> {
+        Ok(Response::builder().status(200).body(Body::from("{}"))?)
+    }
+
+    fn check(interval_sec: u64) -> CheckConfig {
+        CheckConfig {
+            interval_sec: Some(interval_sec),
+            cron: None,
+            batch: OctoplexRequest {
+                timeout_msec: Duration::from_secs(1),
+                ..batch_of(vec![SingleHttpRequest {
+                    uri: "https://www.google.com/".to_string(),
+                    ..Default::default()
+                }])
+            },
+            expected_status: vec![200],
+            max_latency_msec: None,
+            body_contains: None,
+            keep_results: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_checks_on_interval() {
+        pause();
+        let definitions = vec![("home".to_string(), check(10))].into_iter().collect();
+        let checks = GenericCheckScheduler::new(multiplexer(), definitions).expect("invalid checks");
+
+        sleep(Duration::from_secs(25)).await;
+        let status = checks.status("home").expect("check not found");
+        assert_eq!((status.runs, status.failures, status.results.len()), (3, 0, 2));
+        assert!(status.results.iter().all(|result| result.success));
+
+        let metrics = checks.metrics();
+        assert!(metrics.contains("octoplex_check_success{check=\"home\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("octoplex_check_runs_total{check=\"home\"} 3\n"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn reports_failed_assertions() {
+        pause();
+        let checks = GenericCheckScheduler::new(multiplexer(), HashMap::new()).unwrap();
+
+        let mut config = check(60);
+        config.expected_status = vec![204];
+        config.max_latency_msec = Some(10);
+        config.body_contains = Some("octoplex".to_string());
+        checks.upsert("home", config).expect("invalid check");
+
+        sleep(Duration::from_secs(1)).await;
+        let status = checks.status("home").expect("check not found");
+        assert_eq!((status.runs, status.failures), (1, 1));
+        assert_eq!(status.results[0].errors.len(), 3, "{:?}", status.results[0].errors);
+
+        assert!(checks.remove("home").is_some());
+        assert!(checks.status("home").is_none());
+
+        let mut config = check(60);
+        config.cron = Some("* * * * *".to_string());
+        assert!(checks.upsert("both", config).is_err());
+        assert!(checks.upsert("no spaces", check(60)).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use hyper::Uri;
+use thiserror::Error;
+use tokio::time::{Duration, Instant};
+
+use crate::api::{CircuitState, CircuitStats};
+use crate::http_client::origin_of;
+
+// without any threshold the breaker keeps track of origins, but never trips
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    #[serde(default)]
+    pub consecutive_failures: Option<u32>,
+    #[serde(default)]
+    pub error_rate: Option<f64>, // 0.0 - 1.0, within the window
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32, // in the window, before the error rate is considered
+    #[serde(default = "default_window_sec")]
+    pub window_sec: u64,
+    #[serde(default = "default_open_sec")]
+    pub open_sec: u64, // until requests are let through again to probe the origin
+    #[serde(default = "default_half_open_requests")]
+    pub half_open_requests: u32,
+}
+
+fn default_min_requests() -> u32 {
+    20
+}
+
+fn default_window_sec() -> u64 {
+    30
+}
+
+fn default_open_sec() -> u64 {
+    30
+}
+
+fn default_half_open_requests() -> u32 {
+    1
+}
+
+#[derive(Error, Debug)]
+#[error("circuit of {origin} is open")]
+pub struct CircuitOpen {
+    pub origin: String,
+}
+
+// handed back to `record` with the outcome; a probe ticket dropped without one, e.g. because the
+// request was cancelled, frees its probe slot without counting as a failure
+pub struct CircuitTicket<'a> {
+    breaker: &'a CircuitBreaker,
+    origin: String,
+    probe: bool,
+}
+
+impl Drop for CircuitTicket<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.release_probe(&self.origin);
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    opened: Instant,
+    probes_in_flight: u32,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        let now = Instant::now();
+
+        Circuit {
+            state: CircuitState::Closed,
+            opened: now,
+            probes_in_flight: 0,
+            consecutive_failures: 0,
+            window_start: now,
+            window_requests: 0,
+            window_failures: 0,
+        }
+    }
+
+    fn open(&mut self) {
+        self.state = CircuitState::Open;
+        self.opened = Instant::now();
+        self.probes_in_flight = 0;
+    }
+
+    fn close(&mut self) {
+        *self = Circuit::new();
+    }
+
+    // XXX a fixed window, the error rate starts from scratch at every window boundary
+    fn roll_window(&mut self, window: Duration) {
+        let now = Instant::now();
+
+        if now.saturating_duration_since(self.window_start) >= window {
+            self.window_start = now;
+            self.window_requests = 0;
+            self.window_failures = 0;
+        }
+    }
+
+    fn is_idle(&mut self, window: Duration) -> bool {
+        self.roll_window(window);
+
+        self.state == CircuitState::Closed && self.window_requests == 0
+    }
+}
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// closed circuits without requests in their window are dropped now and then, they would start out closed anyway
+struct CircuitTable {
+    by_origin: HashMap<String, Circuit>,
+    swept: Instant,
+}
+
+impl CircuitTable {
+    fn sweep(&mut self, window: Duration) {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.swept) < SWEEP_INTERVAL {
+            return;
+        }
+
+        self.by_origin.retain(|_, circuit| !circuit.is_idle(window));
+        self.swept = now;
+    }
+}
+
+pub struct CircuitBreaker {
+    config: Option<CircuitBreakerConfig>,
+    circuits: Mutex<CircuitTable>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(None)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: Option<CircuitBreakerConfig>) -> Self {
+        CircuitBreaker {
+            config,
+            circuits: Mutex::new(CircuitTable {
+                by_origin: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    // no ticket means there is nothing to record, the breaker is disabled or the uri has no origin
+    pub fn admit(&self, uri: &Uri) -> Result<Option<CircuitTicket<'_>>, CircuitOpen> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+        let origin = match origin_of(uri) {
+            Some(origin) => origin,
+            None => return Ok(None),
+        };
+
+        let mut circuits = self.circuits.lock().expect("circuits poisoned");
+        circuits.sweep(Duration::from_secs(config.window_sec));
+        let circuit = circuits.by_origin.entry(origin.clone()).or_insert_with(Circuit::new);
+
+        if circuit.state == CircuitState::Open
+            && circuit.opened.elapsed() >= Duration::from_secs(config.open_sec)
+        {
+            circuit.state = CircuitState::HalfOpen;
+        }
+
+        match circuit.state {
+            CircuitState::Closed => Ok(Some(CircuitTicket { breaker: self, origin, probe: false })),
+            CircuitState::HalfOpen if circuit.probes_in_flight < config.half_open_requests => {
+                circuit.probes_in_flight += 1;
+                Ok(Some(CircuitTicket { breaker: self, origin, probe: true }))
+            }
+            _ => Err(CircuitOpen { origin }),
+        }
+    }
+
+    // `healthy` is None for outcomes which say nothing about the origin, e.g. blocked requests
+    pub fn record(&self, mut ticket: CircuitTicket<'_>, healthy: Option<bool>) {
+        // taken over from the ticket, which has nothing left to release then
+        let probe = std::mem::replace(&mut ticket.probe, false);
+        let origin = std::mem::take(&mut ticket.origin);
+        drop(ticket);
+
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        let mut circuits = self.circuits.lock().expect("circuits poisoned");
+        let circuit = match circuits.by_origin.get_mut(&origin) {
+            Some(circuit) => circuit,
+            None => return,
+        };
+
+        if probe {
+            // a probe which lost the race against another probe is not considered
+            if circuit.state != CircuitState::HalfOpen {
+                return;
+            }
+            circuit.probes_in_flight = circuit.probes_in_flight.saturating_sub(1);
+
+            match healthy {
+                Some(true) => circuit.close(),
+                Some(false) => circuit.open(),
+                None => (),
+            }
+            return;
+        }
+
+        let healthy = match healthy {
+            Some(healthy) if circuit.state == CircuitState::Closed => healthy,
+            _ => return,
+        };
+
+        circuit.roll_window(Duration::from_secs(config.window_sec));
+        circuit.window_requests += 1;
+
+        if healthy {
+            circuit.consecutive_failures = 0;
+            return;
+        }
+
+        circuit.consecutive_failures += 1;
+        circuit.window_failures += 1;
+
+        let too_many_failures = matches!(config.consecutive_failures,
+            Some(max) if circuit.consecutive_failures >= max);
+        let error_rate = f64::from(circuit.window_failures) / f64::from(circuit.window_requests);
+        let too_many_errors = circuit.window_requests >= config.min_requests
+            && matches!(config.error_rate, Some(max) if error_rate >= max);
+
+        if too_many_failures || too_many_errors {
+            circuit.open();
+        }
+    }
+
+    fn release_probe(&self, origin: &str) {
+        let mut circuits = self.circuits.lock().expect("circuits poisoned");
+
+        let half_open = circuits.by_origin.get_mut(origin).filter(|circuit| circuit.state == CircuitState::HalfOpen);
+        if let Some(circuit) = half_open {
+            circuit.probes_in_flight = circuit.probes_in_flight.saturating_sub(1);
+        }
+    }
+
+    pub fn stats(&self) -> Vec<CircuitStats> {
+        let circuits = self.circuits.lock().expect("circuits poisoned");
+        let mut stats = circuits.by_origin.iter()
+            .map(|(origin, circuit)| CircuitStats {
+                origin: origin.clone(),
+                state: circuit.state,
+                consecutive_failures: circuit.consecutive_failures,
+                window_requests: circuit.window_requests,
+                window_failures: circuit.window_failures,
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| a.origin.cmp(&b.origin));
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Uri;
+    use tokio::time::{advance, pause, timeout, Duration};
+
+    use crate::api::CircuitState;
+    use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(Some(CircuitBreakerConfig {
+            consecutive_failures: Some(3),
+            error_rate: Some(0.5),
+            min_requests: 4,
+            window_sec: 60,
+            open_sec: 10,
+            half_open_requests: 1,
+        }))
+    }
+
+    fn send(breaker: &CircuitBreaker, uri: &Uri, healthy: bool) {
+        let ticket = breaker.admit(uri).expect("circuit open").expect("no ticket");
+        breaker.record(ticket, Some(healthy));
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures_and_recovers() {
+        pause();
+        let breaker = breaker();
+        let api = Uri::from_static("https://api.example.com/a");
+
+        for _ in 0..3 {
+            send(&breaker, &api, false);
+        }
+        assert!(breaker.admit(&api).is_err());
+        assert!(breaker.admit(&Uri::from_static("https://api.example.com:8443/")).is_ok());
+        assert_eq!(breaker.stats()[0].state, CircuitState::Open);
+
+        advance(Duration::from_secs(10)).await;
+        let probe = breaker.admit(&api).expect("probe rejected").expect("no ticket");
+        assert!(breaker.admit(&api).is_err(), "only one probe at a time");
+        breaker.record(probe, Some(false));
+        assert!(breaker.admit(&api).is_err());
+
+        advance(Duration::from_secs(10)).await;
+        send(&breaker, &api, true);
+        assert_eq!(breaker.stats()[0].state, CircuitState::Closed);
+        assert!(breaker.admit(&api).is_ok());
+    }
+
+    #[tokio::test]
+    async fn releases_dropped_probes() {
+        pause();
+        let breaker = breaker();
+        let api = Uri::from_static("https://api.example.com/a");
+
+        for _ in 0..3 {
+            send(&breaker, &api, false);
+        }
+        advance(Duration::from_secs(10)).await;
+
+        // like a request whose client went away, or which ran into the deadline
+        let probing = async {
+            let _ticket = breaker.admit(&api).expect("probe rejected");
+            std::future::pending::<()>().await;
+        };
+        assert!(timeout(Duration::from_secs(1), probing).await.is_err());
+
+        assert_eq!(breaker.stats()[0].state, CircuitState::HalfOpen);
+        send(&breaker, &api, true);
+        assert_eq!(breaker.stats()[0].state, CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn opens_on_error_rate() {
+        pause();
+        let breaker = breaker();
+        let api = Uri::from_static("https://api.example.com/a");
+
+        for healthy in &[false, true, false, true, true] {
+            send(&breaker, &api, *healthy);
+        }
+        assert!(breaker.admit(&api).is_ok(), "2 of 5 is below the error rate");
+
+        send(&breaker, &api, false);
+        assert!(breaker.admit(&api).is_err());
+    }
+
+    #[tokio::test]
+    async fn forgets_idle_circuits() {
+        pause();
+        let breaker = breaker();
+        let api = Uri::from_static("https://api.example.com/a");
+        let search = Uri::from_static("https://search.example.com/");
+
+        for _ in 0..3 {
+            send(&breaker, &api, false);
+        }
+        send(&breaker, &search, true);
+
+        // the open circuit stays, the closed one has had no requests in its window by the next sweep
+        advance(Duration::from_secs(61)).await;
+        send(&breaker, &Uri::from_static("https://reports.example.com/"), true);
+        let origins = breaker.stats().into_iter().map(|stats| stats.origin).collect::<Vec<_>>();
+        assert_eq!(origins, vec!["https://api.example.com:443", "https://reports.example.com:443"]);
+    }
+}
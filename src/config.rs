@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::auth::AuthConfig;
+use crate::callback::CallbackConfig;
+use crate::checks::CheckConfig;
+use crate::circuit_breaker::CircuitBreakerConfig;
+use crate::jobs::JobsConfig;
+use crate::quota::QuotaConfig;
+use crate::shaping::ShapingConfig;
+use crate::target_policy::TargetPolicyConfig;
+use crate::upstream::UpstreamConfig;
+
+const CONFIG_PATH_VAR: &str = "OCTOPLEX_CONFIG";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub auth: AuthConfig,
+    pub quotas: QuotaConfig,
+    pub target_policy: TargetPolicyConfig,
+    pub shaping: ShapingConfig,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub upstreams: HashMap<String, UpstreamConfig>, // by name
+    pub jobs: JobsConfig,
+    pub callbacks: CallbackConfig,
+    pub checks: HashMap<String, CheckConfig>, // by name
+    pub recipes: HashMap<String, Value>, // by name, batches with placeholders
+}
+
+impl Config {
+    // the configuration file is optional, without one all defaults apply
+    pub fn load() -> Result<Self> {
+        match env::var_os(CONFIG_PATH_VAR) {
+            Some(path) => Self::from_file(Path::new(&path)),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+
+        let config: Config = serde_json::from_str(&content)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        config.validate().with_context(|| format!("invalid config file {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.quotas.validate()?;
+        self.shaping.validate()
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::{HeaderMap, HeaderValue, Request, Uri};
+use http::header::{COOKIE, SET_COOKIE};
+
+use crate::api::Cookie;
+
+// the cookies of one batch, after RFC 6265
+// every request stores its own, which only it and the requests that see it get
+// XXX without a public suffix list, a response may set a cookie for a whole suffix like co.uk
+#[derive(Default)]
+pub struct CookieJar {
+    stored: Mutex<Stored>,
+}
+
+// the request a cookie jar is used for, by position in the batch
+#[derive(Debug, Clone, Default)]
+pub struct CookieScope {
+    pub request: usize,
+    pub sees: Option<BTreeSet<usize>>, // the requests whose cookies it gets besides its own, all if None
+}
+
+impl CookieScope {
+    fn sees(&self, request: usize) -> bool {
+        request == self.request || match &self.sees {
+            Some(sees) => sees.contains(&request),
+            None => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stored {
+    cookies: Vec<StoredCookie>, // in the order they were first set
+    count: u64,
+}
+
+struct StoredCookie {
+    cookie: Cookie, // an expired one hides those the request has seen
+    set_by: usize,
+    set_at: u64, // the later one wins between cookies of the same name, domain and path
+}
+
+impl CookieJar {
+    // the cookies set by the response to a request to uri
+    pub fn store(&self, scope: &CookieScope, uri: &Uri, headers: &HeaderMap) {
+        let now = unix_now();
+        let mut stored = self.stored.lock().expect("cookie jar poisoned");
+
+        let set_cookies = headers.get_all(SET_COOKIE).iter().filter_map(|value| value.to_str().ok());
+        for cookie in set_cookies.filter_map(|set_cookie| parse(uri, set_cookie, now)) {
+            stored.count += 1;
+            let set_at = stored.count;
+            let existing = stored.cookies.iter_mut()
+                .find(|entry| entry.set_by == scope.request && same_cookie(&entry.cookie, &cookie));
+
+            match existing {
+                Some(entry) => {
+                    entry.cookie = cookie;
+                    entry.set_at = set_at;
+                }
+                None => stored.cookies.push(StoredCookie { cookie, set_by: scope.request, set_at }),
+            }
+        }
+    }
+
+    // appended to a Cookie header the request already has
+    pub fn add_to<B>(&self, scope: &CookieScope, request: &mut Request<B>) {
+        let header = match self.header_for(scope, request.uri()) {
+            Some(header) => header,
+            None => return,
+        };
+        let header = match request.headers().get(COOKIE).and_then(|value| value.to_str().ok()) {
+            Some(existing) => format!("{}; {}", existing, header),
+            None => header,
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&header) {
+            request.headers_mut().insert(COOKIE, value);
+        }
+    }
+
+    // as they are after all requests of the batch
+    pub fn cookies(&self) -> Vec<Cookie> {
+        let stored = self.stored.lock().expect("cookie jar poisoned");
+
+        current(&stored.cookies, &CookieScope::default(), unix_now()).into_iter().cloned().collect()
+    }
+
+    // longer paths first, otherwise in the order they were set
+    fn header_for(&self, scope: &CookieScope, uri: &Uri) -> Option<String> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let secure = uri.scheme_str() == Some("https");
+        let stored = self.stored.lock().expect("cookie jar poisoned");
+
+        let mut matching = current(&stored.cookies, scope, unix_now()).into_iter()
+            .filter(|cookie| secure || !cookie.secure)
+            .filter(|cookie| match cookie.host_only {
+                true => host == cookie.domain,
+                false => domain_matches(&host, &cookie.domain),
+            })
+            .filter(|cookie| path_matches(uri.path(), &cookie.path))
+            .collect::<Vec<_>>();
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+
+        match matching.is_empty() {
+            true => None,
+            false => Some(matching.iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; ")),
+        }
+    }
+}
+
+// the latest of the cookies the scope sees with the same name, domain and path, unless it has expired
+fn current<'a>(cookies: &'a [StoredCookie], scope: &CookieScope, now: u64) -> Vec<&'a Cookie> {
+    let seen = cookies.iter().filter(|entry| scope.sees(entry.set_by)).collect::<Vec<_>>();
+
+    seen.iter()
+        .filter(|entry| !seen.iter()
+            .any(|other| other.set_at > entry.set_at && same_cookie(&other.cookie, &entry.cookie)))
+        .filter(|entry| !is_expired(&entry.cookie, now))
+        .map(|entry| &entry.cookie)
+        .collect()
+}
+
+fn same_cookie(a: &Cookie, b: &Cookie) -> bool {
+    a.name == b.name && a.domain == b.domain && a.path == b.path
+}
+
+// None for cookies which are malformed or for a domain the host does not belong to
+fn parse(uri: &Uri, set_cookie: &str, now: u64) -> Option<Cookie> {
+    let host = uri.host()?.to_ascii_lowercase();
+    let mut attributes = set_cookie.split(';');
+    let (name, value) = attributes.next()?.split_once('=')?;
+    if name.trim().is_empty() {
+        return None;
+    }
+
+    let mut cookie = Cookie {
+        name: name.trim().to_string(),
+        value: value.trim().to_string(),
+        domain: host.clone(),
+        path: default_path(uri.path()).to_string(),
+        host_only: true,
+        secure: false,
+        http_only: false,
+        expires: None,
+    };
+    let mut max_age = None;
+
+    for attribute in attributes {
+        let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let value = value.trim();
+
+        match key.trim().to_ascii_lowercase().as_str() {
+            "expires" => if let Ok(expires) = httpdate::parse_http_date(value) {
+                cookie.expires = Some(expires.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()));
+            },
+            "max-age" => if let Ok(seconds) = value.parse::<i64>() {
+                max_age = Some(seconds);
+            },
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                if !domain_matches(&host, &domain) {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" => cookie.path = match value.starts_with('/') {
+                true => value.to_string(),
+                false => default_path(uri.path()).to_string(),
+            },
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            _ => (),
+        }
+    }
+
+    // Max-Age wins over Expires, zero or less expires the cookie right away
+    if let Some(seconds) = max_age {
+        cookie.expires = Some(match u64::try_from(seconds) {
+            Ok(seconds) if seconds > 0 => now.saturating_add(seconds),
+            _ => 0,
+        });
+    }
+
+    Some(cookie)
+}
+
+// the directory of the request path
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(end) => &path[..end],
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.')) && host.parse::<IpAddr>().is_err())
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path.strip_prefix(cookie_path)
+        .is_some_and(|rest| rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+fn is_expired(cookie: &Cookie, now: u64) -> bool {
+    cookie.expires.is_some_and(|expires| expires <= now)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue, Request, Uri};
+
+    use crate::cookies::{CookieJar, CookieScope};
+
+    fn set_cookies(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("Set-Cookie", HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn cookie_header(jar: &CookieJar, uri: &'static str) -> Option<String> {
+        let mut request = Request::get(uri).body(()).unwrap();
+        jar.add_to(&CookieScope::default(), &mut request);
+        request.headers().get("Cookie").map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn applies_domain_and_path_rules() {
+        let jar = CookieJar::default();
+        jar.store(&CookieScope::default(), &Uri::from_static("https://api.example.com/auth/login"), &set_cookies(&[
+            "session=abc; Path=/; Secure; HttpOnly",
+            "scope=auth", // for /auth only
+            "shared=1; Domain=.Example.com; Path=/",
+            "other=1; Domain=other.com",
+            "gone=1; Max-Age=0",
+            "expired=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+            "=nameless",
+        ]));
+
+        assert_eq!(cookie_header(&jar, "https://api.example.com/auth/refresh").as_deref(),
+                   Some("scope=auth; session=abc; shared=1"));
+        assert_eq!(cookie_header(&jar, "https://api.example.com/authors").as_deref(), Some("session=abc; shared=1"));
+        assert_eq!(cookie_header(&jar, "http://api.example.com/").as_deref(), Some("shared=1"));
+        assert_eq!(cookie_header(&jar, "https://www.example.com/").as_deref(), Some("shared=1"));
+        assert_eq!(cookie_header(&jar, "https://example.org/"), None);
+
+        let names = jar.cookies().into_iter().map(|cookie| cookie.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["session", "scope", "shared"]);
+    }
+
+    #[test]
+    fn replaces_and_removes_cookies() {
+        let jar = CookieJar::default();
+        let uri = Uri::from_static("https://api.example.com/");
+        let scope = CookieScope::default();
+        jar.store(&scope, &uri, &set_cookies(&["a=1", "b=1", "c=1; Path=/v1"]));
+        jar.store(&scope, &uri, &set_cookies(&["a=2", "b=; Max-Age=-1", "c=2"]));
+
+        let mut request = Request::get("https://api.example.com/v1/items").header("Cookie", "x=0").body(()).unwrap();
+        jar.add_to(&scope, &mut request);
+        assert_eq!(request.headers()["Cookie"], "x=0; c=1; a=2; c=2");
+    }
+
+    #[test]
+    fn keeps_to_what_requests_see() {
+        let jar = CookieJar::default();
+        let uri = Uri::from_static("https://api.example.com/");
+        let scope = |request: usize, sees: &[usize]| {
+            CookieScope { request, sees: Some(sees.iter().copied().collect()) }
+        };
+        jar.store(&scope(0, &[]), &uri, &set_cookies(&["session=a", "theme=dark"]));
+        jar.store(&scope(1, &[]), &uri, &set_cookies(&["session=b"]));
+        jar.store(&scope(2, &[0]), &uri, &set_cookies(&["theme=; Max-Age=0"]));
+
+        let header = |scope: CookieScope| {
+            let mut request = Request::get("https://api.example.com/").body(()).unwrap();
+            jar.add_to(&scope, &mut request);
+            request.headers().get("Cookie").map(|value| value.to_str().unwrap().to_string())
+        };
+        assert_eq!(header(scope(3, &[0])).as_deref(), Some("session=a; theme=dark"));
+        assert_eq!(header(scope(3, &[1])).as_deref(), Some("session=b"));
+        assert_eq!(header(scope(3, &[0, 2])).as_deref(), Some("session=a"));
+        assert_eq!(header(scope(3, &[])), None);
+        assert_eq!(header(CookieScope::default()).as_deref(), Some("session=b"));
+    }
+}
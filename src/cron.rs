@@ -0,0 +1,155 @@
+use anyhow::{bail, Context, Result};
+use time::{Duration, OffsetDateTime};
+
+// the next match is searched for this far ahead, e.g. "0 0 30 2 *" never matches
+const MAX_SEARCH_DAYS: u32 = 5 * 366;
+
+// minute hour day-of-month month day-of-week, numbers only, in UTC
+// every field takes *, lists, ranges and steps, e.g. "*/15 8-18 * * 1-5"
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64, // bit n is set when n matches
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64, // 0 is Sunday, 7 is mapped to 0
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            bail!("cron expression {:?} needs 5 fields, not {}", expr, fields.len());
+        }
+
+        let field = |index: usize, name: &str, min: u32, max: u32| parse_field(fields[index], min, max)
+            .with_context(|| format!("invalid {} field in cron expression {:?}", name, expr));
+
+        let mut weekdays = field(4, "day-of-week", 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(CronSchedule {
+            minutes: field(0, "minute", 0, 59)?,
+            hours: field(1, "hour", 0, 23)?,
+            days: field(2, "day-of-month", 1, 31)?,
+            months: field(3, "month", 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    // the first matching minute after `after`
+    pub fn next_after(&self, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        let mut next = after.replace_second(0).ok()?.replace_nanosecond(0).ok()? + Duration::minutes(1);
+        let last = next + Duration::days(i64::from(MAX_SEARCH_DAYS));
+
+        while next <= last {
+            if !self.matches_day(next) {
+                next = next.date().next_day()?.midnight().assume_utc();
+            } else if !matches(self.hours, next.hour()) {
+                next = next.replace_minute(0).ok()? + Duration::hours(1);
+            } else if !matches(self.minutes, next.minute()) {
+                next += Duration::minutes(1);
+            } else {
+                return Some(next);
+            }
+        }
+
+        None
+    }
+
+    // as in cron, when both day fields are restricted, a day matching either of them is enough
+    fn matches_day(&self, at: OffsetDateTime) -> bool {
+        let month = matches(self.months, u8::from(at.month()));
+        let day = matches(self.days, at.day());
+        let weekday = matches(self.weekdays, at.weekday().number_days_from_sunday());
+
+        month && match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+fn matches(bits: u64, value: u8) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().context("invalid step")?),
+            None => (item, 1),
+        };
+        if step == 0 {
+            bail!("step has to be at least 1");
+        }
+
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (first.parse()?, last.parse()?),
+                None => {
+                    let value = range.parse()?;
+                    // as in cron, "5/10" means from 5 to the maximum
+                    (value, if item.contains('/') { max } else { value })
+                }
+            },
+        };
+        if first < min || last > max || first > last {
+            bail!("{} is out of range {}-{}", range, min, max);
+        }
+
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Date, Month, OffsetDateTime};
+
+    use crate::cron::CronSchedule;
+
+    fn at(year: i32, month: Month, day: u8, hour: u8, minute: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day).unwrap()
+            .with_hms(hour, minute, 0).unwrap()
+            .assume_utc()
+    }
+
+    fn next(expr: &str, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        CronSchedule::parse(expr).expect("invalid expression").next_after(after)
+    }
+
+    #[test]
+    fn finds_next_match() {
+        let start = at(2024, Month::February, 28, 23, 59);
+
+        assert_eq!(next("* * * * *", start), Some(at(2024, Month::February, 29, 0, 0)));
+        assert_eq!(next("*/15 8-18 * * 1-5", start), Some(at(2024, Month::February, 29, 8, 0)));
+        assert_eq!(next("30 12 1 * *", start), Some(at(2024, Month::March, 1, 12, 30)));
+        // Sunday, written as 7
+        assert_eq!(next("0 0 * * 7", start), Some(at(2024, Month::March, 3, 0, 0)));
+        // either the 15th or a Monday
+        assert_eq!(next("0 0 15 * 1", start), Some(at(2024, Month::March, 4, 0, 0)));
+        assert_eq!(next("0 0 30 2 *", start), None);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expr in &["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "x * * * *"] {
+            assert!(CronSchedule::parse(expr).is_err(), "{} accepted", expr);
+        }
+        assert!(CronSchedule::parse("0,30 */2 1-7 1/3 *").is_ok());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeSet, HashMap};
+
+use thiserror::Error;
+
+use crate::api::SingleHttpRequest;
+
+#[derive(Error, Debug)]
+pub enum DagError {
+    #[error("request id {0:?} is used more than once")]
+    DuplicateId(String),
+    #[error("request depends on unknown request id {0:?}")]
+    UnknownDependency(String),
+    #[error("requests depend on each other in a cycle, including {0:?}")]
+    Cycle(String),
+}
+
+// which requests of a batch depend on which, by position in the batch
+#[derive(Debug)]
+pub struct Dag {
+    ids: Vec<Option<String>>,
+    pub dependencies: Vec<Vec<usize>>,
+    pub dependents: Vec<Vec<usize>>,
+}
+
+impl Dag {
+    pub fn new(requests: &[SingleHttpRequest]) -> Result<Self, DagError> {
+        let mut positions = HashMap::new();
+        for (index, req) in requests.iter().enumerate() {
+            if let Some(id) = &req.id {
+                if positions.insert(id.as_str(), index).is_some() {
+                    return Err(DagError::DuplicateId(id.clone()));
+                }
+            }
+        }
+
+        let mut dependencies = vec![vec![]; requests.len()];
+        let mut dependents = vec![vec![]; requests.len()];
+        for (index, req) in requests.iter().enumerate() {
+            for dependency in &req.depends_on {
+                let position = *positions.get(dependency.as_str())
+                    .ok_or_else(|| DagError::UnknownDependency(dependency.clone()))?;
+
+                if !dependencies[index].contains(&position) {
+                    dependencies[index].push(position);
+                    dependents[position].push(index);
+                }
+            }
+        }
+
+        let dag = Dag {
+            ids: requests.iter().map(|req| req.id.clone()).collect(),
+            dependencies,
+            dependents,
+        };
+        dag.check_acyclic()?;
+
+        Ok(dag)
+    }
+
+    pub fn id(&self, index: usize) -> Option<&str> {
+        self.ids[index].as_deref()
+    }
+
+    // the dependencies of a request, and theirs
+    pub fn ancestors(&self, index: usize) -> BTreeSet<usize> {
+        let mut ancestors = BTreeSet::new();
+        let mut next = self.dependencies[index].clone();
+
+        while let Some(dependency) = next.pop() {
+            if ancestors.insert(dependency) {
+                next.extend(&self.dependencies[dependency]);
+            }
+        }
+
+        ancestors
+    }
+
+    // for messages, requests without an id are referred to by position
+    pub fn label(&self, index: usize) -> String {
+        match self.id(index) {
+            Some(id) => id.to_string(),
+            None => format!("request #{}", index),
+        }
+    }
+
+    // Kahn's algorithm, whatever cannot be put in order is part of or behind a cycle
+    fn check_acyclic(&self) -> Result<(), DagError> {
+        let mut waiting_for = self.dependencies.iter().map(Vec::len).collect::<Vec<_>>();
+        let mut ready = (0..waiting_for.len()).filter(|index| waiting_for[*index] == 0).collect::<Vec<_>>();
+        let mut ordered = 0;
+
+        while let Some(index) = ready.pop() {
+            ordered += 1;
+
+            for dependent in &self.dependents[index] {
+                waiting_for[*dependent] -= 1;
+                if waiting_for[*dependent] == 0 {
+                    ready.push(*dependent);
+                }
+            }
+        }
+
+        match waiting_for.iter().position(|count| *count > 0) {
+            Some(index) if ordered < waiting_for.len() => Err(DagError::Cycle(self.label(index))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::SingleHttpRequest;
+    use crate::dag::{Dag, DagError};
+
+    fn request(id: &str, depends_on: &[&str]) -> SingleHttpRequest {
+        SingleHttpRequest {
+            id: Some(id.to_string()).filter(|id| !id.is_empty()),
+            depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn builds_dependency_graph() {
+        let dag = Dag::new(&[request("login", &[]), request("a", &["login"]), request("", &["login", "a"])])
+            .expect("invalid graph");
+
+        assert_eq!(dag.dependencies, vec![vec![], vec![0], vec![0, 1]]);
+        assert_eq!(dag.dependents, vec![vec![1, 2], vec![2], vec![]]);
+        assert_eq!(dag.label(2), "request #2");
+
+        let dag = Dag::new(&[request("a", &[]), request("b", &["a"]), request("c", &["b"]), request("d", &[])])
+            .expect("invalid graph");
+        assert_eq!(dag.ancestors(2).into_iter().collect::<Vec<_>>(), vec![0, 1]);
+        assert!(dag.ancestors(3).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_graphs() {
+        assert!(matches!(Dag::new(&[request("a", &[]), request("a", &[])]), Err(DagError::DuplicateId(_))));
+        assert!(matches!(Dag::new(&[request("a", &["b"])]), Err(DagError::UnknownDependency(_))));
+        assert!(matches!(Dag::new(&[request("a", &["a"])]), Err(DagError::Cycle(_))));
+        assert!(matches!(Dag::new(&[request("a", &["c"]), request("b", &["a"]), request("c", &["b"]), request("d", &[])]),
+                         Err(DagError::Cycle(_))));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ring::rand::{SecureRandom, SystemRandom};
+use url::Url;
+use url::form_urlencoded::Serializer;
+
+use crate::api::{MultipartPart, SingleHttpRequest};
+
+const CONTENT_TYPE: &str = "Content-Type";
+const BOUNDARY_BYTES: usize = 16;
+
+// turns json, form and query into the raw uri, headers and body that are sent, this happens after
+// placeholders have been substituted, so that substituted values are encoded as well
+// multipart bodies may be binary, they are only checked here and built by encode_multipart
+pub fn encode_request(req: SingleHttpRequest) -> Result<SingleHttpRequest> {
+    let SingleHttpRequest {
+        id, depends_on, method, uri, mut headers, body, body_patch, json, form, multipart, query, parse_json, extract, expect,
+        paginate, follow_redirects
+    } = req;
+
+    let (body, content_type) = match (body, json, form, &multipart) {
+        (body, None, None, None) => (body, None),
+        (None, Some(json), None, None) =>
+            (Some(serde_json::to_string(&json).context("cannot serialize")?), Some("application/json")),
+        (None, None, Some(form), None) =>
+            (Some(Serializer::new(String::new()).extend_pairs(&form).finish()), Some("application/x-www-form-urlencoded")),
+        (None, None, None, Some(parts)) => {
+            parts.iter().try_for_each(|part| part_content(part).map(drop))?;
+            (None, None)
+        }
+        _ => bail!("body, json, form and multipart are mutually exclusive"),
+    };
+
+    // an explicit Content-Type wins
+    if let Some(content_type) = content_type {
+        if !headers.keys().any(|name| name.eq_ignore_ascii_case(CONTENT_TYPE)) {
+            headers.insert(CONTENT_TYPE.to_string(), content_type.to_string());
+        }
+    }
+
+    let uri = match query.is_empty() {
+        true => uri,
+        false => merge_query(&uri, &query)?,
+    };
+
+    Ok(SingleHttpRequest {
+        id,
+        depends_on,
+        method,
+        uri,
+        headers,
+        body,
+        body_patch,
+        json: None,
+        form: None,
+        multipart,
+        query: BTreeMap::new(),
+        parse_json,
+        extract,
+        expect,
+        paginate,
+        follow_redirects,
+    })
+}
+
+// the Content-Type with a new boundary, and the multipart/form-data body
+pub fn encode_multipart(parts: &[MultipartPart]) -> Result<(String, Vec<u8>)> {
+    let mut random = [0; BOUNDARY_BYTES];
+    SystemRandom::new().fill(&mut random).map_err(|_| anyhow!("cannot generate boundary"))?;
+    let boundary = format!("octoplex-{}", random.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"", quote(&part.name)).as_bytes());
+        if let Some(filename) = &part.filename {
+            body.extend_from_slice(format!("; filename=\"{}\"", quote(filename)).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+
+        let content_type = match (&part.content_type, &part.filename) {
+            (Some(content_type), _) => Some(content_type.as_str()),
+            (None, Some(_)) => Some("application/octet-stream"),
+            (None, None) => None,
+        };
+        if let Some(content_type) = content_type {
+            if content_type.contains(&['\r', '\n'][..]) {
+                bail!("content type of part {:?} contains a line break", part.name);
+            }
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(&part_content(part)?);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    Ok((format!("multipart/form-data; boundary={}", boundary), body))
+}
+
+fn part_content(part: &MultipartPart) -> Result<Vec<u8>> {
+    match (&part.text, &part.base64) {
+        (Some(text), None) => Ok(text.clone().into_bytes()),
+        (None, Some(base64)) => BASE64.decode(base64)
+            .with_context(|| format!("part {:?} is not valid base64", part.name)),
+        _ => bail!("part {:?} needs either text or base64", part.name),
+    }
+}
+
+// as browsers do, so names cannot end the quoted string or the header line
+fn quote(value: &str) -> String {
+    value.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+// parameters of the uri with the same name are replaced
+pub fn merge_query(uri: &str, query: &BTreeMap<String, String>) -> Result<String> {
+    let mut url = Url::parse(uri).with_context(|| format!("cannot add query to {}", uri))?;
+    let kept = url.query_pairs()
+        .filter(|(name, _)| !query.contains_key(name.as_ref()))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(kept)
+        .extend_pairs(query);
+
+    Ok(url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::{MultipartPart, SingleHttpRequest};
+    use crate::encoding::{encode_multipart, encode_request};
+
+    #[test]
+    fn encodes_json_form_and_query() {
+        let req = encode_request(SingleHttpRequest {
+            uri: "https://api.example.com/search?q=old&page=2".to_string(),
+            json: Some(json!({"name": "Bruce Campbell", "tags": ["a", "b"]})),
+            query: vec![("q".to_string(), "a&b c".to_string()), ("lang".to_string(), "ü".to_string())]
+                .into_iter().collect(),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(req.uri, "https://api.example.com/search?page=2&lang=%C3%BC&q=a%26b+c");
+        assert_eq!(req.body.as_deref(), Some(r#"{"name":"Bruce Campbell","tags":["a","b"]}"#));
+        assert_eq!(req.headers["Content-Type"], "application/json");
+
+        let req = encode_request(SingleHttpRequest {
+            uri: "https://api.example.com/token".to_string(),
+            headers: vec![("content-type".to_string(), "application/x-www-form-urlencoded; charset=utf-8".to_string())]
+                .into_iter().collect(),
+            form: Some(vec![("grant_type".to_string(), "password".to_string()), ("user".to_string(), "a b".to_string())]
+                .into_iter().collect()),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(req.body.as_deref(), Some("grant_type=password&user=a+b"));
+        assert_eq!(req.headers.len(), 1, "explicit Content-Type is kept");
+        assert_eq!(req.uri, "https://api.example.com/token");
+    }
+
+    #[test]
+    fn rejects_multiple_bodies() {
+        let req = SingleHttpRequest {
+            uri: "https://api.example.com/".to_string(),
+            body: Some("{}".to_string()),
+            json: Some(json!({})),
+            ..Default::default()
+        };
+
+        assert!(encode_request(req).is_err());
+
+        let req = SingleHttpRequest {
+            uri: "https://api.example.com/upload".to_string(),
+            multipart: Some(vec![MultipartPart {
+                name: "file".to_string(),
+                text: Some("hello".to_string()),
+                base64: Some("aGVsbG8=".to_string()),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        assert!(encode_request(req).is_err(), "text and base64 are exclusive");
+    }
+
+    #[test]
+    fn encodes_multipart() {
+        let parts = vec![
+            MultipartPart {
+                name: "title".to_string(),
+                text: Some("Evil Dead".to_string()),
+                ..Default::default()
+            },
+            MultipartPart {
+                name: "poster".to_string(),
+                filename: Some("a\"b.png".to_string()),
+                base64: Some("iVBORw0=".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        let (content_type, body) = encode_multipart(&parts).unwrap();
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").expect("no boundary");
+
+        let mut expected = format!("--{}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nEvil Dead\r\n\
+                                    --{}\r\nContent-Disposition: form-data; name=\"poster\"; filename=\"a%22b.png\"\r\n\
+                                    Content-Type: application/octet-stream\r\n\r\n", boundary, boundary).into_bytes();
+        expected.extend_from_slice(&[0x89, b'P', b'N', b'G', 0x0d]);
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        assert_eq!(body, expected);
+
+        let (other, _) = encode_multipart(&parts).unwrap();
+        assert_ne!(content_type, other, "boundary is not random");
+    }
+}
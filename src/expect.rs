@@ -0,0 +1,163 @@
+use std::ops::RangeInclusive;
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::HeaderMap;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::api::{ExpectSpec, StatusSpec};
+use crate::schema::Schema;
+
+// an ExpectSpec with its status ranges, regex and schema compiled, once for every response it is checked against
+pub struct Expectation {
+    spec: ExpectSpec,
+    status: Vec<RangeInclusive<u16>>,
+    body_regex: Option<Regex>,
+    schema: Option<Schema>,
+}
+
+pub fn compile(spec: &ExpectSpec) -> Result<Expectation> {
+    let status = spec.status.iter().map(status_range).collect::<Result<_>>()?;
+    let body_regex = spec.body_regex.as_deref().map(Regex::new).transpose().context("invalid body_regex")?;
+    if let Some(pointer) = spec.json.keys().find(|pointer| !pointer.is_empty() && !pointer.starts_with('/')) {
+        bail!("{:?} is not a JSON Pointer", pointer);
+    }
+    let schema = spec.schema.as_ref().map(Schema::compile).transpose().context("invalid schema")?;
+
+    Ok(Expectation { spec: spec.clone(), status, body_regex, schema })
+}
+
+// all failed assertions in one error
+pub fn check(expectation: &Expectation, status: u16, headers: &HeaderMap, body: &str) -> Result<()> {
+    let spec = &expectation.spec;
+    let mut failures = vec![];
+
+    let accepted = match expectation.status.is_empty() {
+        true => status < 400,
+        false => expectation.status.iter().any(|range| range.contains(&status)),
+    };
+    if !accepted {
+        let expected = match spec.status.is_empty() {
+            true => "below 400".to_string(),
+            false => spec.status.iter().map(describe).collect::<Vec<_>>().join(" or "),
+        };
+        failures.push(format!("status {} is not {}", status, expected));
+    }
+
+    for name in spec.headers.iter().filter(|name| !headers.contains_key(name.as_str())) {
+        failures.push(format!("header {} is missing", name));
+    }
+
+    if let Some(body_regex) = &expectation.body_regex {
+        if !body_regex.is_match(body) {
+            failures.push(format!("body does not match {}", body_regex));
+        }
+    }
+
+    if !spec.json.is_empty() || expectation.schema.is_some() {
+        match serde_json::from_str::<Value>(body) {
+            Ok(doc) => {
+                for (pointer, expected) in &spec.json {
+                    match doc.pointer(pointer) {
+                        Some(value) if value == expected => (),
+                        Some(value) => failures.push(format!("{} is {}, expected {}", pointer, value, expected)),
+                        None => failures.push(format!("nothing at {}, expected {}", pointer, expected)),
+                    }
+                }
+                if let Some(schema) = &expectation.schema {
+                    failures.extend(schema.violations(&doc).into_iter()
+                        .map(|violation| format!("schema {}", violation)));
+                }
+            }
+            Err(e) => failures.push(format!("body is not JSON: {}", e)),
+        }
+    }
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("expectation failed: {}", failures.join("; "))),
+    }
+}
+
+fn status_range(status: &StatusSpec) -> Result<RangeInclusive<u16>> {
+    let range = match status {
+        StatusSpec::Code(code) => *code..=*code,
+        StatusSpec::Range(range) => match range.split_once('-') {
+            Some((first, last)) => first.trim().parse()?..=last.trim().parse()?,
+            None => match range.strip_suffix("xx").map(str::parse::<u16>) {
+                Some(Ok(class)) => match class.checked_mul(100).filter(|first| *first <= u16::MAX - 99) {
+                    Some(first) => first..=first + 99,
+                    None => bail!("invalid status range {:?}", range),
+                },
+                _ => bail!("invalid status range {:?}", range),
+            },
+        },
+    };
+
+    match (100..=599).contains(range.start()) && (100..=599).contains(range.end()) && !range.is_empty() {
+        true => Ok(range),
+        false => bail!("invalid status range {}", describe(status)),
+    }
+}
+
+fn describe(status: &StatusSpec) -> String {
+    match status {
+        StatusSpec::Code(code) => code.to_string(),
+        StatusSpec::Range(range) => range.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+    use serde_json::json;
+
+    use crate::api::ExpectSpec;
+    use crate::expect::{check, compile};
+
+    fn spec(spec: serde_json::Value) -> ExpectSpec {
+        serde_json::from_value(spec).expect("invalid spec")
+    }
+
+    #[test]
+    fn checks_expectations() {
+        let mut headers = HeaderMap::new();
+        headers.insert("ETag", HeaderValue::from_static("\"v1\""));
+        let body = r#"{"data": {"id": 7, "state": "active"}}"#;
+
+        let passing = spec(json!({
+            "status": ["2xx", 304],
+            "headers": ["etag"],
+            "body_regex": "\"state\":\\s*\"active\"",
+            "json": { "/data/id": 7 },
+            "schema": { "required": ["data"] }
+        }));
+        assert!(check(&compile(&passing).unwrap(), 200, &headers, body).is_ok());
+
+        let failing = spec(json!({
+            "status": ["200-204", 404],
+            "headers": ["X-Request-Id"],
+            "json": { "/data/id": 8, "/data/name": "x" },
+            "schema": { "properties": { "data": { "type": "array" } } }
+        }));
+        let error = check(&compile(&failing).unwrap(), 500, &headers, body).unwrap_err().to_string();
+        assert_eq!(error, "expectation failed: status 500 is not 200-204 or 404; header X-Request-Id is missing; \
+                           /data/id is 7, expected 8; nothing at /data/name, expected \"x\"; \
+                           schema /data: expected array, got object");
+
+        let nothing = compile(&spec(json!({}))).unwrap();
+        assert!(check(&nothing, 399, &headers, "").is_ok());
+        assert!(check(&nothing, 400, &headers, "").is_err());
+        assert!(check(&compile(&spec(json!({ "json": { "/id": 1 } }))).unwrap(), 200, &headers, "<html>").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_expectations() {
+        for invalid in &[json!({ "status": ["2x"] }), json!({ "status": [700] }), json!({ "status": ["300-200"] }),
+                         json!({ "status": ["700xx"] }), json!({ "status": ["65535xx"] }), json!({ "body_regex": "(" }),
+                         json!({ "json": { "id": 1 } }), json!({ "schema": 1 }),
+                         json!({ "schema": { "format": "uri" } })] {
+            assert!(compile(&spec(invalid.clone())).is_err(), "{} accepted", invalid);
+        }
+    }
+}
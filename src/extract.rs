@@ -0,0 +1,223 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::HeaderMap;
+use http::header::HeaderName;
+use serde_json::{Map, Value};
+
+use crate::api::ExtractSpec;
+
+// one step of a JSONPath, "$..items[*]['name']" is Descendants, Child, Wildcard, Child
+#[derive(Debug, PartialEq)]
+enum Step {
+    Child(String),
+    Index(i64), // negative counts from the end
+    Wildcard,
+    Descendants, // the node itself and everything below it
+}
+
+pub fn validate(spec: &ExtractSpec) -> Result<()> {
+    for (name, expr) in &spec.values {
+        Expression::parse(expr).with_context(|| format!("invalid expression for {}", name))?;
+    }
+
+    Ok(())
+}
+
+// the headers that are kept, and the values by name, if any are to be extracted from the body
+pub fn extract(spec: &ExtractSpec, headers: HeaderMap, body: &str) -> Result<(HeaderMap, Option<Map<String, Value>>)> {
+    let headers = match &spec.headers {
+        Some(names) => project_headers(names, &headers),
+        None => headers,
+    };
+    if spec.values.is_empty() {
+        return Ok((headers, None));
+    }
+
+    let doc = serde_json::from_str::<Value>(body).context("the response is not JSON")?;
+    let values = spec.values.iter()
+        .map(|(name, expr)| {
+            let value = Expression::parse(expr)?.evaluate(&doc)
+                .with_context(|| format!("cannot extract {}", name))?;
+            Ok((name.clone(), value))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((headers, Some(values)))
+}
+
+fn project_headers(names: &[String], headers: &HeaderMap) -> HeaderMap {
+    let mut projected = HeaderMap::new();
+
+    for name in names.iter().filter_map(|name| name.parse::<HeaderName>().ok()) {
+        for value in headers.get_all(&name) {
+            projected.append(name.clone(), value.clone());
+        }
+    }
+
+    projected
+}
+
+// JSON Pointer (RFC 6901) unless it starts with $, which makes it JSONPath
+enum Expression<'a> {
+    Pointer(&'a str),
+    Path(Vec<Step>),
+}
+
+impl<'a> Expression<'a> {
+    fn parse(expr: &'a str) -> Result<Self> {
+        match expr.starts_with('$') {
+            true => Ok(Expression::Path(parse_path(expr)?)),
+            false if expr.is_empty() || expr.starts_with('/') => Ok(Expression::Pointer(expr)),
+            false => bail!("{:?} is neither a JSON Pointer nor a JSONPath", expr),
+        }
+    }
+
+    // a pointer has to match, a path results in the list of its matches
+    fn evaluate(&self, doc: &Value) -> Result<Value> {
+        match self {
+            Expression::Pointer(pointer) => doc.pointer(pointer).cloned()
+                .ok_or_else(|| anyhow!("nothing at {}", pointer)),
+            Expression::Path(steps) => Ok(Value::Array(select(steps, doc).into_iter().cloned().collect())),
+        }
+    }
+}
+
+// $, .name, .*, ..name, [n], [*] and ['name'], without filters, slices and unions
+fn parse_path(expr: &str) -> Result<Vec<Step>> {
+    let mut rest = expr.strip_prefix('$').ok_or_else(|| anyhow!("JSONPath has to start with $"))?;
+    let mut steps = vec![];
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            steps.push(Step::Descendants);
+            rest = match after.starts_with('[') {
+                true => after,
+                false => parse_name(after, &mut steps)?,
+            };
+        } else if let Some(after) = rest.strip_prefix('.') {
+            rest = parse_name(after, &mut steps)?;
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(|| anyhow!("unclosed [ in {:?}", expr))?;
+            steps.push(parse_bracket(after[..end].trim())?);
+            rest = &after[end + 1..];
+        } else {
+            bail!("unexpected {:?} in {:?}", rest, expr);
+        }
+    }
+
+    Ok(steps)
+}
+
+fn parse_name<'a>(text: &'a str, steps: &mut Vec<Step>) -> Result<&'a str> {
+    let end = text.find(&['.', '['][..]).unwrap_or(text.len());
+    steps.push(match &text[..end] {
+        "" => bail!("missing name after ."),
+        "*" => Step::Wildcard,
+        name => Step::Child(name.to_string()),
+    });
+
+    Ok(&text[end..])
+}
+
+fn parse_bracket(inner: &str) -> Result<Step> {
+    let quoted = inner.len() >= 2 && (inner.starts_with('\'') && inner.ends_with('\'')
+        || inner.starts_with('"') && inner.ends_with('"'));
+
+    Ok(match inner {
+        "*" => Step::Wildcard,
+        _ if quoted => Step::Child(inner[1..inner.len() - 1].to_string()),
+        _ => Step::Index(inner.parse().with_context(|| format!("invalid index {:?}", inner))?),
+    })
+}
+
+fn select<'v>(steps: &[Step], doc: &'v Value) -> Vec<&'v Value> {
+    let mut nodes = vec![doc];
+
+    for step in steps {
+        nodes = nodes.into_iter()
+            .flat_map(|node| match step {
+                Step::Child(name) => node.get(name).into_iter().collect(),
+                Step::Index(index) => {
+                    let len = node.as_array().map(Vec::len).unwrap_or_default() as i64;
+                    let index = if *index < 0 { len + index } else { *index };
+                    usize::try_from(index).ok().and_then(|index| node.get(index)).into_iter().collect()
+                }
+                Step::Wildcard => match node {
+                    Value::Array(values) => values.iter().collect(),
+                    Value::Object(map) => map.values().collect(),
+                    _ => vec![],
+                },
+                Step::Descendants => {
+                    let mut all = vec![];
+                    descendants(node, &mut all);
+                    all
+                }
+            })
+            .collect();
+    }
+
+    nodes
+}
+
+fn descendants<'v>(node: &'v Value, all: &mut Vec<&'v Value>) {
+    all.push(node);
+    match node {
+        Value::Array(values) => values.iter().for_each(|value| descendants(value, all)),
+        Value::Object(map) => map.values().for_each(|value| descendants(value, all)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+    use serde_json::json;
+
+    use crate::api::ExtractSpec;
+    use crate::extract::{extract, parse_path, validate, Step};
+
+    fn spec(values: &[(&str, &str)], headers: Option<&[&str]>) -> ExtractSpec {
+        ExtractSpec {
+            values: values.iter().map(|(name, expr)| (name.to_string(), expr.to_string())).collect(),
+            headers: headers.map(|names| names.iter().map(|name| name.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn extracts_values_and_headers() {
+        let body = r#"{"data": {"id": 7, "items": [{"name": "a", "tags": [{"name": "x"}]}, {"name": "b"}]}}"#;
+        let mut headers = HeaderMap::new();
+        headers.insert("ETag", HeaderValue::from_static("\"v1\""));
+        headers.insert("Server", HeaderValue::from_static("nginx"));
+
+        let spec = spec(&[
+            ("id", "/data/id"),
+            ("names", "$.data.items[*].name"),
+            ("last", "$['data'].items[-1]"),
+            ("all_names", "$..name"),
+        ], Some(&["etag", "X-Missing"]));
+        let (headers, values) = extract(&spec, headers, body).expect("not extracted");
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["ETag"], "\"v1\"");
+        assert_eq!(serde_json::Value::Object(values.unwrap()), json!({
+            "id": 7,
+            "names": ["a", "b"],
+            "last": [{"name": "b"}],
+            "all_names": ["a", "x", "b"],
+        }));
+    }
+
+    #[test]
+    fn reports_extraction_errors() {
+        assert!(extract(&spec(&[("id", "/data/id")], None), HeaderMap::new(), "{}").is_err());
+        assert!(extract(&spec(&[("id", "/id")], None), HeaderMap::new(), "<html>").is_err());
+        assert!(extract(&spec(&[], None), HeaderMap::new(), "<html>").unwrap().1.is_none());
+
+        for expr in &["data/id", "$.", "$[0", "$[x]", "$x"] {
+            assert!(validate(&spec(&[("id", expr)], None)).is_err(), "{} accepted", expr);
+        }
+        assert_eq!(parse_path("$..[0]").unwrap(), vec![Step::Descendants, Step::Index(0)]);
+    }
+}
@@ -1,12 +1,14 @@
 use async_trait::async_trait;
 use anyhow::{Result, Context};
 use http::Response;
-use hyper::{Client, Request, Body};
+use hyper::{Client, Request, Body, Uri};
 use hyper::client::HttpConnector;
 use hyper::client::connect::dns::GaiResolver;
 use hyper_tls::HttpsConnector;
 use native_tls::TlsConnector;
 
+use crate::target_policy::{GuardedConnector, TargetPolicy};
+
 // the purpose of this trait is to decouple dependent code from the implementation and allow mocking
 #[async_trait]
 pub trait HttpClient {
@@ -17,7 +19,7 @@ pub trait HttpClient {
 // XXX as long as we expose Body, Parts, Response and Request, the job is not yet done
 #[derive(Clone)]
 pub struct OctoplexHttpClient {
-    inner: Client<HttpsConnector<HttpConnector<GaiResolver>>, Body>
+    inner: Client<HttpsConnector<GuardedConnector>, Body>
 }
 
 #[async_trait]
@@ -28,13 +30,13 @@ impl HttpClient for OctoplexHttpClient {
     }
 }
 
-pub fn make_hyper_client() -> Result<OctoplexHttpClient> {
+pub fn make_hyper_client(policy: TargetPolicy) -> Result<OctoplexHttpClient> {
     let http_connector = {
         // XXX TokioThreadpoolGaiResolver seems to be broken in hyper-0.13.0-alpha.4
         //let mut http_connector = HttpConnector::new_with_resolver(TokioThreadpoolGaiResolver::new());
         let mut http_connector = HttpConnector::new_with_resolver(GaiResolver::new());
         http_connector.enforce_http(false);
-        http_connector
+        GuardedConnector::new(http_connector, policy)
     };
     let tls_connector = TlsConnector::new().context("cannot create TlsConnector")?;
     let https_connector = HttpsConnector::from((http_connector, tls_connector.into()));
@@ -43,6 +45,24 @@ pub fn make_hyper_client() -> Result<OctoplexHttpClient> {
     Ok(OctoplexHttpClient { inner })
 }
 
+// the connection could not be established, so the request cannot have reached the target
+pub fn is_connect_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_connect))
+}
+
+// scheme://host:port with the default port filled in, this is what per-upstream state is keyed by
+pub fn origin_of(uri: &Uri) -> Option<String> {
+    let scheme = uri.scheme_str()?;
+    let port = match (uri.port_u16(), scheme) {
+        (Some(port), _) => port,
+        (None, "http") => 80,
+        (None, "https") => 443,
+        (None, _) => return None,
+    };
+
+    Some(format!("{}://{}:{}", scheme, uri.host()?.to_ascii_lowercase(), port))
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use anyhow::Result;
@@ -1,28 +1,50 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::{Context, Error, Result};
-use hyper::{Body, Method, Request, Response, header};
+use hyper::{Body, Method, Request, Response, StatusCode, header};
 use hyper::server::Server;
 use hyper::body::aggregate;
 use hyper::service::{service_fn, make_service_fn};
 use hyper::server::conn::AddrStream;
 
-use crate::api::{HealthResponse, OctoplexError};
+use crate::api::{AuctionRequest, ChecksResponse, HealthResponse, RecipesResponse, OctoplexError, OctoplexRequest, StatsResponse,
+                 UpstreamHealthResponse};
+use crate::auth::{Authenticator, ClientIdentity};
+use crate::checks::{CheckConfig, CheckScheduler};
+use crate::jobs::JobManager;
 use crate::multiplexer::Multiplexer;
+use crate::quota::{QuotaExceeded, QuotaManager};
+use crate::recipes::{RecipeBook, RecipeError};
 
-pub async fn launch_http_server(addr: &SocketAddr, multi: &Multiplexer) -> Result<()>
+const JOBS_PREFIX: &str = "/jobs/";
+const CHECKS_PREFIX: &str = "/checks/";
+const RECIPES_PREFIX: &str = "/recipes/";
+
+// everything the routes need, cheap to clone for every connection and request
+#[derive(Clone)]
+pub struct ServerState {
+    pub multi: Multiplexer,
+    pub auth: Arc<Authenticator>,
+    pub quotas: Arc<QuotaManager>,
+    pub jobs: Arc<JobManager>,
+    pub checks: Arc<CheckScheduler>,
+    pub recipes: Arc<RecipeBook>,
+}
+
+pub async fn launch_http_server(addr: &SocketAddr, state: &ServerState) -> Result<()>
 {
     // XXX the nested blocks and repeated clones look horrible, but all this seems necessary
     // https://vorner.github.io/2020/04/13/hyper-traps.html
     let connection_handler = make_service_fn(|_conn: &AddrStream| {
-        let multi = multi.clone();
+        let state = state.clone();
 
         async {
             let request_handler = service_fn(move |req| {
-                let multi = multi.clone();
+                let state = state.clone();
 
                 async move {
-                    router(&multi, req).await
+                    router(&state, req).await
                 }
             });
 
@@ -38,14 +60,80 @@ pub async fn launch_http_server(addr: &SocketAddr, multi: &Multiplexer) -> Resul
         Ok(server.await?)
 }
 
-async fn router(multi: &Multiplexer, req: Request<Body>) -> Result<Response<Body>> {
+async fn router(state: &ServerState, req: Request<Body>) -> Result<Response<Body>> {
     let method = req.method();
     let uri_path = req.uri().path();
 
     match (method, uri_path) {
         (&Method::GET, "/") |
         (&Method::GET, "/healthz") => route_health_check().await,
-        (&Method::POST, "/multiplex") => route_multiplex(multi, req).await,
+        (&Method::GET, "/stats") => match state.auth.authenticate(req.headers()) {
+            Ok(_) => route_stats(state).await,
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::GET, "/metrics") => match state.auth.authenticate(req.headers()) {
+            Ok(_) => route_metrics(state).await,
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::GET, "/checks") => match state.auth.authenticate(req.headers()) {
+            Ok(identity) if state.auth.is_admin(&identity) => route_checks(state).await,
+            Ok(identity) => forbidden_response(format!("{} may not manage checks", identity)),
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::GET, _) | (&Method::PUT, _) | (&Method::DELETE, _) if uri_path.starts_with(CHECKS_PREFIX) => {
+            match state.auth.authenticate(req.headers()) {
+                Ok(identity) if state.auth.is_admin(&identity) => {
+                    let name = uri_path[CHECKS_PREFIX.len()..].to_string();
+                    route_check(state, &name, req).await
+                }
+                Ok(identity) => forbidden_response(format!("{} may not manage checks", identity)),
+                Err(e) => unauthorized_response(e),
+            }
+        }
+        (&Method::POST, _) if uri_path.starts_with(RECIPES_PREFIX) => match state.auth.authenticate(req.headers()) {
+            Ok(identity) => {
+                let name = uri_path[RECIPES_PREFIX.len()..].to_string();
+                route_run_recipe(state, &identity, &name, req).await
+            }
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::GET, "/recipes") => match state.auth.authenticate(req.headers()) {
+            Ok(identity) if state.auth.is_admin(&identity) => route_recipes(state).await,
+            Ok(identity) => forbidden_response(format!("{} may not manage recipes", identity)),
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::GET, _) | (&Method::PUT, _) | (&Method::DELETE, _) if uri_path.starts_with(RECIPES_PREFIX) => {
+            match state.auth.authenticate(req.headers()) {
+                Ok(identity) if state.auth.is_admin(&identity) => {
+                    let name = uri_path[RECIPES_PREFIX.len()..].to_string();
+                    route_recipe(state, &name, req).await
+                }
+                Ok(identity) => forbidden_response(format!("{} may not manage recipes", identity)),
+                Err(e) => unauthorized_response(e),
+            }
+        }
+        (&Method::GET, "/upstreams/health") => match state.auth.authenticate(req.headers()) {
+            Ok(_) => route_upstream_health(state).await,
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::POST, "/multiplex") => match state.auth.authenticate(req.headers()) {
+            Ok(identity) => route_multiplex(state, &identity, req).await,
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::POST, "/auction") => match state.auth.authenticate(req.headers()) {
+            Ok(identity) => route_auction(state, &identity, req).await,
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::POST, "/jobs") => match state.auth.authenticate(req.headers()) {
+            Ok(identity) => route_submit_job(state, &identity, req).await,
+            Err(e) => unauthorized_response(e),
+        },
+        (&Method::GET, _) | (&Method::DELETE, _) if uri_path.starts_with(JOBS_PREFIX) => {
+            match state.auth.authenticate(req.headers()) {
+                Ok(identity) => route_job(state, &identity, req.method(), &uri_path[JOBS_PREFIX.len()..]).await,
+                Err(e) => unauthorized_response(e),
+            }
+        }
         _ => route_not_found().await,
     }
 }
@@ -63,7 +151,92 @@ async fn route_health_check() -> Result<Response<Body>> {
         .context("cannot build response")
 }
 
-async fn route_multiplex(multi: &Multiplexer, req: Request<Body>) -> Result<Response<Body>> {
+async fn route_stats(state: &ServerState) -> Result<Response<Body>> {
+    let resp = StatsResponse {
+        circuits: state.multi.circuit_stats(),
+    };
+    let resp_json = serde_json::to_string(&resp).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(resp_json))
+        .context("cannot build response")
+}
+
+async fn route_upstream_health(state: &ServerState) -> Result<Response<Body>> {
+    let resp = UpstreamHealthResponse {
+        upstreams: state.multi.upstream_health(),
+    };
+    let resp_json = serde_json::to_string(&resp).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(resp_json))
+        .context("cannot build response")
+}
+
+async fn route_metrics(state: &ServerState) -> Result<Response<Body>> {
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .body(Body::from(state.checks.metrics()))
+        .context("cannot build response")
+}
+
+async fn route_checks(state: &ServerState) -> Result<Response<Body>> {
+    let resp = ChecksResponse {
+        checks: state.checks.statuses(),
+    };
+    let resp_json = serde_json::to_string(&resp).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(resp_json))
+        .context("cannot build response")
+}
+
+// PUT creates or replaces a check, DELETE removes it
+async fn route_check(state: &ServerState, name: &str, req: Request<Body>) -> Result<Response<Body>> {
+    use bytes::Buf;
+
+    let check = match *req.method() {
+        Method::PUT => {
+            let entire_body = match aggregate(req).await {
+                Ok(b) => b,
+                Err(e) => return error_response(e),
+            };
+
+            let check_config: CheckConfig = match serde_json::from_reader(entire_body.reader()) {
+                Ok(b) => b,
+                Err(e) => return error_response(e),
+            };
+
+            match state.checks.upsert(name, check_config) {
+                Ok(check) => Some(check),
+                Err(e) => return error_response(format!("{:#}", e)),
+            }
+        }
+        Method::DELETE => state.checks.remove(name),
+        _ => state.checks.status(name),
+    };
+    let check = match check {
+        Some(check) => check,
+        None => return route_not_found().await,
+    };
+    let check_json = serde_json::to_string(&check).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(check_json))
+        .context("cannot build response")
+}
+
+async fn route_multiplex(state: &ServerState, identity: &ClientIdentity,
+                         req: Request<Body>) -> Result<Response<Body>> {
     // deserialize json body
     // XXX ensure zero-copy
 
@@ -75,12 +248,25 @@ async fn route_multiplex(multi: &Multiplexer, req: Request<Body>) -> Result<Resp
         Err(e) => return Ok(error_response(e)?),
     };
 
-    let oct_req = match serde_json::from_reader(entire_body.reader()) {
+    let oct_req: OctoplexRequest = match serde_json::from_reader(entire_body.reader()) {
         Ok(b) => b,
         Err(e) => return Ok(error_response(e)?),
     };
 
-    let oct_resp = match multi.handle(oct_req).await {
+    debug!("batch of {} requests from {}", oct_req.request_count(), identity);
+
+    run_batch(state, identity, oct_req).await
+}
+
+// shared by everything that runs a batch on behalf of a client and answers with its response
+async fn run_batch(state: &ServerState, identity: &ClientIdentity,
+                   oct_req: OctoplexRequest) -> Result<Response<Body>> {
+    if let Err(e) = state.quotas.admit(identity, oct_req.quota_cost()) {
+        return too_many_requests_response(e);
+    }
+
+    let limits = state.quotas.limits_for(identity);
+    let oct_resp = match state.multi.handle_detached(oct_req, &limits).await {
         Ok(r) => r,
         Err(e) => return Ok(error_response(e)?),
     };
@@ -94,6 +280,177 @@ async fn route_multiplex(multi: &Multiplexer, req: Request<Body>) -> Result<Resp
         .context("cannot build response")
 }
 
+async fn route_run_recipe(state: &ServerState, identity: &ClientIdentity, name: &str,
+                          req: Request<Body>) -> Result<Response<Body>> {
+    use bytes::Buf;
+
+    let entire_body = match aggregate(req).await {
+        Ok(b) => b,
+        Err(e) => return error_response(e),
+    };
+
+    let params: serde_json::Map<String, serde_json::Value> = match entire_body.remaining() {
+        0 => serde_json::Map::new(),
+        _ => match serde_json::from_reader(entire_body.reader()) {
+            Ok(b) => b,
+            Err(e) => return error_response(e),
+        },
+    };
+
+    let oct_req = match state.recipes.expand(name, &params) {
+        Ok(r) => r,
+        Err(RecipeError::NotFound(_)) => return route_not_found().await,
+        Err(e) => return error_response(e),
+    };
+
+    debug!("recipe {} with {} requests from {}", name, oct_req.request_count(), identity);
+
+    run_batch(state, identity, oct_req).await
+}
+
+async fn route_recipes(state: &ServerState) -> Result<Response<Body>> {
+    let resp = RecipesResponse {
+        recipes: state.recipes.list(),
+    };
+    let resp_json = serde_json::to_string(&resp).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(resp_json))
+        .context("cannot build response")
+}
+
+// PUT creates or replaces a recipe, DELETE removes it
+async fn route_recipe(state: &ServerState, name: &str, req: Request<Body>) -> Result<Response<Body>> {
+    use bytes::Buf;
+
+    let recipe = match *req.method() {
+        Method::PUT => {
+            let entire_body = match aggregate(req).await {
+                Ok(b) => b,
+                Err(e) => return error_response(e),
+            };
+
+            let template: serde_json::Value = match serde_json::from_reader(entire_body.reader()) {
+                Ok(b) => b,
+                Err(e) => return error_response(e),
+            };
+
+            match state.recipes.upsert(name, template) {
+                Ok(recipe) => Some(recipe),
+                Err(e) => return error_response(e),
+            }
+        }
+        Method::DELETE => state.recipes.remove(name),
+        _ => state.recipes.get(name),
+    };
+    let recipe = match recipe {
+        Some(recipe) => recipe,
+        None => return route_not_found().await,
+    };
+    let recipe_json = serde_json::to_string(&recipe).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(recipe_json))
+        .context("cannot build response")
+}
+
+async fn route_auction(state: &ServerState, identity: &ClientIdentity,
+                       req: Request<Body>) -> Result<Response<Body>> {
+    use bytes::Buf;
+
+    let entire_body = match aggregate(req).await {
+        Ok(b) => b,
+        Err(e) => return error_response(e),
+    };
+
+    let auction_req: AuctionRequest = match serde_json::from_reader(entire_body.reader()) {
+        Ok(b) => b,
+        Err(e) => return error_response(e),
+    };
+
+    debug!("auction with {} bidders from {}", auction_req.bidders.len(), identity);
+
+    if let Err(e) = state.quotas.admit(identity, auction_req.bidders.len()) {
+        return too_many_requests_response(e);
+    }
+
+    let limits = state.quotas.limits_for(identity);
+    let auction_resp = match state.multi.auction(auction_req, &limits).await {
+        Ok(r) => r,
+        Err(e) => return error_response(e),
+    };
+
+    let auction_resp_json = serde_json::to_string(&auction_resp).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(auction_resp_json))
+        .context("cannot build response")
+}
+
+async fn route_submit_job(state: &ServerState, identity: &ClientIdentity,
+                          req: Request<Body>) -> Result<Response<Body>> {
+    use bytes::Buf;
+
+    let entire_body = match aggregate(req).await {
+        Ok(b) => b,
+        Err(e) => return error_response(e),
+    };
+
+    let oct_req: OctoplexRequest = match serde_json::from_reader(entire_body.reader()) {
+        Ok(b) => b,
+        Err(e) => return error_response(e),
+    };
+
+    debug!("job of {} requests from {}", oct_req.request_count(), identity);
+
+    if let Err(e) = state.quotas.admit(identity, oct_req.quota_cost()) {
+        return too_many_requests_response(e);
+    }
+
+    let limits = state.quotas.limits_for(identity);
+    let oct_req = match state.multi.validate(oct_req, &limits) {
+        Ok(r) => r,
+        Err(e) => return error_response(e),
+    };
+    let job = match state.jobs.submit(state.multi.clone(), identity, oct_req, limits) {
+        Ok(job) => job,
+        Err(e) => return error_response_with_status(StatusCode::INTERNAL_SERVER_ERROR, e),
+    };
+    let job_json = serde_json::to_string(&job).context("cannot serialize")?;
+
+    Response::builder()
+        .status(202)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(job_json))
+        .context("cannot build response")
+}
+
+// GET returns the progress, DELETE cancels a running job or removes a finished one
+async fn route_job(state: &ServerState, identity: &ClientIdentity, method: &Method,
+                   id: &str) -> Result<Response<Body>> {
+    let job = match *method {
+        Method::DELETE => state.jobs.cancel(identity, id),
+        _ => state.jobs.get(identity, id),
+    };
+    let job = match job {
+        Some(job) => job,
+        None => return route_not_found().await,
+    };
+    let job_json = serde_json::to_string(&job).context("cannot serialize")?;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(job_json))
+        .context("cannot build response")
+}
+
 async fn route_not_found() -> Result<Response<Body>> {
     Response::builder()
         .status(404)
@@ -102,14 +459,42 @@ async fn route_not_found() -> Result<Response<Body>> {
         .context("cannot build response")
 }
 
+fn unauthorized_response(err: impl ToString) -> Result<Response<Body>> {
+    let mut resp = error_response_with_status(StatusCode::UNAUTHORIZED, err)?;
+    resp.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+
+    Ok(resp)
+}
+
+fn forbidden_response(err: impl ToString) -> Result<Response<Body>> {
+    error_response_with_status(StatusCode::FORBIDDEN, err)
+}
+
+fn too_many_requests_response(err: QuotaExceeded) -> Result<Response<Body>> {
+    let retry_after = err.retry_after();
+    let mut resp = error_response_with_status(StatusCode::TOO_MANY_REQUESTS, err)?;
+
+    if let Some(retry_after) = retry_after {
+        // Retry-After has a resolution of seconds, rounding down would invite retrying too early
+        let retry_after_sec = retry_after.as_secs().saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+        resp.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after_sec));
+    }
+
+    Ok(resp)
+}
+
 fn error_response(err: impl ToString) -> Result<Response<Body>> {
+    error_response_with_status(StatusCode::BAD_REQUEST, err)
+}
+
+fn error_response_with_status(status: StatusCode, err: impl ToString) -> Result<Response<Body>> {
     let error = OctoplexError {
         error: err.to_string()
     };
     let error_json = serde_json::to_string(&error).context("cannot serialize")?;
 
     Response::builder()
-        .status(400)
+        .status(status)
         .header("Content-Type", "application/json; charset=utf-8")
         .body(Body::from(error_json))
         .context("cannot build response")
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::task::JoinHandle;
+
+use crate::api::{JobResponse, JobState, OctoplexRequest, OctoplexResponse, SingleOutcome};
+use crate::auth::ClientIdentity;
+use crate::http_client::HttpClient;
+use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+
+const JOB_ID_BYTES: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobsConfig {
+    #[serde(default = "default_ttl_sec")]
+    pub ttl_sec: u64, // how long results are kept after a job has finished
+    #[serde(default)]
+    pub store_dir: Option<PathBuf>, // finished jobs are written here, and restored at startup
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            ttl_sec: default_ttl_sec(),
+            store_dir: None,
+        }
+    }
+}
+
+fn default_ttl_sec() -> u64 {
+    3600
+}
+
+// what is written to the store, one file per job
+#[derive(Serialize, Deserialize)]
+struct StoredJob {
+    client_id: String,
+    expires_unix_sec: u64,
+    job: JobResponse,
+}
+
+struct Job {
+    client_id: String,
+    status: JobResponse,
+    expires: Option<SystemTime>, // once finished
+    task: Option<JoinHandle<()>>, // while running
+}
+
+// jobs are only visible to the client which submitted them, for everyone else they do not exist
+pub struct JobManager {
+    config: JobsConfig,
+    jobs: Mutex<HashMap<String, Job>>, // by id
+    random: SystemRandom,
+}
+
+impl JobManager {
+    // XXX jobs which are still running when the process stops are lost, only finished ones are stored
+    pub fn new(config: JobsConfig) -> Result<Self> {
+        let mut jobs = HashMap::new();
+
+        if let Some(dir) = &config.store_dir {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("cannot create job store {}", dir.display()))?;
+
+            for (id, job) in load_jobs(dir)? {
+                jobs.insert(id, job);
+            }
+        }
+
+        Ok(JobManager {
+            config,
+            jobs: Mutex::new(jobs),
+            random: SystemRandom::new(),
+        })
+    }
+
+    pub fn submit<C>(self: &Arc<Self>, multi: GenericMultiplexer<C>, identity: &ClientIdentity,
+                     batch: OctoplexRequest, limits: BatchLimits) -> Result<JobResponse>
+        where C: HttpClient + Clone + Send + Sync + 'static
+    {
+        let id = self.new_id()?;
+        let status = JobResponse {
+            id: id.clone(),
+            state: JobState::Running,
+            completed: 0,
+            total: batch.request_count(),
+            responses: vec![None; batch.request_count()],
+            error: None,
+            aggregate: None,
+            cookies: None,
+        };
+
+        // the job is registered before its task can report anything
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+        self.purge_expired(&mut jobs);
+
+        let manager = self.clone();
+        let task_id = id.clone();
+        let task = tokio::spawn(async move {
+            let callback = batch.callback.clone();
+            let result = multi.handle_with_progress(batch, &limits, |index, outcome| {
+                manager.record_progress(&task_id, index, outcome)
+            }).await;
+
+            if let (Some(callback), Ok(resp)) = (callback, &result) {
+                if let Err(e) = multi.spawn_callback(callback, resp) {
+                    warn!("cannot deliver callback of job {}: {:#}", task_id, e);
+                }
+            }
+            manager.finish(&task_id, result);
+        });
+
+        jobs.insert(id, Job {
+            client_id: identity.client_id.clone(),
+            status: status.clone(),
+            expires: None,
+            task: Some(task),
+        });
+
+        Ok(status)
+    }
+
+    pub fn get(&self, identity: &ClientIdentity, id: &str) -> Option<JobResponse> {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+        self.purge_expired(&mut jobs);
+
+        jobs.get(id)
+            .filter(|job| job.client_id == identity.client_id)
+            .map(|job| job.status.clone())
+    }
+
+    // a running job is stopped and keeps its partial results, a finished one is removed
+    pub fn cancel(&self, identity: &ClientIdentity, id: &str) -> Option<JobResponse> {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+        self.purge_expired(&mut jobs);
+
+        let job = jobs.get_mut(id).filter(|job| job.client_id == identity.client_id)?;
+
+        match job.task.take() {
+            Some(task) => {
+                task.abort();
+                job.status.state = JobState::Cancelled;
+                self.finalize(id, job);
+                Some(job.status.clone())
+            }
+            None => {
+                let job = jobs.remove(id)?;
+                self.delete_stored(id);
+                Some(job.status)
+            }
+        }
+    }
+
+    fn record_progress(&self, id: &str, index: usize, outcome: &SingleOutcome) {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+
+        if let Some(job) = jobs.get_mut(id).filter(|job| job.status.state == JobState::Running) {
+            if job.status.responses[index].is_none() {
+                job.status.completed += 1;
+            }
+            job.status.responses[index] = serde_json::to_value(outcome).ok();
+        }
+    }
+
+    fn finish(&self, id: &str, result: Result<OctoplexResponse>) {
+        let mut jobs = self.jobs.lock().expect("jobs poisoned");
+
+        // a cancelled job has been finalized already
+        let job = match jobs.get_mut(id).filter(|job| job.status.state == JobState::Running) {
+            Some(job) => job,
+            None => return,
+        };
+
+        match result {
+            Ok(resp) => {
+                job.status.state = JobState::Completed;
+                job.status.completed = resp.responses.len();
+                job.status.responses = resp.responses.iter()
+                    .map(|outcome| serde_json::to_value(outcome).ok())
+                    .collect();
+                job.status.aggregate = resp.aggregate;
+                job.status.cookies = resp.cookies;
+            }
+            Err(e) => {
+                job.status.state = JobState::Failed;
+                job.status.error = Some(e.to_string());
+            }
+        }
+
+        job.task = None;
+        self.finalize(id, job);
+    }
+
+    // starts the TTL and stores the job, if there is a store
+    fn finalize(&self, id: &str, job: &mut Job) {
+        let expires = SystemTime::now() + Duration::from_secs(self.config.ttl_sec);
+        job.expires = Some(expires);
+
+        let dir = match &self.config.store_dir {
+            Some(dir) => dir,
+            None => return,
+        };
+        let stored = StoredJob {
+            client_id: job.client_id.clone(),
+            expires_unix_sec: expires.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            job: job.status.clone(),
+        };
+
+        // XXX blocking I/O while holding the lock, fine for the size of a batch response
+        let written = serde_json::to_vec(&stored)
+            .context("cannot serialize job")
+            .and_then(|json| fs::write(job_path(dir, id), json).context("cannot write job"));
+        if let Err(e) = written {
+            warn!("cannot store job {}: {:#}", id, e);
+        }
+    }
+
+    fn purge_expired(&self, jobs: &mut HashMap<String, Job>) {
+        let now = SystemTime::now();
+        let expired = jobs.iter()
+            .filter(|(_, job)| matches!(job.expires, Some(expires) if expires <= now))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            jobs.remove(&id);
+            self.delete_stored(&id);
+        }
+    }
+
+    fn delete_stored(&self, id: &str) {
+        if let Some(dir) = &self.config.store_dir {
+            let _ = fs::remove_file(job_path(dir, id));
+        }
+    }
+
+    fn new_id(&self) -> Result<String> {
+        let mut bytes = [0u8; JOB_ID_BYTES];
+        self.random.fill(&mut bytes).map_err(|_| anyhow!("cannot generate job id"))?;
+
+        Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+fn job_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.json", id))
+}
+
+// unreadable files are skipped, expired ones are deleted
+fn load_jobs(dir: &Path) -> Result<Vec<(String, Job)>> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("cannot read job store {}", dir.display()))?;
+    let now = SystemTime::now();
+    let mut jobs = vec![];
+
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        let stored: StoredJob = match fs::read(&path).ok().and_then(|json| serde_json::from_slice(&json).ok()) {
+            Some(stored) => stored,
+            None => {
+                warn!("skipping unreadable job file {}", path.display());
+                continue;
+            }
+        };
+
+        let expires = UNIX_EPOCH + Duration::from_secs(stored.expires_unix_sec);
+        if expires <= now {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+
+        jobs.push((stored.job.id.clone(), Job {
+            client_id: stored.client_id,
+            status: stored.job,
+            expires: Some(expires),
+            task: None,
+        }));
+    }
+
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use hyper::{Body, Response};
+    use tokio::time::{advance, pause, Duration};
+
+    use crate::api::{ExecutionMode, JobState, OctoplexRequest, SingleHttpRequest};
+    use crate::auth::{AuthMethod, ClientIdentity};
+    use crate::http_client::tests::{MOCK_REQUEST_DURATION, MockHttpClient};
+    use crate::jobs::{JobManager, JobsConfig};
+    use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+    use crate::multiplexer::tests::batch_of;
+
+    fn ok_response() -> Result<Response<Body>> {
+        Ok(Response::builder().status(200).body(Body::from("{}"))?)
+    }
+
+    fn client(client_id: &str) -> ClientIdentity {
+        ClientIdentity {
+            client_id: client_id.to_string(),
+            method: AuthMethod::ApiKey,
+        }
+    }
+
+    fn sequential_batch(count: usize) -> OctoplexRequest {
+        OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 10,
+            execution: ExecutionMode::Sequential,
+            ..batch_of((0..count)
+                .map(|n| SingleHttpRequest {
+                    uri: format!("https://www.google.com/{}", n),
+                    ..Default::default()
+                })
+                .collect())
+        }
+    }
+
+    // in steps, so that the job tasks see every timer on the way
+    async fn advance_by(duration: Duration) {
+        for _ in 0..duration.as_millis() {
+            advance(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn multiplexer(count: usize) -> GenericMultiplexer<MockHttpClient> {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(count).returning(|_req| ok_response());
+
+        GenericMultiplexer::new(client)
+    }
+
+    #[tokio::test]
+    async fn reports_progress_and_results() {
+        let jobs = Arc::new(JobManager::new(JobsConfig::default()).unwrap());
+        let alice = client("alice");
+
+        pause();
+        let job = jobs.submit(multiplexer(2), &alice, sequential_batch(2), BatchLimits::default())
+            .expect("job not submitted");
+        assert_eq!((job.state, job.completed, job.total), (JobState::Running, 0, 2));
+
+        advance_by(MOCK_REQUEST_DURATION * 3 / 2).await;
+        let running = jobs.get(&alice, &job.id).expect("job not found");
+        assert_eq!((running.state, running.completed), (JobState::Running, 1));
+        assert!(running.responses[0].is_some() && running.responses[1].is_none());
+
+        advance_by(MOCK_REQUEST_DURATION).await;
+        let finished = jobs.get(&alice, &job.id).expect("job not found");
+        assert_eq!((finished.state, finished.completed), (JobState::Completed, 2));
+        assert!(jobs.get(&client("bob"), &job.id).is_none(), "job of another client");
+
+        assert!(jobs.cancel(&alice, &job.id).is_some());
+        assert!(jobs.get(&alice, &job.id).is_none(), "removed job");
+    }
+
+    #[tokio::test]
+    async fn cancels_running_jobs() {
+        let jobs = Arc::new(JobManager::new(JobsConfig::default()).unwrap());
+        let alice = client("alice");
+
+        pause();
+        let job = jobs.submit(multiplexer(2), &alice, sequential_batch(4), BatchLimits::default())
+            .expect("job not submitted");
+
+        advance_by(MOCK_REQUEST_DURATION * 3 / 2).await;
+        assert!(jobs.cancel(&client("bob"), &job.id).is_none(), "job of another client");
+        let cancelled = jobs.cancel(&alice, &job.id).expect("job not found");
+        assert_eq!((cancelled.state, cancelled.completed), (JobState::Cancelled, 1));
+
+        advance_by(MOCK_REQUEST_DURATION * 3).await;
+        let cancelled = jobs.get(&alice, &job.id).expect("job not found");
+        assert_eq!((cancelled.state, cancelled.completed), (JobState::Cancelled, 1));
+    }
+
+    #[tokio::test]
+    async fn restores_stored_jobs() {
+        let store_dir = env::temp_dir().join(format!("octoplex-jobs-{}", std::process::id()));
+        let config = JobsConfig { ttl_sec: 60, store_dir: Some(store_dir.clone()) };
+        let jobs = Arc::new(JobManager::new(config.clone()).unwrap());
+        let alice = client("alice");
+
+        pause();
+        let job = jobs.submit(multiplexer(1), &alice, sequential_batch(1), BatchLimits::default())
+            .expect("job not submitted");
+        advance_by(MOCK_REQUEST_DURATION * 2).await;
+
+        let restored = JobManager::new(config.clone()).unwrap().get(&alice, &job.id).expect("job not restored");
+        assert_eq!((restored.state, restored.completed), (JobState::Completed, 1));
+
+        assert!(jobs.cancel(&alice, &job.id).is_some());
+        assert!(JobManager::new(config).unwrap().get(&alice, &job.id).is_none(), "removed job");
+        let _ = fs::remove_dir_all(&store_dir);
+    }
+
+    #[tokio::test]
+    async fn purges_expired_jobs_on_submit() {
+        let jobs = Arc::new(JobManager::new(JobsConfig { ttl_sec: 0, store_dir: None }).unwrap());
+        let alice = client("alice");
+
+        pause();
+        jobs.submit(multiplexer(1), &alice, sequential_batch(1), BatchLimits::default()).expect("job not submitted");
+        advance_by(MOCK_REQUEST_DURATION * 2).await;
+        assert_eq!(jobs.jobs.lock().unwrap().len(), 1);
+
+        jobs.submit(multiplexer(0), &alice, sequential_batch(0), BatchLimits::default()).expect("job not submitted");
+        assert_eq!(jobs.jobs.lock().unwrap().len(), 1, "expired job kept");
+    }
+}
@@ -1,28 +1,83 @@
+mod aggregate;
 mod api;
+mod auction;
+mod auth;
+mod callback;
+mod checks;
+mod circuit_breaker;
+mod config;
+mod cookies;
+mod cron;
+mod dag;
+mod encoding;
+mod expect;
+mod extract;
 mod http_client;
 mod http_server;
+mod jobs;
 mod multiplexer;
+mod paginate;
+mod placeholder;
+mod quota;
+mod rate_limit;
+mod recipes;
+mod redirect;
+mod schema;
+mod shaping;
+mod target_policy;
+mod template;
+mod upstream;
 
 extern crate strum;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate log;
 
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::Result;
 
+use crate::auth::Authenticator;
+use crate::checks::CheckScheduler;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::Config;
 use crate::multiplexer::Multiplexer;
-use crate::http_server::launch_http_server;
+use crate::http_server::{launch_http_server, ServerState};
 use crate::http_client::make_hyper_client;
+use crate::jobs::JobManager;
+use crate::quota::QuotaManager;
+use crate::recipes::RecipeBook;
+use crate::shaping::UpstreamShaper;
+use crate::target_policy::TargetPolicy;
+use crate::upstream::{member_uris, UpstreamPools};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     pretty_env_logger::init();
 
+    let config = Config::load()?;
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    let http_client = make_hyper_client()?;
-    let multiplexer = Multiplexer::new(http_client);
-    let http_server = launch_http_server(&addr, &multiplexer);
+    let target_policy = TargetPolicy::new(config.target_policy).trusting(&member_uris(&config.upstreams));
+    let http_client = make_hyper_client(target_policy)?;
+    let multi = Multiplexer::new(http_client)
+        .with_shaper(UpstreamShaper::new(config.shaping))
+        .with_circuit_breaker(CircuitBreaker::new(config.circuit_breaker))
+        .with_upstreams(UpstreamPools::new(config.upstreams)?)
+        .with_callbacks(config.callbacks);
+    multi.spawn_health_checks();
+    let checks = CheckScheduler::new(multi.clone(), config.checks)?;
+
+    let state = ServerState {
+        multi,
+        auth: Arc::new(Authenticator::from_config(&config.auth)?),
+        quotas: Arc::new(QuotaManager::new(config.quotas)),
+        jobs: Arc::new(JobManager::new(config.jobs)?),
+        checks: Arc::new(checks),
+        recipes: Arc::new(RecipeBook::new(config.recipes)?),
+    };
+    let http_server = launch_http_server(&addr, &state);
 
     println!("Listening on http://{}", addr);
 
@@ -1,22 +1,56 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
 use anyhow::Error as AnyError;
 use tokio::time::{timeout_at, Duration, Instant};
 use tokio::time::error::Elapsed;
-use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
 use humantime::format_duration;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use thiserror::Error;
 
 // XXX these dependencies have to be removed, we should only depend on http_client
-use hyper::{Request, Body};
+use hyper::{Request, Body, Uri};
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use serde_json::Value;
+use serde_json::value::{to_raw_value, RawValue};
 use hyper::body::to_bytes;
 use http::response::Parts;
 
-use crate::api::{OctoplexRequest, OctoplexResponse, SingleHttpResponse, SingleOutcome, SingleHttpFailure};
-use crate::http_client::{HttpClient, OctoplexHttpClient};
+use crate::api::{Callback, CircuitStats, ExecutionMode, ExtractSpec, FailureKind, MultipartPart,
+                 HttpMethod, PageFollowing, PaginateSpec, Pagination, RedirectHop, RedirectPolicy, Redirects, UpstreamHealth,
+                 OctoplexRequest, OctoplexResponse, SingleHttpRequest, SingleHttpResponse, SingleOutcome,
+                 SingleHttpFailure};
+use crate::callback::{deliver, CallbackConfig};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::cookies::{CookieJar, CookieScope};
+use crate::dag::{Dag, DagError};
+use crate::http_client::{is_connect_error, HttpClient, OctoplexHttpClient};
+use crate::aggregate::aggregate;
+use crate::encoding::{encode_multipart, encode_request};
+use crate::expect::{self, Expectation};
+use crate::extract;
+use crate::paginate;
+use crate::redirect;
+use crate::placeholder::{body_escape, json_references, parameters, references, substitute, substitute_json,
+                         substitute_parameters, visit_strings, Escape, StepResponse};
+use crate::shaping::UpstreamShaper;
+use crate::upstream::{UpstreamPools, UpstreamTarget};
+use crate::target_policy::{host_matches, TargetBlocked};
+use crate::template::expand_request;
 
 pub type Multiplexer = GenericMultiplexer<OctoplexHttpClient>;
 type ValidationOutcome = Result<OctoplexRequest, ValidationError>;
-type RequestOutcome = Result<(Duration, Parts, String), RequestError>;
+type RequestOutcome = Result<Fetched, RequestError>;
+
+// a response as it was read, before it is checked and turned into a SingleHttpResponse
+struct Fetched {
+    duration: Duration,
+    head: Parts,
+    body: String,
+    pagination: Option<Pagination>,
+    redirects: Option<Redirects>,
+}
 
 #[derive(Error, Debug)]
 enum ValidationError {
@@ -26,6 +60,16 @@ enum ValidationError {
     EmptyBatchRequested,
     #[error("there may not be more than {0} requests in the batch")]
     MaximumBatchSizeExceeded(usize),
+    #[error("max_concurrency has to be at least 1")]
+    ZeroConcurrency,
+    #[error(transparent)]
+    InvalidDependencies(#[from] DagError),
+    #[error("callback {0:?} is not a valid http(s) URI or not allowed")]
+    InvalidCallback(String),
+    #[error("aggregate pointer {0:?} is not a JSON Pointer")]
+    InvalidAggregatePointer(String),
+    #[error("invalid for_each: {0}")]
+    InvalidForEach(String),
 }
 
 #[derive(Error, Debug)]
@@ -34,6 +78,12 @@ enum RequestError {
     RequestInvalid { error: AnyError },
     #[error("the request failed: {error}")]
     RequestFailure { error: AnyError, duration: Duration },
+    #[error("the request was blocked: {error}")]
+    RequestBlocked { error: AnyError, duration: Duration },
+    #[error("the request was rate limited: {error}")]
+    RequestRateLimited { error: AnyError, duration: Duration },
+    #[error("the request was not sent: {error}")]
+    CircuitOpen { error: AnyError },
     #[error("failure during response: {error}")]
     ResponseFailure { error: AnyError, duration: Duration },
     #[error("timeout elapsed")]
@@ -41,18 +91,108 @@ enum RequestError {
 }
 
 enum ValidatedRequest {
-    ValidRequest(Request<Body>),
-    InvalidRequest(AnyError),
+    Valid(Request<Body>),
+    Upstream(UpstreamTarget, SingleHttpRequest), // built once a member has been picked
+    Deferred(SingleHttpRequest), // built once its dependencies have responded
+    Paginated(SingleHttpRequest, PaginateSpec), // every page is validated and built before it is sent
+    Redirecting(SingleHttpRequest, RedirectPolicy), // so is every hop
+    Invalid(AnyError),
+    Blocked(AnyError),
 }
 
 const MAX_REQUEST_DURATION: Duration = Duration::from_millis(60 * 60 * 1_000); // XXX config
 const MAX_BATCH_SIZE: usize = 50; // XXX config
 
+// what a batch is validated against, these may differ per client
+#[derive(Debug, Clone)]
+pub struct BatchLimits {
+    pub max_request_duration: Duration,
+    pub max_batch_size: usize,
+    pub allowed_hosts: Vec<String>, // globs, empty allows any host
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        BatchLimits {
+            max_request_duration: MAX_REQUEST_DURATION,
+            max_batch_size: MAX_BATCH_SIZE,
+            allowed_hosts: vec![],
+        }
+    }
+}
+
+impl BatchLimits {
+    fn allows_host(&self, host: Option<&str>) -> bool {
+        match host {
+            _ if self.allowed_hosts.is_empty() => true,
+            Some(host) => self.allowed_hosts.iter().any(|pattern| host_matches(pattern, host)),
+            None => false,
+        }
+    }
+}
+
+// what is done with the response of a request, by position in the batch
+struct ResponseHandling {
+    parse_json: bool,
+    extract: Option<ExtractSpec>,
+    expect: Option<Expectation>, // compiled along with validating the request
+}
+
+// what the requests of a batch share while they are executed
+struct BatchRun<'a> {
+    limits: &'a BatchLimits,
+    deadline: Instant,
+    jar: Option<&'a CookieJar>, // when the batch asks for one
+    cookie_scope: CookieScope, // of the request being executed
+}
+
+// the order and parallelism requests of a batch are executed with
+struct Schedule {
+    dag: Dag,
+    max_concurrency: usize,
+    stop_on_failure: bool,
+}
+
+impl Schedule {
+    fn new(batch: &OctoplexRequest) -> Result<Self, ValidationError> {
+        let (max_concurrency, stop_on_failure) = match batch.execution {
+            ExecutionMode::Parallel => (batch.max_concurrency.unwrap_or(usize::MAX), false),
+            ExecutionMode::Sequential => (1, false),
+            ExecutionMode::SequentialStopOnFailure => (1, true),
+        };
+
+        if max_concurrency == 0 {
+            return Err(ValidationError::ZeroConcurrency);
+        }
+
+        Ok(Schedule {
+            dag: Dag::new(&batch.requests)?,
+            max_concurrency,
+            stop_on_failure,
+        })
+    }
+
+    // one request at a time sees the cookies of all before it, otherwise only those of its dependencies
+    fn cookie_scope(&self, index: usize) -> CookieScope {
+        CookieScope {
+            request: index,
+            sees: match self.max_concurrency {
+                1 => None,
+                _ => Some(self.dag.ancestors(index)),
+            },
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct GenericMultiplexer<C>
     where C: HttpClient + Clone + Send + Sync
 {
-    http_client: C // hyper::Client is cheap to clone and retains shared state (pool, connector)
+    http_client: C, // hyper::Client is cheap to clone and retains shared state (pool, connector)
+    shaper: Arc<UpstreamShaper>,
+    breaker: Arc<CircuitBreaker>,
+    upstreams: Arc<UpstreamPools>,
+    callbacks: Arc<CallbackConfig>,
     // XXX dns cache, metrics, etc
 }
 
@@ -61,133 +201,615 @@ impl<C> GenericMultiplexer<C>
 {
     pub fn new(http_client: C) -> Self {
         GenericMultiplexer {
-            http_client
+            http_client,
+            shaper: Arc::new(UpstreamShaper::default()),
+            breaker: Arc::new(CircuitBreaker::default()),
+            upstreams: Arc::new(UpstreamPools::default()),
+            callbacks: Arc::new(CallbackConfig::default()),
         }
     }
 
-    pub async fn handle(&self, batch: OctoplexRequest) -> Result<OctoplexResponse> {
-        let batch = Self::validate_request(batch)?;
+    pub fn with_shaper(mut self, shaper: UpstreamShaper) -> Self {
+        self.shaper = Arc::new(shaper);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Arc::new(breaker);
+        self
+    }
+
+    pub fn with_upstreams(mut self, upstreams: UpstreamPools) -> Self {
+        self.upstreams = Arc::new(upstreams);
+        self
+    }
 
-        let deadline = Instant::now() + batch.timeout_msec;
-        let out_requests = Self::build_out_requests(batch);
+    pub fn with_callbacks(mut self, callbacks: CallbackConfig) -> Self {
+        self.callbacks = Arc::new(callbacks);
+        self
+    }
 
-        let mut outcomes = self.execute_requests(out_requests, deadline).await;
+    pub fn circuit_stats(&self) -> Vec<CircuitStats> {
+        self.breaker.stats()
+    }
 
-        let mut responses = vec![];
-        for outcome in outcomes.drain(..) {
-            let response = match outcome {
-                Err(RequestError::RequestInvalid { error }) =>
-                    SingleOutcome::Failure(SingleHttpFailure {
-                        error: error.to_string(),
-                        duration_msec: Duration::from_millis(0),
-                    }),
-                Err(RequestError::RequestFailure { error, duration }) =>
-                    SingleOutcome::Failure(SingleHttpFailure {
-                        error: error.to_string(),
-                        duration_msec: duration,
-                    }),
-                Err(RequestError::ResponseFailure { error, duration }) =>
-                    SingleOutcome::Failure(SingleHttpFailure {
-                        error: error.to_string(),
-                        duration_msec: duration,
-                    }),
-                Err(RequestError::ResponseTimeout { error, duration }) =>
-                    SingleOutcome::Failure(SingleHttpFailure {
-                        error: error.to_string(),
-                        duration_msec: duration,
-                    }),
-                Ok((req_duration, head, body_bytes)) =>
-                    SingleOutcome::Success(SingleHttpResponse {
-                        headers: head.headers.into(),
-                        status: head.status.as_u16(),
-                        content: Some(body_bytes),
-                        duration_msec: req_duration,
-                    }),
-            };
-            responses.push(response);
-        }
+    pub fn upstream_health(&self) -> Vec<UpstreamHealth> {
+        self.upstreams.health()
+    }
+
+    // the probes go through the same client as the requests
+    pub fn spawn_health_checks(&self)
+        where C: 'static
+    {
+        self.upstreams.spawn_health_checks(self.http_client.clone());
+    }
+
+    pub async fn handle(&self, batch: OctoplexRequest, limits: &BatchLimits) -> Result<OctoplexResponse> {
+        self.handle_with_progress(batch, limits, |_, _| ()).await
+    }
+
+    // with a callback the batch runs in a task of its own, which is not cancelled when the client
+    // disconnects, so that the response is delivered anyway
+    pub async fn handle_detached(&self, batch: OctoplexRequest, limits: &BatchLimits) -> Result<OctoplexResponse>
+        where C: 'static
+    {
+        let callback = match batch.callback.clone() {
+            Some(callback) => callback,
+            None => return self.handle(batch, limits).await,
+        };
+        let multi = self.clone();
+        let limits = limits.clone();
+
+        tokio::spawn(async move {
+            let resp = multi.handle(batch, &limits).await?;
+            multi.spawn_callback(callback, &resp)?;
+            Ok(resp)
+        }).await.context("batch task failed")?
+    }
+
+    // retries happen in the background, a callback which cannot be delivered is only logged
+    pub fn spawn_callback(&self, callback: Callback, resp: &OctoplexResponse) -> Result<()>
+        where C: 'static
+    {
+        let payload = serde_json::to_vec(resp).context("cannot serialize")?;
+        let http_client = self.http_client.clone();
+        let config = self.callbacks.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = deliver(&http_client, &config, &callback, payload.into()).await {
+                warn!("cannot deliver callback to {}: {:#}", callback.uri, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    // `progress` learns about every outcome as soon as it is there, by position in the batch
+    pub async fn handle_with_progress(&self, batch: OctoplexRequest, limits: &BatchLimits,
+                                      progress: impl Fn(usize, &SingleOutcome) + Send + Sync)
+                                      -> Result<OctoplexResponse>
+    {
+        let batch = Self::validate_request(batch, limits)?;
+        let schedule = Schedule::new(&batch)?;
+        let aggregate_spec = batch.aggregate.clone();
+        let return_jar = batch.cookie_jar.as_ref().is_some_and(|spec| spec.return_jar);
+        let jar = batch.cookie_jar.as_ref().map(|_| CookieJar::default());
+        let run = BatchRun {
+            limits,
+            deadline: Instant::now() + batch.timeout_msec,
+            jar: jar.as_ref(),
+            cookie_scope: CookieScope::default(),
+        };
+        let (out_requests, handling) = self.build_out_requests(batch, limits);
+
+        let responses = self.execute_requests(out_requests, &schedule, &handling, &run, &progress).await;
+
+        let aggregate = aggregate_spec.map(|spec| {
+            let parts = responses.iter().zip(&handling).enumerate()
+                .map(|(index, (outcome, handling))| {
+                    let key = schedule.dag.id(index).map(str::to_string).unwrap_or_else(|| index.to_string());
+                    let body = match outcome {
+                        SingleOutcome::Success(resp) if succeeded(outcome, handling) => Some(resp.body()),
+                        _ => None,
+                    };
+                    (key, body)
+                });
+
+            aggregate(&spec, parts)
+        });
 
         Ok(OctoplexResponse {
-            responses
+            responses,
+            aggregate,
+            cookies: jar.filter(|_| return_jar).map(|jar| jar.cookies()),
         })
     }
 
-    fn validate_request(batch: OctoplexRequest) -> ValidationOutcome {
-        if batch.timeout_msec > MAX_REQUEST_DURATION {
-            return Err(ValidationError::MaximumTimeoutExceeded(MAX_REQUEST_DURATION));
+    // for batches which run later, so that they can be rejected right away; running validates them again
+    pub fn validate(&self, batch: OctoplexRequest, limits: &BatchLimits) -> Result<OctoplexRequest> {
+        let batch = Self::validate_request(batch, limits)?;
+        Schedule::new(&batch)?;
+
+        Ok(batch)
+    }
+
+    fn validate_request(batch: OctoplexRequest, limits: &BatchLimits) -> ValidationOutcome {
+        if batch.timeout_msec > limits.max_request_duration {
+            return Err(ValidationError::MaximumTimeoutExceeded(limits.max_request_duration));
         }
 
-        if batch.requests.len() == 0 {
+        if batch.request_count() == 0 {
             return Err(ValidationError::EmptyBatchRequested);
         }
 
-        if batch.requests.len() > MAX_BATCH_SIZE {
-            return Err(ValidationError::MaximumBatchSizeExceeded(MAX_BATCH_SIZE));
+        if batch.request_count() > limits.max_batch_size {
+            return Err(ValidationError::MaximumBatchSizeExceeded(limits.max_batch_size));
+        }
+
+        if let Some(spec) = batch.aggregate.as_ref().filter(|spec| !spec.pointer.is_empty()) {
+            if !spec.pointer.starts_with('/') {
+                return Err(ValidationError::InvalidAggregatePointer(spec.pointer.clone()));
+            }
+        }
+
+        if let Some(callback) = &batch.callback {
+            let valid = match callback.uri.parse::<Uri>() {
+                Ok(uri) => matches!(uri.scheme_str(), Some("http") | Some("https"))
+                    && limits.allows_host(uri.host()),
+                Err(_) => false,
+            };
+            if !valid {
+                return Err(ValidationError::InvalidCallback(callback.uri.clone()));
+            }
+        }
+
+        Self::expand_for_each(batch)
+    }
+
+    // every parameter the request refers to has to be given, the batch is rejected otherwise
+    fn expand_for_each(mut batch: OctoplexRequest) -> ValidationOutcome {
+        let for_each = match batch.for_each.take() {
+            Some(for_each) => for_each,
+            None => return Ok(batch),
+        };
+
+        let mut names = BTreeSet::new();
+        visit_strings(&for_each.request, &mut |text| names.extend(parameters(text).map(str::to_string)));
+        let template: SingleHttpRequest = serde_json::from_value(for_each.request.clone())
+            .map_err(|e| ValidationError::InvalidForEach(format!("request: {}", e)))?;
+        let escaped = [
+            ("/uri".to_string(), Escape::Uri),
+            ("/body".to_string(), body_escape(&template.headers, template.body.as_deref())),
+        ];
+
+        for (index, params) in for_each.params.iter().enumerate() {
+            if let Some(missing) = names.iter().find(|name| !params.contains_key(*name)) {
+                return Err(ValidationError::InvalidForEach(format!("params #{} lack {}", index, missing)));
+            }
+
+            let mut request = for_each.request.clone();
+            substitute_parameters(&mut request, params, &escaped)
+                .map_err(|e| ValidationError::InvalidForEach(format!("params #{}: {}", index, e)))?;
+            let request = serde_json::from_value(request)
+                .map_err(|e| ValidationError::InvalidForEach(format!("request for params #{}: {}", index, e)))?;
+            batch.requests.push(request);
         }
 
         Ok(batch)
     }
 
-    fn build_out_requests(batch: OctoplexRequest) -> Vec<ValidatedRequest> {
+    fn build_out_requests(&self, batch: OctoplexRequest, limits: &BatchLimits)
+                          -> (Vec<ValidatedRequest>, Vec<ResponseHandling>)
+    {
+        let template = batch.template.unwrap_or_default();
         let mut out_reqs = Vec::new();
+        let mut handling = Vec::new();
 
         for http_req in batch.requests {
-            let mut out_req_builder = Request::builder()
-                .method(http_req.method.as_ref())
-                .uri(http_req.uri);
+            let mut request_handling = ResponseHandling {
+                parse_json: http_req.parse_json.unwrap_or(batch.parse_json),
+                extract: http_req.extract.clone(),
+                expect: None,
+            };
+            let out_req = expand_request(&template, http_req)
+                .and_then(|http_req| {
+                    http_req.extract.iter().try_for_each(extract::validate)?;
+                    request_handling.expect = http_req.expect.as_ref().map(expect::compile).transpose()?;
+                    http_req.paginate.iter().try_for_each(paginate::validate)?;
+                    http_req.follow_redirects.iter().try_for_each(redirect::validate)?;
+                    Ok(http_req)
+                })
+                .and_then(|http_req| match http_req.depends_on.is_empty() {
+                    true => self.validate_target(http_req, limits),
+                    false => Self::defer_request(http_req),
+                })
+                .unwrap_or_else(ValidatedRequest::Invalid);
 
-            for (name, value) in &http_req.headers {
-                out_req_builder = out_req_builder.header(name, value);
+            out_reqs.push(out_req);
+            handling.push(request_handling);
+        }
+
+        (out_reqs, handling)
+    }
+
+    // for upstream:// requests the allowed hosts of the client apply to the upstream name
+    fn validate_target(&self, http_req: SingleHttpRequest, limits: &BatchLimits) -> Result<ValidatedRequest> {
+        let mut http_req = encode_request(http_req)?;
+        if let Some(spec) = http_req.paginate.take() {
+            // the first page is checked right away
+            let first_page = SingleHttpRequest { uri: paginate::first_page(&spec, &http_req.uri)?, ..http_req.clone() };
+            return Ok(match self.validate_target(first_page, limits)? {
+                ValidatedRequest::Blocked(error) => ValidatedRequest::Blocked(error),
+                _ => ValidatedRequest::Paginated(http_req, spec),
+            });
+        }
+        if let Some(policy) = http_req.follow_redirects.take() {
+            return Ok(match self.validate_target(http_req.clone(), limits)? {
+                ValidatedRequest::Blocked(error) => ValidatedRequest::Blocked(error),
+                _ => ValidatedRequest::Redirecting(http_req, policy),
+            });
+        }
+        let (host, out_req) = match self.upstreams.target_of(&http_req)? {
+            Some(target) => (target.pool.clone(), ValidatedRequest::Upstream(target, http_req)),
+            None => {
+                let req = Self::build_out_request(http_req)?;
+                (req.uri().host().unwrap_or_default().to_string(), ValidatedRequest::Valid(req))
             }
+        };
 
-            let req_body = match http_req.body {
-                Some(body) => Body::from(body),
-                None => Body::empty(),
-            };
+        Ok(match limits.allows_host(Some(&host)) {
+            true => out_req,
+            false => ValidatedRequest::Blocked(anyhow!("host {} is not allowed for this client", host)),
+        })
+    }
 
-            let out_req = out_req_builder.body(req_body)
-                .map(|req| ValidatedRequest::ValidRequest(req))
-                .unwrap_or_else(|err| ValidatedRequest::InvalidRequest(err.into()));
+    // placeholders are only substituted in requests with dependencies, and only refer to those
+    fn defer_request(http_req: SingleHttpRequest) -> Result<ValidatedRequest> {
+        let texts = http_req.headers.values()
+            .chain(std::iter::once(&http_req.uri))
+            .chain(http_req.body.as_ref())
+            .chain(http_req.form.iter().flat_map(|form| form.values()))
+            .chain(http_req.multipart.iter().flatten().filter_map(|part| part.text.as_ref()))
+            .chain(http_req.query.values());
+        let mut ids = texts.flat_map(|text| references(text)).collect::<Vec<_>>();
+        ids.extend(http_req.json.iter().flat_map(json_references));
+
+        if let Some(id) = ids.into_iter().find(|id| !http_req.depends_on.iter().any(|d| d == id)) {
+            return Err(anyhow!("placeholder refers to {}, which is not in depends_on", id));
+        }
 
-            out_reqs.push(out_req);
+        Ok(ValidatedRequest::Deferred(http_req))
+    }
+
+    fn resolve_deferred(&self, http_req: SingleHttpRequest, index: usize, dag: &Dag,
+                        outcomes: &[Option<SingleOutcome>], limits: &BatchLimits) -> ValidatedRequest
+    {
+        let responses = dag.dependencies[index].iter()
+            .filter_map(|dependency| match (dag.id(*dependency), &outcomes[*dependency]) {
+                (Some(id), Some(SingleOutcome::Success(resp))) => Some((id, StepResponse {
+                    status: resp.status,
+                    headers: resp.headers.as_map(),
+                    body: resp.body(),
+                })),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        substitute_request(http_req, &responses)
+            .and_then(|http_req| self.validate_target(http_req, limits))
+            .unwrap_or_else(ValidatedRequest::Invalid)
+    }
+
+    fn build_out_request(http_req: SingleHttpRequest) -> Result<Request<Body>> {
+        let mut out_req_builder = Request::builder()
+            .method(http_req.method.unwrap_or_default().as_ref())
+            .uri(http_req.uri);
+
+        // the boundary of a multipart body replaces any Content-Type of the request
+        let multipart = http_req.multipart.as_deref().map(encode_multipart).transpose()?;
+        for (name, value) in &http_req.headers {
+            if multipart.is_none() || !name.eq_ignore_ascii_case(CONTENT_TYPE.as_str()) {
+                out_req_builder = out_req_builder.header(name, value);
+            }
         }
 
-        out_reqs
+        let req_body = match (http_req.body, multipart) {
+            (_, Some((content_type, body))) => {
+                out_req_builder = out_req_builder.header(CONTENT_TYPE, content_type);
+                Body::from(body)
+            }
+            (Some(body), None) => Body::from(body),
+            (None, None) => Body::empty(),
+        };
+
+        Ok(out_req_builder.body(req_body)?)
     }
 
-    async fn execute_requests(&self, mut requests: Vec<ValidatedRequest>,
-                              deadline: Instant) -> Vec<RequestOutcome>
+    // every request is started as soon as all of its dependencies have succeeded and the concurrency
+    // allows, in batch order
+    async fn execute_requests(&self, requests: Vec<ValidatedRequest>, schedule: &Schedule,
+                              handling: &[ResponseHandling], run: &BatchRun<'_>,
+                              progress: &(dyn Fn(usize, &SingleOutcome) + Send + Sync)) -> Vec<SingleOutcome>
     {
+        let dag = &schedule.dag;
+        let mut pending = requests.into_iter().map(Some).collect::<Vec<_>>();
+        let mut outcomes = pending.iter().map(|_| None).collect::<Vec<Option<SingleOutcome>>>();
+        let mut waiting_for = dag.dependencies.iter().map(Vec::len).collect::<Vec<_>>();
+        let mut ready = (0..pending.len()).filter(|index| waiting_for[*index] == 0).collect::<BTreeSet<_>>();
+
         // XXX a low timeout will interrupt establishing and keeping a keep-alive connection, which
         // would otherwise speed up subsequent requests
-        let responses_with_timeout = requests
-            .drain(..)
-            .map(|req| self.execute_request(req, deadline))
-            .collect::<Vec<_>>();
+        let mut running = FuturesUnordered::new();
+        loop {
+            while running.len() < schedule.max_concurrency {
+                let index = match ready.iter().next() {
+                    Some(index) => *index,
+                    None => break,
+                };
+                ready.remove(&index);
+
+                let request = match pending[index].take().expect("request started twice") {
+                    ValidatedRequest::Deferred(http_req) =>
+                        self.resolve_deferred(http_req, index, dag, &outcomes, run.limits),
+                    request => request,
+                };
+                running.push(self.execute_step(index, request, BatchRun {
+                    cookie_scope: schedule.cookie_scope(index),
+                    ..*run
+                }));
+            }
+
+            let (index, outcome) = match running.next().await {
+                Some(finished) => finished,
+                None => break,
+            };
+            let outcome = into_single_outcome(outcome, &handling[index]);
+            let succeeded = succeeded(&outcome, &handling[index]);
+            progress(index, &outcome);
+            outcomes[index] = Some(outcome);
+
+            if !succeeded {
+                let mut skipped = skip_dependents(index, dag, &mut outcomes);
+                if schedule.stop_on_failure {
+                    skipped.append(&mut skip_remaining(index, dag, &mut outcomes));
+                    ready.clear();
+                }
+
+                for index in skipped {
+                    progress(index, outcomes[index].as_ref().expect("skipped request without outcome"));
+                }
+                continue;
+            }
 
-        join_all(responses_with_timeout).await
+            for dependent in &dag.dependents[index] {
+                waiting_for[*dependent] -= 1;
+                if waiting_for[*dependent] == 0 && outcomes[*dependent].is_none() {
+                    ready.insert(*dependent);
+                }
+            }
+        }
+
+        outcomes.into_iter()
+            .map(|outcome| outcome.expect("request without outcome"))
+            .collect()
     }
 
-    async fn execute_request(&self, request: ValidatedRequest, deadline: Instant)
-                             -> RequestOutcome
+    async fn execute_step(&self, index: usize, request: ValidatedRequest, run: BatchRun<'_>)
+                          -> (usize, RequestOutcome)
     {
-        let request = match request {
-            ValidatedRequest::ValidRequest(req) => req,
-            ValidatedRequest::InvalidRequest(err) => return Err(RequestError::RequestInvalid { error: err }),
+        let outcome = match request {
+            ValidatedRequest::Paginated(http_req, spec) =>
+                self.execute_paginated_request(http_req, spec, &run).await,
+            request => self.execute_target(request, &run).await,
         };
 
+        (index, outcome)
+    }
+
+    async fn execute_target(&self, request: ValidatedRequest, run: &BatchRun<'_>) -> RequestOutcome
+    {
+        match request {
+            ValidatedRequest::Redirecting(http_req, policy) =>
+                self.execute_redirected_request(http_req, policy, run).await,
+            request => self.execute_request(request, run).await,
+        }
+    }
+
+    async fn execute_request(&self, request: ValidatedRequest, run: &BatchRun<'_>) -> RequestOutcome
+    {
+        match request {
+            ValidatedRequest::Valid(req) => self.send_request(req, run).await,
+            ValidatedRequest::Upstream(target, http_req) =>
+                self.execute_upstream_request(target, http_req, run).await,
+            ValidatedRequest::Deferred(_) => unreachable!("deferred requests are resolved before execution"),
+            ValidatedRequest::Paginated(..) => unreachable!("pages are executed one by one"),
+            ValidatedRequest::Redirecting(..) => unreachable!("hops are executed one by one"),
+            ValidatedRequest::Invalid(err) => Err(RequestError::RequestInvalid { error: err }),
+            ValidatedRequest::Blocked(err) =>
+                Err(RequestError::RequestBlocked { error: err, duration: Duration::from_millis(0) }),
+        }
+    }
+
+    // fails over to the next member as long as members are left and the deadline allows
+    // XXX the duration of failed attempts is not included in the outcome
+    async fn execute_upstream_request(&self, target: UpstreamTarget, http_req: SingleHttpRequest,
+                                      run: &BatchRun<'_>) -> RequestOutcome
+    {
+        let mut tried = vec![];
+        let mut last_failure = None;
+
+        loop {
+            let lease = match self.upstreams.select(&target, &tried) {
+                Some(lease) => lease,
+                None => return last_failure.unwrap_or_else(|| Err(RequestError::RequestFailure {
+                    error: anyhow!("upstream {} has no member to send to", target.pool),
+                    duration: Duration::from_millis(0),
+                })),
+            };
+            tried.push(lease.index);
+
+            let req = Self::build_out_request(SingleHttpRequest {
+                uri: lease.uri_for(&target),
+                ..http_req.clone()
+            }).map_err(|error| RequestError::RequestInvalid { error })?;
+
+            let outcome = self.send_request(req, run).await;
+            let can_fail_over = match &outcome {
+                Err(RequestError::CircuitOpen { .. }) => true,
+                // other failures may come after the member received the request, which only
+                // idempotent requests can take
+                Err(RequestError::RequestFailure { error, .. }) =>
+                    is_connect_error(error) || is_idempotent(http_req.method.unwrap_or_default()),
+                _ => false,
+            };
+            if !can_fail_over || Instant::now() >= run.deadline {
+                return outcome;
+            }
+
+            last_failure = Some(outcome);
+        }
+    }
+
+    // the pages, or their items, end up in one JSON array, a page with an error status is returned as it is
+    // the deadline stops following pages, unless not even the first page has been fetched
+    async fn execute_paginated_request(&self, http_req: SingleHttpRequest, spec: PaginateSpec,
+                                       run: &BatchRun<'_>) -> RequestOutcome
+    {
+        let start_time = Instant::now();
+        let invalid = |error| RequestError::RequestInvalid { error };
+
+        let mut collected = vec![];
+        let mut next_uri = Some(paginate::first_page(&spec, &http_req.uri).map_err(invalid)?);
+        let mut last_head = None;
+        let mut pages = 0;
+
+        while let Some(page_uri) = next_uri.take() {
+            if pages == spec.max_pages {
+                next_uri = Some(page_uri);
+                break;
+            }
+
+            let page_req = SingleHttpRequest { uri: page_uri.clone(), ..http_req.clone() };
+            let request = self.validate_target(page_req, run.limits).map_err(invalid)?;
+            let Fetched { head, body, .. } = match self.execute_target(request, run).await {
+                Ok(fetched) if fetched.head.status.as_u16() >= 400 && pages > 0 =>
+                    return Err(RequestError::ResponseFailure {
+                        error: anyhow!("page {} was answered with {}", page_uri, fetched.head.status),
+                        duration: Instant::now().saturating_duration_since(start_time),
+                    }),
+                Ok(fetched) if fetched.head.status.as_u16() >= 400 => return Ok(fetched),
+                Ok(fetched) => fetched,
+                Err(RequestError::ResponseTimeout { .. }) if pages > 0 => {
+                    next_uri = Some(page_uri);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let page = serde_json::from_str::<Value>(&body).unwrap_or(Value::String(body));
+            next_uri = paginate::next_page(&spec, &page_uri, pages + 1, &head.headers, &page).map_err(invalid)?;
+            last_head = Some(head);
+
+            match (&spec.items, &spec.follow) {
+                // the empty page at the end is not one
+                (_, PageFollowing::Param { .. }) if next_uri.is_none() => break,
+                (Some(pointer), _) => match paginate::items(&spec, &page) {
+                    Some(items) => collected.extend(items.iter().cloned()),
+                    None => return Err(RequestError::ResponseFailure {
+                        error: anyhow!("page {} has no items at {}", page_uri, pointer),
+                        duration: Instant::now().saturating_duration_since(start_time),
+                    }),
+                },
+                (None, _) => collected.push(page),
+            }
+            pages += 1;
+        }
+
+        let body = serde_json::to_string(&collected)
+            .map_err(|error| invalid(error.into()))?;
+        let head = last_head.expect("no page fetched");
+
+        Ok(Fetched {
+            duration: Instant::now().saturating_duration_since(start_time),
+            head,
+            body,
+            pagination: Some(Pagination { pages, next_uri }),
+            redirects: None,
+        })
+    }
+
+    // the response to the last hop is returned, with the chain of redirects that led there
+    async fn execute_redirected_request(&self, http_req: SingleHttpRequest, policy: RedirectPolicy,
+                                        run: &BatchRun<'_>) -> RequestOutcome
+    {
+        let start_time = Instant::now();
+        let mut hop_req = http_req;
+        let mut chain = vec![];
+
+        loop {
+            let request = self.validate_target(hop_req.clone(), run.limits)
+                .map_err(|error| RequestError::RequestInvalid { error })?;
+            let fetched = self.execute_request(request, run).await?;
+            let duration = Instant::now().saturating_duration_since(start_time);
+
+            let status = fetched.head.status.as_u16();
+            let next_req = match redirect::next_request(&policy, &hop_req, status, &fetched.head.headers) {
+                Ok(Some(next_req)) => next_req,
+                Ok(None) => return Ok(Fetched {
+                    duration,
+                    redirects: Some(Redirects { final_uri: hop_req.uri, chain }),
+                    ..fetched
+                }),
+                Err(error) => return Err(RequestError::ResponseFailure { error, duration }),
+            };
+            if chain.len() == policy.max_hops {
+                return Err(RequestError::ResponseFailure {
+                    error: anyhow!("more than {} redirects, the last one to {}", policy.max_hops, next_req.uri),
+                    duration,
+                });
+            }
+
+            chain.push(RedirectHop { uri: hop_req.uri, status });
+            hop_req = next_req;
+        }
+    }
+
+    // the cookies of the jar are added when the request is sent, so that it gets those set until then
+    async fn send_request(&self, mut request: Request<Body>, run: &BatchRun<'_>) -> RequestOutcome {
+        let (deadline, jar) = (run.deadline, run.jar);
+        if let Some(jar) = jar {
+            jar.add_to(&run.cookie_scope, &mut request);
+        }
+        let uri = request.uri().clone();
+        let ticket = self.breaker.admit(request.uri())
+            .map_err(|error| RequestError::CircuitOpen { error: error.into() })?;
+
         let timeout_start_time = Instant::now();
 
         let timeout_future = timeout_at(deadline, async {
+            // held until the response has been read
+            let _permit = self.shaper.admit(request.uri(), deadline).await
+                .map_err(|error| {
+                    let duration = Instant::now().saturating_duration_since(timeout_start_time);
+
+                    RequestError::RequestRateLimited { error: error.into(), duration }
+                })?;
+
             let start_time = Instant::now();
             let resp = self.http_client.request(request).await
                 .map_err(|error| {
                     let duration = Instant::now().saturating_duration_since(timeout_start_time);
 
-                    RequestError::RequestFailure { error, duration }
+                    match TargetBlocked::is_cause_of(&error) {
+                        true => RequestError::RequestBlocked { error, duration },
+                        false => RequestError::RequestFailure { error, duration },
+                    }
                 })?;
 
             let (parts, body_stream) = resp.into_parts();
+            if let Some(jar) = jar {
+                jar.store(&run.cookie_scope, &uri, &parts.headers);
+            }
 
             // XXX impl Buf is not Send (disabled code), we have to clone/own the data :(
             //use hyper::body::aggregate;
@@ -210,29 +832,253 @@ impl<C> GenericMultiplexer<C>
 
             let duration = Instant::now().saturating_duration_since(start_time);
 
-            Ok((duration, parts, body_bytes))
+            Ok(Fetched { duration, head: parts, body: body_bytes, pagination: None, redirects: None })
         });
 
-        timeout_future.await
-            .map_err(|error| {
+        let outcome = match timeout_future.await {
+            Ok(outcome) => outcome,
+            Err(error) => {
                 let duration = Instant::now().saturating_duration_since(timeout_start_time);
 
-                RequestError::ResponseTimeout { error, duration }
-            })?
+                Err(RequestError::ResponseTimeout { error, duration })
+            }
+        };
+
+        if let Some(ticket) = ticket {
+            self.breaker.record(ticket, upstream_health(&outcome));
+        }
+
+        outcome
+    }
+}
+
+fn substitute_request(http_req: SingleHttpRequest, responses: &HashMap<&str, StepResponse>)
+                      -> Result<SingleHttpRequest>
+{
+    let uri = substitute(&http_req.uri, responses, Escape::Uri)?;
+    let headers = http_req.headers.iter()
+        .map(|(name, value)| Ok((name.clone(), substitute(value, responses, Escape::Header)?)))
+        .collect::<Result<_>>()?;
+    let body_escape = body_escape(&http_req.headers, http_req.body.as_deref());
+    let body = http_req.body.as_deref()
+        .map(|body| substitute(body, responses, body_escape))
+        .transpose()?;
+    let json = http_req.json.as_ref()
+        .map(|json| substitute_json(json, responses))
+        .transpose()?;
+    let form = http_req.form.as_ref()
+        .map(|form| substitute_values(form, responses))
+        .transpose()?;
+    let multipart = http_req.multipart.as_ref()
+        .map(|parts| parts.iter()
+            .map(|part| Ok(MultipartPart {
+                text: part.text.as_deref().map(|text| substitute(text, responses, Escape::Verbatim)).transpose()?,
+                ..part.clone()
+            }))
+            .collect::<Result<Vec<_>>>())
+        .transpose()?;
+    let query = substitute_values(&http_req.query, responses)?;
+
+    Ok(SingleHttpRequest { uri, headers, body, json, form, multipart, query, ..http_req })
+}
+
+fn substitute_values(values: &BTreeMap<String, String>, responses: &HashMap<&str, StepResponse>)
+                     -> Result<BTreeMap<String, String>>
+{
+    values.iter()
+        .map(|(name, value)| Ok((name.clone(), substitute(value, responses, Escape::Verbatim)?)))
+        .collect()
+}
+
+fn into_single_outcome(outcome: RequestOutcome, handling: &ResponseHandling) -> SingleOutcome {
+    match outcome {
+        Err(RequestError::RequestInvalid { error }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::Invalid,
+                error: error.to_string(),
+                duration_msec: Duration::from_millis(0),
+            }),
+        Err(RequestError::RequestFailure { error, duration }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::Request,
+                error: error.to_string(),
+                duration_msec: duration,
+            }),
+        Err(RequestError::RequestBlocked { error, duration }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::Blocked,
+                error: error.to_string(),
+                duration_msec: duration,
+            }),
+        Err(RequestError::RequestRateLimited { error, duration }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::RateLimited,
+                error: error.to_string(),
+                duration_msec: duration,
+            }),
+        Err(RequestError::CircuitOpen { error }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::CircuitOpen,
+                error: error.to_string(),
+                duration_msec: Duration::from_millis(0),
+            }),
+        Err(RequestError::ResponseFailure { error, duration }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::Response,
+                error: error.to_string(),
+                duration_msec: duration,
+            }),
+        Err(RequestError::ResponseTimeout { error, duration }) =>
+            SingleOutcome::Failure(SingleHttpFailure {
+                kind: FailureKind::Timeout,
+                error: error.to_string(),
+                duration_msec: duration,
+            }),
+        Ok(fetched) => {
+            let req_duration = fetched.duration;
+            let checked = match &handling.expect {
+                Some(spec) => expect::check(spec, fetched.head.status.as_u16(), &fetched.head.headers, &fetched.body)
+                    .map_err(|error| (FailureKind::Expectation, error)),
+                None => Ok(()),
+            };
+            let resp = checked.and_then(|()| into_response(fetched, handling)
+                .map_err(|error| (FailureKind::Extraction, error)));
+
+            match resp {
+                Ok(resp) => SingleOutcome::Success(resp),
+                Err((kind, error)) => SingleOutcome::Failure(SingleHttpFailure {
+                    kind,
+                    error: format!("{:#}", error),
+                    duration_msec: req_duration,
+                }),
+            }
+        }
+    }
+}
+
+// extraction only applies below 400, error responses are kept whole
+// the pages of a paginated request are JSON already
+fn into_response(fetched: Fetched, handling: &ResponseHandling) -> Result<SingleHttpResponse> {
+    let Fetched { duration, head, body, pagination, redirects } = fetched;
+    let parse_json = pagination.is_some() || handling.parse_json && is_json(&head.headers);
+    let (headers, values) = match &handling.extract {
+        Some(spec) if head.status.as_u16() < 400 => extract::extract(spec, head.headers, &body)?,
+        _ => (head.headers, None),
+    };
+
+    let json = match values {
+        Some(values) => Some(to_raw_value(&values).context("cannot serialize")?),
+        // XXX the body is copied once more, RawValue cannot take over the String after parsing
+        None if parse_json => serde_json::from_str::<&RawValue>(&body).ok().map(ToOwned::to_owned),
+        None => None,
+    };
+
+    Ok(SingleHttpResponse {
+        headers: headers.into(),
+        status: head.status.as_u16(),
+        content: match json {
+            Some(_) => None,
+            None => Some(body),
+        },
+        json,
+        pagination,
+        redirects,
+        duration_msec: duration,
+    })
+}
+
+// application/json, and structured syntax like application/problem+json
+fn is_json(headers: &HeaderMap) -> bool {
+    let essence = headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|essence| essence.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+// a failed request takes everything depending on it down, directly or indirectly
+fn skip_dependents(failed: usize, dag: &Dag, outcomes: &mut [Option<SingleOutcome>]) -> Vec<usize> {
+    let mut skipped = vec![];
+    let mut stack = vec![failed];
+
+    while let Some(index) = stack.pop() {
+        for dependent in &dag.dependents[index] {
+            if outcomes[*dependent].is_none() {
+                let error = format!("the request was skipped: {} did not succeed", dag.label(index));
+                outcomes[*dependent] = Some(skipped_outcome(error));
+                skipped.push(*dependent);
+                stack.push(*dependent);
+            }
+        }
+    }
+
+    skipped
+}
+
+fn skip_remaining(failed: usize, dag: &Dag, outcomes: &mut [Option<SingleOutcome>]) -> Vec<usize> {
+    let mut skipped = vec![];
+
+    for (index, outcome) in outcomes.iter_mut().enumerate().filter(|(_, outcome)| outcome.is_none()) {
+        let error = format!("the request was skipped: the batch stopped after {} failed", dag.label(failed));
+        *outcome = Some(skipped_outcome(error));
+        skipped.push(index);
+    }
+
+    skipped
+}
+
+fn skipped_outcome(error: String) -> SingleOutcome {
+    SingleOutcome::Failure(SingleHttpFailure {
+        kind: FailureKind::Skipped,
+        error,
+        duration_msec: Duration::from_millis(0),
+    })
+}
+
+// with expectations, whatever meets them counts as success
+fn succeeded(outcome: &SingleOutcome, handling: &ResponseHandling) -> bool {
+    matches!(outcome, SingleOutcome::Success(resp) if resp.status < 400 || handling.expect.is_some())
+}
+
+fn is_idempotent(method: HttpMethod) -> bool {
+    matches!(method, HttpMethod::GET | HttpMethod::PUT | HttpMethod::DELETE)
+}
+
+// only failures the upstream is to blame for count against its circuit
+fn upstream_health(outcome: &RequestOutcome) -> Option<bool> {
+    match outcome {
+        Ok(fetched) => Some(!fetched.head.status.is_server_error()),
+        Err(RequestError::RequestFailure { .. }) |
+        Err(RequestError::ResponseFailure { .. }) |
+        Err(RequestError::ResponseTimeout { .. }) => Some(false),
+        Err(_) => None,
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
     use anyhow::{Context, Result};
-    use hyper::{Response, Body};
+    use hyper::{Body, Client, Response, Uri};
+    use hyper::body::to_bytes;
+    use serde_json::Value;
     use thiserror::Error;
+    use tokio::time::{pause, timeout};
 
     use crate::http_client::tests::{MOCK_REQUEST_DURATION, MockHttpClient};
-    use crate::multiplexer::{GenericMultiplexer};
-    use crate::api::{OctoplexRequest, SingleHttpRequest, HttpMethod};
+    use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+    use crate::api::{AggregateSpec, Callback, CircuitState, ExecutionMode, ExpectSpec, ExtractSpec, FailureKind,
+                     ForEach, MergeMode, MultipartPart, OctoplexRequest, PageFollowing, PaginateSpec, SingleHttpRequest,
+                     CookieJarSpec, HttpMethod, RedirectPolicy, RequestTemplate, SingleOutcome};
+    use crate::callback::SIGNATURE_HEADER;
+    use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+    use crate::shaping::{OnLimit, ShapingConfig, UpstreamLimit, UpstreamShaper};
+    use crate::upstream::{Balancing, MemberConfig, UpstreamConfig, UpstreamPools};
 
     #[derive(Error, Debug)]
     enum SimpleError {
@@ -254,12 +1100,27 @@ mod tests {
         Err(SimpleError::SomeError.into())
     }
 
+    // a parallel batch, the other fields are filled in with struct update syntax
+    pub fn batch_of(requests: Vec<SingleHttpRequest>) -> OctoplexRequest {
+        OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 2,
+            execution: ExecutionMode::Parallel,
+            max_concurrency: None,
+            parse_json: false,
+            aggregate: None,
+            for_each: None,
+            cookie_jar: None,
+            callback: None,
+            template: None,
+            requests,
+        }
+    }
+
     fn google_request() -> SingleHttpRequest {
         SingleHttpRequest {
-            method: HttpMethod::GET,
+            method: Some(HttpMethod::GET),
             uri: "https://www.google.com/".to_string(),
-            headers: Default::default(),
-            body: None
+            ..Default::default()
         }
     }
 
@@ -269,11 +1130,11 @@ mod tests {
 
         let batch = OctoplexRequest {
             timeout_msec: Duration::from_millis(5_000_000),
-            requests: vec![google_request()],
+            ..batch_of(vec![google_request()])
         };
 
         let result = GenericMultiplexer::new(client)
-            .handle(batch).await;
+            .handle(batch, &BatchLimits::default()).await;
 
         assert!(result.is_err(), "expected Err, got result = {:?}", result);
     }
@@ -282,13 +1143,10 @@ mod tests {
     async fn rejects_empty_batch() {
         let client = MockHttpClient::new();
 
-        let batch = OctoplexRequest {
-            timeout_msec: MOCK_REQUEST_DURATION * 2,
-            requests: vec![],
-        };
+        let batch = batch_of(vec![]);
 
         let result = GenericMultiplexer::new(client)
-            .handle(batch).await;
+            .handle(batch, &BatchLimits::default()).await;
 
         assert!(result.is_err(), "expected Err, got result = {:?}", result);
     }
@@ -302,13 +1160,10 @@ mod tests {
             requests.push(google_request());
         }
 
-        let batch = OctoplexRequest {
-            timeout_msec: MOCK_REQUEST_DURATION * 2,
-            requests,
-        };
+        let batch = batch_of(requests);
 
         let result = GenericMultiplexer::new(client)
-            .handle(batch).await;
+            .handle(batch, &BatchLimits::default()).await;
 
         assert!(result.is_err(), "expected Err, got result = {:?}", result);
     }
@@ -318,13 +1173,10 @@ mod tests {
         let mut client = MockHttpClient::new();
         client.expect_request().returning(|_req| ok_response());
 
-        let batch = OctoplexRequest {
-            timeout_msec: MOCK_REQUEST_DURATION * 2,
-            requests: vec![google_request()],
-        };
+        let batch = batch_of(vec![google_request()]);
 
         let result = GenericMultiplexer::new(client)
-            .handle(batch).await;
+            .handle(batch, &BatchLimits::default()).await;
 
         assert!(result.is_ok(), "expected Ok, got result = {:?}", result);
         assert_eq!(result.as_ref().unwrap().responses.len(), 1);
@@ -338,32 +1190,909 @@ mod tests {
 
         let batch = OctoplexRequest {
             timeout_msec: MOCK_REQUEST_DURATION / 2,
-            requests: vec![google_request()],
+            ..batch_of(vec![google_request()])
         };
 
         let result = GenericMultiplexer::new(client)
-            .handle(batch).await;
+            .handle(batch, &BatchLimits::default()).await;
 
         assert!(result.is_ok(), "expected Ok, got result = {:?}", result);
         assert_eq!(result.as_ref().unwrap().responses.len(), 1);
         assert_eq!(result.as_ref().unwrap().responses[0].as_ref(), "Failure");
+        match &result.as_ref().unwrap().responses[0] {
+            SingleOutcome::Failure(failure) => assert_eq!(failure.kind, FailureKind::Timeout),
+            _ => unreachable!(),
+        }
     }
 
     #[tokio::test]
-    async fn handles_http_error() {
+    async fn shapes_requests_per_upstream() {
         let mut client = MockHttpClient::new();
-        client.expect_request().returning(|_req| err_response());
+        client.expect_request().times(1).returning(|_req| ok_response());
+
+        let shaper = UpstreamShaper::new(ShapingConfig {
+            on_limit: OnLimit::FailFast,
+            upstreams: vec![UpstreamLimit {
+                origin: "https://www.google.com:443".to_string(),
+                max_concurrency: Some(1),
+                ..Default::default()
+            }],
+        });
+
+        let batch = batch_of(vec![google_request(), google_request()]);
+
+        let result = GenericMultiplexer::new(client)
+            .with_shaper(shaper)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let kinds = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Failure(failure) => Some(failure.kind),
+                SingleOutcome::Success(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![None, Some(FailureKind::RateLimited)]);
+    }
+
+    #[tokio::test]
+    async fn opens_circuit_on_failures() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(2).returning(|_req| err_response());
+
+        let breaker = CircuitBreaker::new(Some(CircuitBreakerConfig {
+            consecutive_failures: Some(2),
+            error_rate: None,
+            min_requests: 10,
+            window_sec: 60,
+            open_sec: 60,
+            half_open_requests: 1,
+        }));
+        let multi = GenericMultiplexer::new(client).with_circuit_breaker(breaker);
+
+        for _ in 0..2 {
+            let batch = batch_of(vec![google_request()]);
+            multi.handle(batch, &BatchLimits::default()).await.expect("batch failed");
+        }
+
+        let batch = batch_of(vec![google_request()]);
+        let result = multi.handle(batch, &BatchLimits::default()).await.expect("batch failed");
+
+        match &result.responses[0] {
+            SingleOutcome::Failure(failure) => assert_eq!(failure.kind, FailureKind::CircuitOpen),
+            _ => unreachable!(),
+        }
+        assert_eq!(multi.circuit_stats()[0].state, CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_next_upstream_member() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(2).returning(|req| {
+            assert_eq!(req.uri().path(), "/v1/price");
+
+            match req.uri().host().unwrap() {
+                "billing-1.internal" => err_response(),
+                _ => ok_response(),
+            }
+        });
 
         let batch = OctoplexRequest {
-            timeout_msec: MOCK_REQUEST_DURATION * 2,
-            requests: vec![google_request()],
+            timeout_msec: MOCK_REQUEST_DURATION * 3,
+            ..batch_of(vec![SingleHttpRequest {
+                uri: "upstream://billing/v1/price".to_string(),
+                ..Default::default()
+            }])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .with_upstreams(billing_pool())
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        assert_eq!(result.responses[0].as_ref(), "Success");
+    }
+
+    #[tokio::test]
+    async fn fails_over_posts_only_before_connecting() {
+        // nothing listens on the discard port
+        let connect_error = Client::new().get(Uri::from_static("http://127.0.0.1:9/")).await
+            .expect_err("connected to the discard port");
+        let connect_error = Mutex::new(Some(connect_error));
+
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(3).returning(move |req| {
+            if req.uri().path() == "/v1/charge" {
+                return err_response(); // may have been charged already
+            }
+            match connect_error.lock().unwrap().take() {
+                Some(connect_error) => Err(connect_error.into()),
+                None => ok_response(),
+            }
+        });
+
+        let post = |path: &str| SingleHttpRequest {
+            method: Some(HttpMethod::POST),
+            uri: format!("upstream://billing{}", path),
+            ..Default::default()
+        };
+        let batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            execution: ExecutionMode::Sequential,
+            ..batch_of(vec![post("/v1/charge"), post("/v1/refund")])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .with_upstreams(billing_pool())
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let outcomes = result.responses.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+        assert_eq!(outcomes, vec!["Failure", "Success"]);
+    }
+
+    fn billing_pool() -> UpstreamPools {
+        let members = vec![
+            MemberConfig { uri: "https://billing-1.internal".to_string(), weight: 1 },
+            MemberConfig { uri: "https://billing-2.internal".to_string(), weight: 1 },
+        ];
+
+        UpstreamPools::new(vec![
+            ("billing".to_string(), UpstreamConfig {
+                members,
+                balancing: Balancing::RoundRobin,
+                hash_header: None,
+                health_check: None,
+            }),
+        ].into_iter().collect()).expect("invalid config")
+    }
+
+    fn dependent_batch() -> OctoplexRequest {
+        let step = |id: &str, path: &str| SingleHttpRequest {
+            id: Some(id.to_string()),
+            depends_on: vec!["login".to_string()],
+            uri: format!("https://api.example.com{}", path),
+            headers: vec![("Authorization".to_string(), "Bearer {{login.body/token}}".to_string())]
+                .into_iter().collect(),
+            ..Default::default()
         };
 
+        OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            ..batch_of(vec![
+                step("orders", "/orders"),
+                SingleHttpRequest {
+                    id: Some("login".to_string()),
+                    method: Some(HttpMethod::POST),
+                    uri: "https://auth.example.com/login".to_string(),
+                    ..Default::default()
+                },
+                step("invoices", "/invoices"),
+                SingleHttpRequest {
+                    depends_on: vec!["orders".to_string()],
+                    uri: "https://api.example.com/orders/{{orders.body/0}}".to_string(),
+                    ..Default::default()
+                },
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn executes_dependent_requests() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let body = match req.uri().path() {
+                "/login" => "{\"token\": \"t-1\"}",
+                "/orders" => "[17, 18]",
+                "/orders/17" | "/invoices" => "{}",
+                path => panic!("unexpected request to {}", path),
+            };
+            if req.uri().host() == Some("api.example.com") && req.uri().path() != "/orders/17" {
+                assert_eq!(req.headers()["Authorization"], "Bearer t-1");
+            }
+
+            Response::builder().status(200).body(Body::from(body)).context("cannot build response")
+        });
+
+        let result = GenericMultiplexer::new(client)
+            .handle(dependent_batch(), &BatchLimits::default()).await
+            .expect("batch failed");
+
+        assert!(result.responses.iter().all(|outcome| outcome.as_ref() == "Success"), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn embeds_json_responses() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let (content_type, body) = match req.uri().path() {
+                "/login" => ("application/json; charset=utf-8", "{\"token\": \"t-1\"}"),
+                "/orders" => ("application/json", " [17, 18]\n"),
+                "/orders/17" => ("application/problem+json", "not json"),
+                _ => ("application/json", "{}"),
+            };
+
+            Response::builder().status(200).header("Content-Type", content_type).body(Body::from(body))
+                .context("cannot build response")
+        });
+
+        let mut batch = OctoplexRequest { parse_json: true, ..dependent_batch() };
+        batch.requests[2].parse_json = Some(false);
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+        let result = serde_json::to_value(&result).unwrap();
+
+        let bodies = result["responses"].as_array().unwrap().iter()
+            .map(|outcome| (&outcome["Success"]["json"], outcome["Success"].get("content")))
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, vec![
+            (&serde_json::json!([17, 18]), None),
+            (&serde_json::json!({"token": "t-1"}), None),
+            (&Value::Null, Some(&serde_json::json!("{}"))),
+            (&Value::Null, Some(&serde_json::json!("not json"))),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn extracts_response_values() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let body = match req.uri().path() {
+                "/login" => "{\"token\": \"t-1\", \"user\": {\"name\": \"octoplex\"}}",
+                "/orders" => "[17, 18]",
+                _ => "{}",
+            };
+
+            Response::builder().status(200).header("X-Request-Id", "r-1").body(Body::from(body))
+                .context("cannot build response")
+        });
+
+        let extract = |values: &[(&str, &str)]| Some(ExtractSpec {
+            values: values.iter().map(|(name, expr)| (name.to_string(), expr.to_string())).collect(),
+            headers: Some(vec![]),
+        });
+        let mut batch = dependent_batch();
+        batch.requests[1].extract = extract(&[("token", "/token")]);
+        batch.requests[2].extract = extract(&[("id", "/id")]);
+
         let result = GenericMultiplexer::new(client)
-            .handle(batch).await;
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        match &result.responses[1] {
+            SingleOutcome::Success(resp) => {
+                assert_eq!(resp.json.as_ref().map(|json| json.get()), Some(r#"{"token":"t-1"}"#));
+                assert!(resp.content.is_none() && resp.headers.as_map().is_empty());
+            }
+            outcome => panic!("login failed: {:?}", outcome),
+        }
+        match &result.responses[2] {
+            SingleOutcome::Failure(failure) => {
+                assert_eq!(failure.kind, FailureKind::Extraction);
+                assert_eq!(failure.error, "cannot extract id: nothing at /id");
+            }
+            outcome => panic!("extraction did not fail: {:?}", outcome),
+        }
+        assert_eq!(result.responses[3].as_ref(), "Success", "placeholders refer to the extracted values");
+    }
+
+    #[tokio::test]
+    async fn applies_response_expectations() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let (status, body) = match req.uri().path() {
+                "/login" => (404, "{\"token\": \"t-1\"}"),
+                "/orders" => (200, "[17, 18]"),
+                "/invoices" => (500, "{}"),
+                _ => (200, "{\"state\": \"open\"}"),
+            };
+
+            Response::builder().status(status).body(Body::from(body)).context("cannot build response")
+        });
+
+        let expect = |expect: Value| Some(serde_json::from_value::<ExpectSpec>(expect).unwrap());
+        let mut batch = dependent_batch();
+        batch.requests[1].expect = expect(serde_json::json!({ "status": [404] }));
+        batch.requests[2].expect = expect(serde_json::json!({ "status": ["2xx"] }));
+        batch.requests[3].expect = expect(serde_json::json!({ "json": { "/state": "closed" } }));
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let errors = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Failure(failure) => Some((failure.kind, failure.error.as_str())),
+                SingleOutcome::Success(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![
+            None,
+            None, // a 404 which was expected lets its dependents run
+            Some((FailureKind::Expectation, "expectation failed: status 500 is not 2xx")),
+            Some((FailureKind::Expectation, "expectation failed: /state is \"open\", expected \"closed\"")),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn aggregates_json_responses() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let body = match req.uri().path() {
+                "/login" => "{\"token\": \"t-1\"}",
+                "/orders" => "[17, 18]",
+                "/invoices" => "not json",
+                _ => "{\"id\": 17}",
+            };
+
+            Response::builder().status(200).body(Body::from(body)).context("cannot build response")
+        });
+
+        let batch = OctoplexRequest {
+            aggregate: Some(AggregateSpec { merge: MergeMode::ByKey, pointer: String::new() }),
+            ..dependent_batch()
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let aggregate = result.aggregate.expect("no aggregate");
+        assert_eq!(aggregate.document, serde_json::json!({
+            "login": {"token": "t-1"},
+            "orders": [17, 18],
+            "3": {"id": 17},
+        }));
+        assert_eq!(aggregate.missing, vec!["invoices"]);
+    }
+
+    #[tokio::test]
+    async fn follows_pages() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let (link, body) = match req.uri().query() {
+                None => ("</items?page=2>; rel=\"next\"", "{\"items\": [1, 2]}"),
+                Some("page=2") => ("</items?page=3>; rel=\"next\"", "{\"items\": [3]}"),
+                Some("page=3") => ("", "{\"items\": [4]}"),
+                Some("offset=0") => ("", "[\"a\"]"),
+                query => panic!("unexpected query {:?}", query),
+            };
+
+            Response::builder().status(200).header("Link", link).body(Body::from(body))
+                .context("cannot build response")
+        });
+
+        let paginated = |follow, max_pages, items: Option<&str>| SingleHttpRequest {
+            uri: "https://api.example.com/items".to_string(),
+            paginate: Some(PaginateSpec { follow, max_pages, items: items.map(str::to_string) }),
+            ..Default::default()
+        };
+        let batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            ..batch_of(vec![
+                paginated(PageFollowing::Link, 10, Some("/items")),
+                paginated(PageFollowing::Param { name: "offset".to_string(), start: 0, step: 1 }, 1, None),
+            ])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let pages = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Success(resp) => {
+                    let pagination = resp.pagination.as_ref().expect("no pagination");
+                    (resp.body(), pagination.pages, pagination.next_uri.as_deref())
+                }
+                SingleOutcome::Failure(failure) => panic!("request failed: {:?}", failure),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pages, vec![
+            ("[1,2,3,4]", 3, None),
+            (r#"[["a"]]"#, 1, Some("https://api.example.com/items?offset=1")),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(8).returning(|req| {
+            let credentials = req.headers().contains_key("Authorization");
+            let (status, location) = match (req.method().as_str(), req.uri().host(), req.uri().path()) {
+                ("POST", Some("api.example.com"), "/login") if credentials => (303, "/home"),
+                ("GET", Some("api.example.com"), "/home") if credentials => (200, ""),
+                ("GET", Some("api.example.com"), "/loop") => (302, "/loop"),
+                ("GET", Some("api.example.com"), "/away") => (302, "https://other.example.com/"),
+                ("PUT", Some("api.example.com"), "/moved") => (307, "https://eu.example.com/moved"),
+                ("PUT", Some("eu.example.com"), "/moved") if !credentials => (200, ""),
+                request => panic!("unexpected request {:?}", request),
+            };
+
+            let mut resp = Response::builder().status(status);
+            if !location.is_empty() {
+                resp = resp.header("Location", location);
+            }
+            resp.body(Body::empty()).context("cannot build response")
+        });
+
+        let redirected = |method, path: &str, max_hops, same_origin| SingleHttpRequest {
+            method: Some(method),
+            uri: format!("https://api.example.com{}", path),
+            headers: HashMap::from([("Authorization".to_string(), "Bearer x".to_string())]),
+            body: Some("{}".to_string()),
+            follow_redirects: Some(RedirectPolicy { max_hops, same_origin }),
+            ..Default::default()
+        };
+        let batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            ..batch_of(vec![
+                redirected(HttpMethod::POST, "/login", 5, false),
+                redirected(HttpMethod::GET, "/loop", 2, false),
+                redirected(HttpMethod::GET, "/away", 5, true),
+                redirected(HttpMethod::PUT, "/moved", 5, false),
+            ])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let outcomes = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Success(resp) => {
+                    let redirects = resp.redirects.as_ref().expect("no redirects");
+                    let chain = redirects.chain.iter().map(|hop| (hop.uri.as_str(), hop.status)).collect::<Vec<_>>();
+                    Ok((resp.status, redirects.final_uri.as_str(), chain))
+                }
+                SingleOutcome::Failure(failure) => Err(failure.error.as_str()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes, vec![
+            Ok((200, "https://api.example.com/home", vec![("https://api.example.com/login", 303)])),
+            Err("more than 2 redirects, the last one to https://api.example.com/loop"),
+            Ok((302, "https://api.example.com/away", vec![])),
+            Ok((200, "https://eu.example.com/moved", vec![("https://api.example.com/moved", 307)])),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn carries_cookies() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(5).returning(|req| {
+            let set_cookies: &[&str] = match (req.uri().host(), req.uri().path()) {
+                (Some("api.example.com"), "/login") =>
+                    &["session=abc; Path=/; Secure", "tracker=1; Domain=example.com"],
+                (Some("api.example.com"), "/logout") => &["session=; Max-Age=0"],
+                _ => &[],
+            };
+            let cookies = req.headers().get("Cookie").map(|value| value.to_str().unwrap().to_string());
+
+            let mut resp = Response::builder().status(200);
+            if req.uri().path() == "/login" {
+                resp = resp.status(302).header("Location", "/home");
+            }
+            for set_cookie in set_cookies {
+                resp = resp.header("Set-Cookie", *set_cookie);
+            }
+            resp.body(Body::from(cookies.unwrap_or_default())).context("cannot build response")
+        });
+
+        let get = |uri: &str| SingleHttpRequest { uri: uri.to_string(), ..Default::default() };
+        let batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 10,
+            execution: ExecutionMode::Sequential,
+            cookie_jar: Some(CookieJarSpec { return_jar: true }),
+            ..batch_of(vec![
+                SingleHttpRequest {
+                    method: Some(HttpMethod::POST),
+                    follow_redirects: Some(RedirectPolicy { max_hops: 1, same_origin: true }),
+                    ..get("https://api.example.com/login")
+                },
+                get("https://www.example.com/"),
+                get("https://other.org/"),
+                get("https://api.example.com/logout"),
+            ])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let sent = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Success(resp) => resp.body(),
+                SingleOutcome::Failure(failure) => panic!("request failed: {:?}", failure),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["session=abc; tracker=1", "tracker=1", "", "session=abc; tracker=1"]);
+
+        let jar = result.cookies.expect("no cookie jar").into_iter()
+            .map(|cookie| (cookie.name, cookie.domain, cookie.host_only))
+            .collect::<Vec<_>>();
+        assert_eq!(jar, vec![("tracker".to_string(), "example.com".to_string(), false)]);
+    }
+
+    #[tokio::test]
+    async fn scopes_cookies_to_dependencies() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let cookies = req.headers().get("Cookie").map(|value| value.to_str().unwrap().to_string());
+            let mut resp = Response::builder().status(200);
+            if req.uri().path() == "/login" {
+                resp = resp.header("Set-Cookie", "session=abc");
+            }
+            resp.body(Body::from(cookies.unwrap_or_default())).context("cannot build response")
+        });
+
+        let request = |id: &str, path: &str, depends_on: &[&str]| SingleHttpRequest {
+            id: Some(id.to_string()),
+            depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+            uri: format!("https://api.example.com{}", path),
+            ..Default::default()
+        };
+        // b starts right after login has finished, without depending on it
+        let batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            cookie_jar: Some(CookieJarSpec { return_jar: false }),
+            ..batch_of(vec![
+                request("login", "/login", &[]),
+                request("x", "/x", &[]),
+                request("a", "/a", &["login"]),
+                request("b", "/b", &["x"]),
+            ])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let sent = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Success(resp) => resp.body(),
+                SingleOutcome::Failure(failure) => panic!("request failed: {:?}", failure),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["", "", "session=abc", ""]);
+    }
+
+    #[tokio::test]
+    async fn expands_for_each() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|req| {
+            let body = match req.uri().path() {
+                "/users/7" => "{\"name\": \"seven\"}".to_string(),
+                path => path.to_string(),
+            };
+
+            Response::builder().status(200).body(Body::from(body)).context("cannot build response")
+        });
+
+        let for_each = |params: Value| Some(ForEach {
+            request: serde_json::json!({
+                "id": "user-{{user_id}}",
+                "uri": "https://api.example.com/users/{{user_id}}"
+            }),
+            params: serde_json::from_value(params).unwrap(),
+        });
+        let mut batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            for_each: for_each(serde_json::json!([{ "user_id": 7 }, { "user_id": "8" }, { "user_id": 9 }])),
+            ..batch_of(vec![SingleHttpRequest {
+                depends_on: vec!["user-7".to_string()],
+                uri: "https://api.example.com/names/{{user-7.body/name}}".to_string(),
+                ..Default::default()
+            }])
+        };
+
+        let multi = GenericMultiplexer::new(client);
+        let result = multi.handle(batch.clone(), &BatchLimits::default()).await.expect("batch failed");
+
+        let bodies = result.responses.iter().map(|outcome| match outcome {
+            SingleOutcome::Success(resp) => resp.body(),
+            SingleOutcome::Failure(failure) => panic!("request failed: {:?}", failure),
+        }).collect::<Vec<_>>();
+        assert_eq!(bodies, vec!["/names/seven", "{\"name\": \"seven\"}", "/users/8", "/users/9"]);
+
+        batch.for_each = for_each(serde_json::json!([{ "user_id": 7 }, { "id": 8 }]));
+        let result = multi.handle(batch.clone(), &BatchLimits::default()).await;
+        assert!(format!("{:?}", result.unwrap_err()).contains("params #1 lack user_id"));
+
+        batch.for_each = for_each(serde_json::json!([{ "user_id": ".." }]));
+        let result = multi.handle(batch.clone(), &BatchLimits::default()).await;
+        assert!(format!("{:?}", result.unwrap_err()).contains("cannot be substituted into a uri"));
+
+        let limits = BatchLimits { max_batch_size: 3, ..Default::default() };
+        batch.for_each = for_each(serde_json::json!([{ "user_id": 7 }, { "user_id": 8 }, { "user_id": 9 }]));
+        assert!(multi.handle(batch, &limits).await.is_err(), "expanded batch is too large");
+    }
+
+    #[tokio::test]
+    async fn encodes_substituted_structured_requests() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(3).returning(|req| {
+            if req.uri().path() == "/upload" {
+                let content_type = req.headers()["Content-Type"].to_str().unwrap().to_string();
+                assert!(content_type.starts_with("multipart/form-data; boundary="), "{}", content_type);
+                let body = futures::executor::block_on(to_bytes(req.into_body())).unwrap();
+                let part = b"\r\n\r\nO'Brien \"Jr\"\r\n";
+                assert!(body.windows(part.len()).any(|text| text == part), "{:?}", body);
+            } else if req.uri().path() == "/orders" {
+                assert_eq!(req.uri().query(), Some("limit=5&user=O%27Brien+%22Jr%22"));
+                assert_eq!(req.headers()["Content-Type"], "application/json");
+                let body = futures::executor::block_on(to_bytes(req.into_body())).unwrap();
+                assert_eq!(body, r#"{"user":"O'Brien \"Jr\""}"#);
+            }
+
+            let body = r#"{"name": "O'Brien \"Jr\""}"#;
+            Response::builder().status(200).body(Body::from(body)).context("cannot build response")
+        });
+
+        let batch = OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 4,
+            ..batch_of(vec![
+                SingleHttpRequest {
+                    id: Some("user".to_string()),
+                    uri: "https://api.example.com/user".to_string(),
+                    ..Default::default()
+                },
+                SingleHttpRequest {
+                    depends_on: vec!["user".to_string()],
+                    method: Some(HttpMethod::POST),
+                    uri: "https://api.example.com/orders?limit=5".to_string(),
+                    json: Some(serde_json::json!({"user": "{{user.body/name}}"})),
+                    query: vec![("user".to_string(), "{{user.body/name}}".to_string())].into_iter().collect(),
+                    ..Default::default()
+                },
+                SingleHttpRequest {
+                    depends_on: vec!["user".to_string()],
+                    method: Some(HttpMethod::POST),
+                    uri: "https://api.example.com/upload".to_string(),
+                    headers: vec![("Content-Type".to_string(), "text/plain".to_string())].into_iter().collect(),
+                    multipart: Some(vec![MultipartPart {
+                        name: "name".to_string(),
+                        text: Some("{{user.body/name}}".to_string()),
+                        ..Default::default()
+                    }]),
+                    ..Default::default()
+                },
+            ])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        assert!(result.responses.iter().all(|outcome| outcome.as_ref() == "Success"), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn skips_dependents_of_failed_requests() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(1).returning(|_req| {
+            Response::builder().status(401).body(Body::empty()).context("cannot build response")
+        });
+
+        let result = GenericMultiplexer::new(client)
+            .handle(dependent_batch(), &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let kinds = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Failure(failure) => Some(failure.kind),
+                SingleOutcome::Success(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![Some(FailureKind::Skipped), None, Some(FailureKind::Skipped),
+                               Some(FailureKind::Skipped)]);
+    }
+
+    #[tokio::test]
+    async fn rejects_dependency_cycles() {
+        let client = MockHttpClient::new();
+        let mut batch = dependent_batch();
+        batch.requests[1].depends_on = vec!["invoices".to_string()];
+
+        let multi = GenericMultiplexer::new(client);
+
+        assert!(multi.validate(batch.clone(), &BatchLimits::default()).is_err(), "rejected before running");
+        assert!(multi.handle(batch, &BatchLimits::default()).await.is_err());
+        assert!(multi.validate(dependent_batch(), &BatchLimits::default()).is_ok());
+    }
+
+    fn numbered_batch(execution: ExecutionMode, max_concurrency: Option<usize>) -> OctoplexRequest {
+        OctoplexRequest {
+            timeout_msec: MOCK_REQUEST_DURATION * 10,
+            execution,
+            max_concurrency,
+            ..batch_of((0..4)
+                .map(|n| SingleHttpRequest {
+                    uri: format!("https://www.google.com/{}", n),
+                    ..Default::default()
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_sequential_batch_on_failure() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut client = MockHttpClient::new();
+        let sent_by_client = sent.clone();
+        client.expect_request().times(2).returning(move |req| {
+            sent_by_client.lock().unwrap().push(req.uri().path().to_string());
+
+            match req.uri().path() {
+                "/1" => err_response(),
+                _ => ok_response(),
+            }
+        });
+
+        let result = GenericMultiplexer::new(client)
+            .handle(numbered_batch(ExecutionMode::SequentialStopOnFailure, None), &BatchLimits::default()).await
+            .expect("batch failed");
+
+        assert_eq!(*sent.lock().unwrap(), vec!["/0", "/1"]);
+        let kinds = result.responses.iter()
+            .map(|outcome| match outcome {
+                SingleOutcome::Failure(failure) => Some(failure.kind),
+                SingleOutcome::Success(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![None, Some(FailureKind::Request), Some(FailureKind::Skipped),
+                               Some(FailureKind::Skipped)]);
+    }
+
+    #[tokio::test]
+    async fn limits_batch_concurrency() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(4).returning(|_req| ok_response());
+
+        pause();
+        let multi = GenericMultiplexer::new(client);
+        let limits = BatchLimits::default();
+        let handling = multi.handle(numbered_batch(ExecutionMode::Parallel, Some(2)), &limits);
+        tokio::pin!(handling);
+
+        // two rounds of two requests each
+        assert!(timeout(MOCK_REQUEST_DURATION * 2 - Duration::from_millis(1), &mut handling).await.is_err());
+        let result = timeout(Duration::from_millis(1), &mut handling).await
+            .expect("more than two rounds")
+            .expect("batch failed");
+        assert!(result.responses.iter().all(|outcome| outcome.as_ref() == "Success"));
+
+        let client = MockHttpClient::new();
+        let result = GenericMultiplexer::new(client)
+            .handle(numbered_batch(ExecutionMode::Parallel, Some(0)), &BatchLimits::default()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn delivers_callback_after_batch() {
+        // the batch and the callback are sent by clones of the client, each in a task of its own
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let delivered_by_client = delivered.clone();
+        let mut client = MockHttpClient::new();
+        client.expect_clone().times(1).returning(move || {
+            let delivered = delivered_by_client.clone();
+            let mut batch_client = MockHttpClient::new();
+            batch_client.expect_request().returning(|_req| ok_response());
+            batch_client.expect_clone().returning(move || {
+                let delivered = delivered.clone();
+                let mut callback_client = MockHttpClient::new();
+                callback_client.expect_request().returning(move |req| {
+                    delivered.lock().unwrap().push((req.uri().to_string(), req.headers().get(SIGNATURE_HEADER).cloned()));
+                    ok_response()
+                });
+                callback_client
+            });
+            batch_client
+        });
+
+        let batch = OctoplexRequest {
+            callback: Some(Callback {
+                uri: "https://hooks.example.com/done".to_string(),
+                secret: Some("s3cret".to_string()),
+            }),
+            ..batch_of(vec![google_request()])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle_detached(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+        assert_eq!(result.responses[0].as_ref(), "Success");
+
+        tokio::time::sleep(MOCK_REQUEST_DURATION * 2).await;
+        {
+            let delivered = delivered.lock().unwrap();
+            assert_eq!(delivered.len(), 1);
+            assert_eq!(delivered[0].0, "https://hooks.example.com/done");
+            assert!(delivered[0].1.is_some(), "unsigned callback");
+        }
+
+        let batch = OctoplexRequest {
+            callback: Some(Callback { uri: "ftp://hooks.example.com/".to_string(), secret: None }),
+            ..numbered_batch(ExecutionMode::Parallel, None)
+        };
+        let mut client = MockHttpClient::new();
+        client.expect_clone().returning(MockHttpClient::new);
+        let result = GenericMultiplexer::new(client)
+            .handle_detached(batch, &BatchLimits::default()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn handles_http_error() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().returning(|_req| err_response());
+
+        let batch = batch_of(vec![google_request()]);
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await;
 
         assert!(result.is_ok(), "expected Ok, got result = {:?}", result);
         assert_eq!(result.as_ref().unwrap().responses.len(), 1);
         assert_eq!(result.as_ref().unwrap().responses[0].as_ref(), "Failure");
     }
+
+    #[tokio::test]
+    async fn applies_batch_template() {
+        let mut client = MockHttpClient::new();
+        client.expect_request()
+            .withf(|req| req.method() == "POST" && req.uri() == "https://www.google.com/search?q=octoplex")
+            .returning(|_req| ok_response());
+
+        let batch = OctoplexRequest {
+            template: Some(RequestTemplate {
+                method: Some(HttpMethod::POST),
+                uri_base: Some("https://www.google.com/".to_string()),
+                ..Default::default()
+            }),
+            ..batch_of(vec![SingleHttpRequest {
+                uri: "search?q=octoplex".to_string(),
+                ..Default::default()
+            }])
+        };
+
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await;
+
+        assert!(result.is_ok(), "expected Ok, got result = {:?}", result);
+        assert_eq!(result.as_ref().unwrap().responses[0].as_ref(), "Success");
+    }
+
+    #[tokio::test]
+    async fn applies_client_limits() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(1).returning(|_req| ok_response());
+
+        let limits = BatchLimits {
+            max_batch_size: 2,
+            allowed_hosts: vec!["*.google.com".to_string()],
+            ..Default::default()
+        };
+        let multiplexer = GenericMultiplexer::new(client);
+
+        let batch = batch_of(vec![google_request(), google_request(), google_request()]);
+        let result = multiplexer.handle(batch, &limits).await;
+        assert!(result.is_err(), "expected Err, got result = {:?}", result);
+
+        let batch = batch_of(vec![
+            google_request(),
+            SingleHttpRequest {
+                uri: "https://www.example.com/".to_string(),
+                ..Default::default()
+            },
+        ]);
+        let result = multiplexer.handle(batch, &limits).await;
+        assert!(result.is_ok(), "expected Ok, got result = {:?}", result);
+        assert_eq!(result.as_ref().unwrap().responses[0].as_ref(), "Success");
+        match &result.as_ref().unwrap().responses[1] {
+            SingleOutcome::Failure(failure) => assert_eq!(failure.kind, FailureKind::Blocked),
+            _ => panic!("expected a blocked request"),
+        }
+    }
 }
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use anyhow::{bail, Context, Result};
+use http::HeaderMap;
+use http::header::LINK;
+use serde_json::Value;
+use url::Url;
+
+use crate::api::{PageFollowing, PaginateSpec};
+use crate::encoding::merge_query;
+
+const MAX_PAGES: usize = 100; // XXX config
+
+pub fn validate(spec: &PaginateSpec) -> Result<()> {
+    if spec.max_pages == 0 || spec.max_pages > MAX_PAGES {
+        bail!("max_pages has to be between 1 and {}", MAX_PAGES);
+    }
+
+    let next_uri = match &spec.follow {
+        PageFollowing::NextUri(pointer) => Some(pointer),
+        _ => None,
+    };
+    if let Some(pointer) = spec.items.iter().chain(next_uri).find(|pointer| !pointer.starts_with('/')) {
+        bail!("{:?} is not a JSON Pointer", pointer);
+    }
+    if let PageFollowing::Param { start, step, .. } = spec.follow {
+        if step == 0 {
+            bail!("step has to be at least 1");
+        }
+        if param_value(start, step, spec.max_pages).is_none() {
+            bail!("start and step exceed the largest page parameter");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn first_page(spec: &PaginateSpec, uri: &str) -> Result<String> {
+    match &spec.follow {
+        PageFollowing::Param { name, start, .. } => with_param(uri, name, *start),
+        _ => Ok(uri.to_string()),
+    }
+}
+
+// after `fetched` pages, the last of which was `page_uri`, relative uris are resolved against it
+pub fn next_page(spec: &PaginateSpec, page_uri: &str, fetched: usize, headers: &HeaderMap, page: &Value)
+                 -> Result<Option<String>>
+{
+    let next = match &spec.follow {
+        PageFollowing::Link => next_link(headers),
+        PageFollowing::NextUri(pointer) => page.pointer(pointer)
+            .and_then(Value::as_str)
+            .filter(|uri| !uri.is_empty())
+            .map(str::to_string),
+        // there is no telling where the end is, other than an empty page
+        PageFollowing::Param { name, start, step } => match items(spec, page).is_some_and(Vec::is_empty) {
+            true => None,
+            false => {
+                let value = param_value(*start, *step, fetched).context("page parameter out of range")?;
+                return with_param(page_uri, name, value).map(Some);
+            }
+        },
+    };
+
+    next.map(|next| {
+        Url::parse(page_uri)
+            .and_then(|base| base.join(&next))
+            .map(|uri| uri.to_string())
+            .with_context(|| format!("invalid next page {}", next))
+    }).transpose()
+}
+
+// the items of a page, the page itself if there is no pointer to them
+pub fn items<'a>(spec: &PaginateSpec, page: &'a Value) -> Option<&'a Vec<Value>> {
+    page.pointer(spec.items.as_deref().unwrap_or_default())?.as_array()
+}
+
+// of the page after `fetched` pages, None on overflow
+fn param_value(start: u64, step: u64, fetched: usize) -> Option<u64> {
+    u64::try_from(fetched).ok()?.checked_mul(step)?.checked_add(start)
+}
+
+fn with_param(uri: &str, name: &str, value: u64) -> Result<String> {
+    merge_query(uri, &BTreeMap::from([(name.to_string(), value.to_string())]))
+}
+
+// Link: <https://api.example.com/items?page=2>; rel="next", <...>; rel="last"
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    for value in headers.get_all(LINK).iter().filter_map(|value| value.to_str().ok()) {
+        let mut rest = value;
+
+        while let Some(start) = rest.find('<') {
+            let end = start + rest[start..].find('>')?;
+            let uri = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+
+            let params = &rest[..rest.find('<').unwrap_or(rest.len())];
+            let is_next = params.split(';')
+                .filter_map(|param| param.trim().strip_prefix("rel="))
+                .any(|rel| rel.trim_matches('"').split_whitespace().any(|rel| rel.eq_ignore_ascii_case("next")));
+            if is_next {
+                return Some(uri.to_string());
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+    use serde_json::json;
+
+    use crate::api::{OctoplexRequest, PageFollowing, PaginateSpec};
+    use crate::paginate::{first_page, next_page, validate};
+
+    fn spec(follow: PageFollowing, items: Option<&str>) -> PaginateSpec {
+        PaginateSpec { follow, max_pages: 10, items: items.map(str::to_string) }
+    }
+
+    #[test]
+    fn finds_next_pages() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Link", HeaderValue::from_static(
+            r#"<https://api.example.com/items?page=1>; rel="prev first", </items?page=3>; rel="next""#));
+        let page_uri = "https://api.example.com/items?page=2";
+
+        let link = spec(PageFollowing::Link, None);
+        assert_eq!(next_page(&link, page_uri, 2, &headers, &json!([])).unwrap().as_deref(),
+                   Some("https://api.example.com/items?page=3"));
+        assert_eq!(next_page(&link, page_uri, 2, &HeaderMap::new(), &json!([])).unwrap(), None);
+
+        let next_uri = spec(PageFollowing::NextUri("/links/next".to_string()), Some("/data"));
+        let page = json!({"data": [1], "links": {"next": "?cursor=abc"}});
+        assert_eq!(next_page(&next_uri, page_uri, 1, &headers, &page).unwrap().as_deref(),
+                   Some("https://api.example.com/items?cursor=abc"));
+        assert_eq!(next_page(&next_uri, page_uri, 1, &headers, &json!({"links": {"next": null}})).unwrap(), None);
+
+        let offset = spec(PageFollowing::Param { name: "offset".to_string(), start: 0, step: 50 }, Some("/data"));
+        let first = first_page(&offset, "https://api.example.com/items?q=x").unwrap();
+        assert_eq!(first, "https://api.example.com/items?q=x&offset=0");
+        assert_eq!(next_page(&offset, &first, 1, &headers, &page).unwrap().as_deref(),
+                   Some("https://api.example.com/items?q=x&offset=50"));
+        assert_eq!(next_page(&offset, &first, 1, &headers, &json!({"data": []})).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_pagination() {
+        assert!(validate(&spec(PageFollowing::Link, Some("/items"))).is_ok());
+        assert!(validate(&spec(PageFollowing::Link, Some("items"))).is_err());
+        assert!(validate(&spec(PageFollowing::NextUri("next".to_string()), None)).is_err());
+        assert!(validate(&spec(PageFollowing::Param { name: "page".to_string(), start: 1, step: 0 }, None)).is_err());
+        assert!(validate(&PaginateSpec { max_pages: 1000, ..spec(PageFollowing::Link, None) }).is_err());
+        assert!(validate(&spec(PageFollowing::Param { name: "page".to_string(), start: u64::MAX, step: 1 }, None))
+            .is_err());
+        assert!(validate(&spec(PageFollowing::Param { name: "page".to_string(), start: 0, step: u64::MAX / 5 }, None))
+            .is_err());
+    }
+
+    #[test]
+    fn counts_pages_and_redirects_against_the_quota() {
+        let batch: OctoplexRequest = serde_json::from_value(json!({
+            "timeout_msec": 1000,
+            "requests": [
+                { "uri": "https://api.example.com/a" },
+                { "uri": "https://api.example.com/b", "paginate": { "follow": "link", "max_pages": 20 } },
+                { "uri": "https://api.example.com/c", "paginate": { "follow": "link", "max_pages": 2 },
+                  "follow_redirects": { "max_hops": 3 } },
+            ],
+            "for_each": {
+                "request": { "uri": "https://api.example.com/{{x}}", "paginate": { "follow": "link" },
+                             "follow_redirects": {} },
+                "params": [{ "x": "d" }, { "x": "e" }]
+            }
+        })).unwrap();
+
+        assert_eq!(batch.request_count(), 5);
+        assert_eq!(batch.quota_cost(), 1 + 20 + 2 * 4 + 2 * 10 * 6);
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::header::{HeaderMap, CONTENT_TYPE};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+// {{login.status}}, {{login.header.X-Token}}, {{login.body}} or {{login.body/data/token}}
+// {{user_id}}, without a dot, is a parameter of a recipe or of for_each instead
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+// the response of a request that a placeholder refers to
+pub struct StepResponse<'a> {
+    pub status: u16,
+    pub headers: &'a HeaderMap,
+    pub body: &'a str,
+}
+
+enum Field<'a> {
+    Status,
+    Header(&'a str),
+    Body(&'a str), // JSON pointer, empty for the entire body
+}
+
+struct Placeholder<'a> {
+    id: &'a str,
+    field: Field<'a>,
+}
+
+#[derive(Error, Debug)]
+#[error("parameter {0:?} cannot be substituted into a uri")]
+pub struct InvalidUriParameter(pub String);
+
+// how substituted values are escaped for the place they end up in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escape {
+    Verbatim, // encoded later, like form and query values
+    Uri, // percent-encoded, so they stay within their path segment or query value
+    Header, // no line breaks
+    Json, // escaped within string literals of a JSON body, verbatim elsewhere
+}
+
+// the ids of all requests referenced by placeholders in the text
+pub fn references(text: &str) -> Vec<&str> {
+    placeholders(text).into_iter()
+        .map(|(_, placeholder)| placeholder.id)
+        .collect()
+}
+
+// the ids referenced by placeholders in any string of a JSON value
+pub fn json_references(value: &Value) -> Vec<&str> {
+    match value {
+        Value::String(text) => references(text),
+        Value::Array(values) => values.iter().flat_map(json_references).collect(),
+        Value::Object(map) => map.values().flat_map(json_references).collect(),
+        _ => vec![],
+    }
+}
+
+// substitutes in string values, the substituted values end up properly escaped once serialized
+pub fn substitute_json(value: &Value, responses: &HashMap<&str, StepResponse>) -> Result<Value> {
+    Ok(match value {
+        Value::String(text) => Value::String(substitute(text, responses, Escape::Verbatim)?),
+        Value::Array(values) => Value::Array(values.iter()
+            .map(|value| substitute_json(value, responses))
+            .collect::<Result<_>>()?),
+        Value::Object(map) => Value::Object(map.iter()
+            .map(|(key, value)| Ok((key.clone(), substitute_json(value, responses)?)))
+            .collect::<Result<_>>()?),
+        _ => value.clone(),
+    })
+}
+
+// anything between braces that is not a valid placeholder is left as it is
+pub fn substitute(text: &str, responses: &HashMap<&str, StepResponse>, escape: Escape) -> Result<String> {
+    let mut substituted = String::with_capacity(text.len());
+    let mut copied = 0;
+
+    for (range, placeholder) in placeholders(text) {
+        let value = resolve(&placeholder, responses)?;
+        substituted.push_str(&text[copied..range.start]);
+        match escape {
+            Escape::Verbatim => substituted.push_str(&value),
+            Escape::Uri => substituted.push_str(&encode_uri_value(&value)
+                .ok_or_else(|| anyhow!("{} cannot be substituted into a uri", value))?),
+            Escape::Header => {
+                if value.contains(['\r', '\n']) {
+                    bail!("value of {} has a line break and cannot be substituted into a header", placeholder.id);
+                }
+                substituted.push_str(&value);
+            }
+            Escape::Json if in_json_string(&text[..range.start]) => {
+                let quoted = Value::String(value).to_string();
+                substituted.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Escape::Json => substituted.push_str(&value),
+        }
+        copied = range.end;
+    }
+    substituted.push_str(&text[copied..]);
+
+    Ok(substituted)
+}
+
+// everything but unreserved characters is percent-encoded, None for . and .. which are path segments still
+pub fn encode_uri_value(value: &str) -> Option<String> {
+    if value == "." || value == ".." {
+        return None;
+    }
+
+    Some(value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect())
+}
+
+// a JSON Content-Type, or a body that looks like JSON without one
+pub fn body_escape(headers: &HashMap<String, String>, body: Option<&str>) -> Escape {
+    let content_type = headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE.as_str()))
+        .map(|(_, value)| value.to_ascii_lowercase());
+    let is_json = match content_type {
+        Some(content_type) => content_type.contains("json"),
+        None => body.is_some_and(|body| body.trim_start().starts_with(['{', '['])),
+    };
+
+    match is_json {
+        true => Escape::Json,
+        false => Escape::Verbatim,
+    }
+}
+
+// whether the end of the JSON text lies within a string literal
+fn in_json_string(text: &str) -> bool {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in text.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ => (),
+        }
+    }
+
+    in_string
+}
+
+fn placeholders(text: &str) -> Vec<(Range<usize>, Placeholder<'_>)> {
+    let mut found = vec![];
+    let mut position = 0;
+
+    while let Some(start) = text[position..].find(OPEN).map(|start| position + start) {
+        let end = match text[start + OPEN.len()..].find(CLOSE) {
+            Some(end) => start + OPEN.len() + end + CLOSE.len(),
+            None => break,
+        };
+
+        match parse(&text[start + OPEN.len()..end - CLOSE.len()]) {
+            Some(placeholder) => {
+                found.push((start..end, placeholder));
+                position = end;
+            }
+            None => position = start + 1,
+        }
+    }
+
+    found
+}
+
+fn parse(expr: &str) -> Option<Placeholder<'_>> {
+    let (id, field) = expr.trim().split_once('.')?;
+
+    let field = match field {
+        "status" => Field::Status,
+        "body" => Field::Body(""),
+        _ if field.starts_with("body/") => Field::Body(&field["body".len()..]),
+        _ if field.starts_with("header.") => Field::Header(&field["header.".len()..]),
+        _ => return None,
+    };
+
+    let valid_id = !id.is_empty() && id.chars().all(is_name_char);
+
+    match valid_id {
+        true => Some(Placeholder { id, field }),
+        false => None,
+    }
+}
+
+fn resolve(placeholder: &Placeholder, responses: &HashMap<&str, StepResponse>) -> Result<String> {
+    let id = placeholder.id;
+    let response = responses.get(id)
+        .ok_or_else(|| anyhow!("placeholder refers to {}, which is not a dependency", id))?;
+
+    match placeholder.field {
+        Field::Status => Ok(response.status.to_string()),
+        Field::Header(name) => response.headers.get(name)
+            .ok_or_else(|| anyhow!("response of {} has no {} header", id, name))?
+            .to_str()
+            .map(str::to_string)
+            .with_context(|| format!("{} header of {} is not valid text", name, id)),
+        Field::Body("") => Ok(response.body.to_string()),
+        Field::Body(pointer) => {
+            let json: Value = serde_json::from_str(response.body)
+                .with_context(|| format!("response body of {} is not valid JSON", id))?;
+
+            match json.pointer(pointer) {
+                Some(Value::String(value)) => Ok(value.clone()),
+                Some(value) => Ok(value.to_string()),
+                None => bail!("response body of {} has nothing at {}", id, pointer),
+            }
+        }
+    }
+}
+
+// the parameter names of all placeholders in the text
+pub fn parameters(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        let (_, end, name) = next_parameter(rest)?;
+        rest = &rest[end..];
+        Some(name)
+    })
+}
+
+pub fn visit_strings(value: &Value, visit: &mut impl FnMut(&str)) {
+    match value {
+        Value::String(text) => visit(text),
+        Value::Array(values) => values.iter().for_each(|value| visit_strings(value, visit)),
+        Value::Object(map) => map.values().for_each(|value| visit_strings(value, visit)),
+        _ => (),
+    }
+}
+
+// values are substituted after parsing, so they cannot break out of the string they end up in; the
+// strings at the `escaped` JSON Pointers are text of their own, like uris or bodies, and escaped for it
+pub fn substitute_parameters(value: &mut Value, params: &Map<String, Value>, escaped: &[(String, Escape)])
+                             -> Result<(), InvalidUriParameter>
+{
+    for (pointer, escape) in escaped {
+        if let Some(Value::String(text)) = value.pointer_mut(pointer) {
+            *text = substitute_parameters_in(text, params, *escape)?;
+        }
+    }
+    substitute_strings(value, params);
+
+    Ok(())
+}
+
+fn substitute_strings(value: &mut Value, params: &Map<String, Value>) {
+    match value {
+        Value::String(text) => if let Ok(substituted) = substitute_parameters_in(text, params, Escape::Verbatim) {
+            *text = substituted; // only uris can fail
+        },
+        Value::Array(values) => values.iter_mut().for_each(|value| substitute_strings(value, params)),
+        Value::Object(map) => map.values_mut().for_each(|value| substitute_strings(value, params)),
+        _ => (),
+    }
+}
+
+// string parameters are inserted as they are, other values as JSON; in a JSON body, strings are escaped
+// within string literals and quoted outside of them, so that every parameter stays a single value
+fn substitute_parameters_in(text: &str, params: &Map<String, Value>, escape: Escape)
+                            -> Result<String, InvalidUriParameter>
+{
+    let mut substituted = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some((start, end, name)) = next_parameter(rest) {
+        substituted.push_str(&rest[..start]);
+        let value = match params.get(name) {
+            Some(value) => value,
+            None => {
+                substituted.push_str(&rest[start..end]);
+                rest = &rest[end..];
+                continue;
+            }
+        };
+        let text_value = match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        };
+        match escape {
+            Escape::Uri => substituted.push_str(&encode_uri_value(&text_value)
+                .ok_or_else(|| InvalidUriParameter(name.to_string()))?),
+            Escape::Json if in_json_string(&substituted) => {
+                let quoted = Value::String(text_value).to_string();
+                substituted.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Escape::Json => substituted.push_str(&value.to_string()),
+            Escape::Verbatim | Escape::Header => substituted.push_str(&text_value),
+        }
+        rest = &rest[end..];
+    }
+    substituted.push_str(rest);
+
+    Ok(substituted)
+}
+
+// the start and end of the next parameter placeholder, and its name
+fn next_parameter(text: &str) -> Option<(usize, usize, &str)> {
+    let mut position = 0;
+
+    while let Some(start) = text[position..].find(OPEN).map(|start| position + start) {
+        let end = start + OPEN.len() + text[start + OPEN.len()..].find(CLOSE)? + CLOSE.len();
+        let name = text[start + OPEN.len()..end - CLOSE.len()].trim();
+
+        if !name.is_empty() && name.chars().all(is_name_char) {
+            return Some((start, end, name));
+        }
+        position = start + 1;
+    }
+
+    None
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use hyper::HeaderMap;
+
+    use serde_json::json;
+
+    use crate::placeholder::{body_escape, parameters, references, substitute, substitute_parameters, Escape,
+                             StepResponse};
+
+    #[test]
+    fn substitutes_response_values() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Session", "s-42".parse().unwrap());
+        let responses = vec![
+            ("login", StepResponse { status: 201, headers: &headers, body: r#"{"token": "t-1", "ttl": 60}"# }),
+        ].into_iter().collect();
+
+        let text = "{{ login.body/token }}:{{login.body/ttl}}:{{login.status}}:{{login.header.x-session}}";
+        assert_eq!(substitute(text, &responses, Escape::Verbatim).unwrap(), "t-1:60:201:s-42");
+        assert_eq!(substitute("{{login.body}}", &responses, Escape::Verbatim).unwrap(),
+                   r#"{"token": "t-1", "ttl": 60}"#);
+        assert_eq!(substitute("{{x}} {{login}} {{{login.status}}}", &responses, Escape::Verbatim).unwrap(),
+                   "{{x}} {{login}} {201}");
+
+        assert!(substitute("{{login.body/missing}}", &responses, Escape::Verbatim).is_err());
+        assert!(substitute("{{logout.status}}", &responses, Escape::Verbatim).is_err());
+    }
+
+    #[test]
+    fn escapes_substituted_values() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Next", "/v2?page=2".parse().unwrap());
+        let body = r#"{"name": "say \"hi\"", "path": "../admin?x=#y", "dot": "..", "text": "a\r\nb", "n": 1}"#;
+        let responses = vec![
+            ("user", StepResponse { status: 200, headers: &headers, body }),
+        ].into_iter().collect();
+
+        // quotes are escaped within JSON strings only
+        let json = r#"{"greeting": "{{user.body/name}}", "n": {{user.body/n}}, "user": {{user.body}}}"#;
+        let substituted = substitute(json, &responses, Escape::Json).unwrap();
+        assert_eq!(substituted, format!(r#"{{"greeting": "say \"hi\"", "n": 1, "user": {}}}"#, body));
+        assert!(serde_json::from_str::<serde_json::Value>(&substituted).is_ok());
+        assert_eq!(substitute(r#"{"a": "\"{{user.status}}"}"#, &responses, Escape::Json).unwrap(),
+                   r#"{"a": "\"200"}"#);
+
+        // slashes, queries and fragments stay within the segment
+        assert_eq!(substitute("https://api.example.com/users/{{user.body/path}}/orders", &responses, Escape::Uri)
+                       .unwrap(),
+                   "https://api.example.com/users/..%2Fadmin%3Fx%3D%23y/orders");
+        assert_eq!(substitute("/x?next={{user.header.x-next}}", &responses, Escape::Uri).unwrap(),
+                   "/x?next=%2Fv2%3Fpage%3D2");
+        assert!(substitute("/users/{{user.body/dot}}/orders", &responses, Escape::Uri).is_err());
+
+        assert_eq!(substitute("{{user.header.x-next}}", &responses, Escape::Header).unwrap(), "/v2?page=2");
+        assert!(substitute("{{user.body/text}}", &responses, Escape::Header).is_err());
+    }
+
+    #[test]
+    fn finds_references() {
+        assert_eq!(references("Bearer {{login.body/token}} {{user.header.etag}} {{none}}"), vec!["login", "user"]);
+    }
+
+    #[test]
+    fn substitutes_parameters() {
+        let mut request = json!({ "uri": "/users/{{ user_id }}?q={{q}}", "headers": { "X-User": "{{user_id}}" },
+                                  "json": { "limit": "{{limit}}", "token": "{{login.body/token}}" } });
+        let params = json!({ "user_id": "7/../admin", "q": "a b", "limit": 10 });
+        assert_eq!(parameters(request["uri"].as_str().unwrap()).collect::<Vec<_>>(), vec!["user_id", "q"]);
+
+        substitute_parameters(&mut request, params.as_object().unwrap(), &[("/uri".to_string(), Escape::Uri)]).unwrap();
+        assert_eq!(request, json!({ "uri": "/users/7%2F..%2Fadmin?q=a%20b", "headers": { "X-User": "7/../admin" },
+                                    "json": { "limit": "10", "token": "{{login.body/token}}" } }));
+
+        let dot = json!({ "user_id": "..", "q": "", "limit": 1 });
+        let mut request = json!({ "uri": "/users/{{user_id}}" });
+        assert!(substitute_parameters(&mut request, dot.as_object().unwrap(), &[("/uri".to_string(), Escape::Uri)])
+            .is_err());
+
+        // neither can break out of the JSON body
+        let mut request = json!({ "body": r#"{"name": "{{q}}", "user": {{user_id}}, "limit": {{limit}}}"# });
+        let params = json!({ "user_id": "1, \"admin\": true", "q": "x\", \"admin\": \"true", "limit": 10 });
+        substitute_parameters(&mut request, params.as_object().unwrap(), &[("/body".to_string(), Escape::Json)])
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(request["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body, json!({ "name": "x\", \"admin\": \"true", "user": "1, \"admin\": true", "limit": 10 }));
+    }
+
+    #[test]
+    fn detects_json_bodies() {
+        let json = vec![("content-type".to_string(), "application/problem+json".to_string())].into_iter().collect();
+        assert_eq!(body_escape(&json, Some("x")), Escape::Json);
+        assert_eq!(body_escape(&HashMap::new(), Some(" [1]")), Escape::Json);
+        assert_eq!(body_escape(&HashMap::new(), Some("a=1")), Escape::Verbatim);
+        assert_eq!(body_escape(&HashMap::new(), None), Escape::Verbatim);
+    }
+}
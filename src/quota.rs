@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use thiserror::Error;
+use tokio::time::{Duration, Instant};
+
+use crate::auth::ClientIdentity;
+use crate::multiplexer::BatchLimits;
+use crate::rate_limit::{self, TokenBucket};
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub defaults: ClientQuota,
+    pub clients: HashMap<String, ClientQuota>, // by client id
+}
+
+// unset fields fall back to the defaults, and from there to the built-in limits
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientQuota {
+    pub batches_per_sec: Option<f64>,
+    pub batch_burst: Option<f64>,
+    pub requests_per_sec: Option<f64>,
+    pub request_burst: Option<f64>,
+    pub max_batch_size: Option<usize>,
+    pub max_timeout_msec: Option<u64>,
+    pub allowed_hosts: Option<Vec<String>>, // globs
+}
+
+impl QuotaConfig {
+    pub fn validate(&self) -> Result<()> {
+        let quotas = std::iter::once(("defaults", &self.defaults))
+            .chain(self.clients.iter().map(|(client_id, quota)| (client_id.as_str(), quota)));
+
+        for (name, quota) in quotas {
+            let rates = [(quota.batches_per_sec, quota.batch_burst), (quota.requests_per_sec, quota.request_burst)];
+            for (rate, burst) in rates.iter().filter_map(|(rate, burst)| rate.map(|rate| (rate, *burst))) {
+                rate_limit::validate(rate, burst).with_context(|| format!("invalid quota of {}", name))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum QuotaExceeded {
+    #[error("batch rate limit exceeded, retry in {}ms", .0.as_millis())]
+    BatchRate(Duration),
+    #[error("request rate limit exceeded, retry in {}ms", .0.as_millis())]
+    RequestRate(Duration),
+    #[error("the batch has more requests than the request burst of this client allows")]
+    RequestBurst,
+}
+
+impl QuotaExceeded {
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            QuotaExceeded::BatchRate(wait) | QuotaExceeded::RequestRate(wait) => Some(*wait),
+            QuotaExceeded::RequestBurst => None,
+        }
+    }
+}
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct ClientBuckets {
+    batches: Option<TokenBucket>,
+    requests: Option<TokenBucket>,
+}
+
+impl ClientBuckets {
+    fn are_full(&mut self) -> bool {
+        self.batches.iter_mut().chain(self.requests.iter_mut()).all(TokenBucket::is_full)
+    }
+}
+
+// clients whose buckets have filled up again are dropped now and then, they would start out full anyway
+struct BucketTable {
+    by_client: HashMap<String, ClientBuckets>,
+    swept: Instant,
+}
+
+pub struct QuotaManager {
+    config: QuotaConfig,
+    buckets: Mutex<BucketTable>,
+}
+
+impl QuotaManager {
+    pub fn new(config: QuotaConfig) -> Self {
+        QuotaManager {
+            config,
+            buckets: Mutex::new(BucketTable {
+                by_client: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn limits_for(&self, identity: &ClientIdentity) -> BatchLimits {
+        let defaults = BatchLimits::default();
+
+        BatchLimits {
+            max_request_duration: self.setting(identity, |q| q.max_timeout_msec)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.max_request_duration),
+            max_batch_size: self.setting(identity, |q| q.max_batch_size)
+                .unwrap_or(defaults.max_batch_size),
+            allowed_hosts: self.setting(identity, |q| q.allowed_hosts.clone())
+                .unwrap_or(defaults.allowed_hosts),
+        }
+    }
+
+    // takes one batch token and one request token per request, either both or none
+    pub fn admit(&self, identity: &ClientIdentity, request_count: usize) -> Result<(), QuotaExceeded> {
+        let mut buckets = self.buckets.lock().expect("quota buckets poisoned");
+        buckets.sweep();
+        let client_buckets = buckets.by_client.entry(identity.client_id.clone())
+            .or_insert_with(|| self.new_buckets(identity));
+        let request_count = request_count as f64;
+
+        // both are checked before either is taken from
+        if let Some(bucket) = &mut client_buckets.batches {
+            let wait = bucket.wait_time(1.0);
+            if !wait.is_zero() {
+                return Err(QuotaExceeded::BatchRate(wait));
+            }
+        }
+        if let Some(bucket) = &mut client_buckets.requests {
+            if bucket.exceeds_capacity(request_count) {
+                return Err(QuotaExceeded::RequestBurst);
+            }
+            let wait = bucket.wait_time(request_count);
+            if !wait.is_zero() {
+                return Err(QuotaExceeded::RequestRate(wait));
+            }
+        }
+
+        // with the lock held, the tokens are still there
+        if let Some(bucket) = &mut client_buckets.batches {
+            bucket.acquire_ahead(1.0);
+        }
+        if let Some(bucket) = &mut client_buckets.requests {
+            bucket.acquire_ahead(request_count);
+        }
+
+        Ok(())
+    }
+
+    fn new_buckets(&self, identity: &ClientIdentity) -> ClientBuckets {
+        let bucket = |rate: Option<f64>, burst: Option<f64>| {
+            rate.map(|rate| TokenBucket::new(rate, burst.unwrap_or(rate)))
+        };
+
+        ClientBuckets {
+            batches: bucket(self.setting(identity, |q| q.batches_per_sec),
+                            self.setting(identity, |q| q.batch_burst)),
+            requests: bucket(self.setting(identity, |q| q.requests_per_sec),
+                             self.setting(identity, |q| q.request_burst)),
+        }
+    }
+
+    fn setting<T>(&self, identity: &ClientIdentity, get: impl Fn(&ClientQuota) -> Option<T>) -> Option<T> {
+        self.config.clients.get(&identity.client_id)
+            .and_then(&get)
+            .or_else(|| get(&self.config.defaults))
+    }
+}
+
+impl BucketTable {
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.swept) < SWEEP_INTERVAL {
+            return;
+        }
+
+        self.by_client.retain(|_, buckets| !buckets.are_full());
+        self.swept = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{advance, pause, Duration};
+
+    use crate::auth::{AuthMethod, ClientIdentity};
+    use crate::quota::{ClientQuota, QuotaConfig, QuotaExceeded, QuotaManager};
+
+    fn identity(client_id: &str) -> ClientIdentity {
+        ClientIdentity {
+            client_id: client_id.to_string(),
+            method: AuthMethod::ApiKey,
+        }
+    }
+
+    fn quotas() -> QuotaManager {
+        QuotaManager::new(QuotaConfig {
+            defaults: ClientQuota {
+                batches_per_sec: Some(1.0),
+                requests_per_sec: Some(10.0),
+                request_burst: Some(20.0),
+                ..Default::default()
+            },
+            clients: vec![
+                ("search".to_string(), ClientQuota {
+                    batches_per_sec: Some(100.0),
+                    max_batch_size: Some(5),
+                    allowed_hosts: Some(vec!["*.example.com".to_string()]),
+                    ..Default::default()
+                }),
+            ].into_iter().collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn limits_batch_and_request_rate() {
+        pause();
+        let quotas = quotas();
+        let billing = identity("billing");
+
+        assert!(quotas.admit(&billing, 5).is_ok());
+        assert!(matches!(quotas.admit(&billing, 5), Err(QuotaExceeded::BatchRate(_))));
+
+        advance(Duration::from_secs(1)).await;
+        assert!(quotas.admit(&billing, 15).is_ok());
+
+        advance(Duration::from_secs(1)).await;
+        let exceeded = quotas.admit(&billing, 20).unwrap_err();
+        assert!(matches!(exceeded, QuotaExceeded::RequestRate(_)));
+        assert_eq!(exceeded.retry_after(), Some(Duration::from_millis(500)));
+
+        assert!(matches!(quotas.admit(&billing, 25), Err(QuotaExceeded::RequestBurst)));
+    }
+
+    #[tokio::test]
+    async fn takes_no_tokens_from_rejected_batches() {
+        pause();
+        let quotas = quotas();
+        let billing = identity("billing");
+
+        assert!(quotas.admit(&billing, 1).is_ok());
+        assert!(matches!(quotas.admit(&billing, 19), Err(QuotaExceeded::BatchRate(_))));
+        assert!(matches!(quotas.admit(&billing, 19), Err(QuotaExceeded::BatchRate(_))));
+
+        // one second refills 10 request tokens, on top of the 19 which were not taken
+        advance(Duration::from_secs(1)).await;
+        assert!(quotas.admit(&billing, 20).is_ok());
+    }
+
+    #[tokio::test]
+    async fn forgets_idle_clients() {
+        pause();
+        let quotas = quotas();
+
+        assert!(quotas.admit(&identity("billing"), 20).is_ok());
+        assert!(quotas.admit(&identity("search"), 1).is_ok());
+
+        advance(Duration::from_secs(30)).await;
+        assert!(quotas.admit(&identity("reports"), 1).is_ok());
+        assert_eq!(quotas.buckets.lock().unwrap().by_client.len(), 3);
+
+        // all buckets have filled up by the next sweep
+        advance(Duration::from_secs(31)).await;
+        assert!(quotas.admit(&identity("search"), 1).is_ok());
+        let clients = quotas.buckets.lock().unwrap().by_client.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(clients, vec!["search".to_string()]);
+    }
+
+    #[test]
+    fn overrides_limits_per_client() {
+        let quotas = quotas();
+
+        let limits = quotas.limits_for(&identity("search"));
+        assert_eq!(limits.max_batch_size, 5);
+        assert_eq!(limits.allowed_hosts, vec!["*.example.com".to_string()]);
+
+        let limits = quotas.limits_for(&identity("billing"));
+        assert_eq!(limits.max_batch_size, 50);
+        assert!(limits.allowed_hosts.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Result};
+use tokio::time::{Duration, Instant};
+
+const MIN_RATE_PER_SEC: f64 = 0.001; // about one token in 17 minutes
+const MAX_RATE_PER_SEC: f64 = 1_000_000.0;
+const MAX_BURST: f64 = 1_000_000_000.0;
+const MAX_WAIT: Duration = Duration::from_secs(24 * 3600); // for callers, a wait this long is forever
+
+// for rates and bursts from the config
+pub fn validate(rate_per_sec: f64, burst: Option<f64>) -> Result<()> {
+    if !(MIN_RATE_PER_SEC..=MAX_RATE_PER_SEC).contains(&rate_per_sec) {
+        bail!("rate {} is not between {} and {} per second", rate_per_sec, MIN_RATE_PER_SEC, MAX_RATE_PER_SEC);
+    }
+    if let Some(burst) = burst.filter(|burst| !(*burst > 0.0 && *burst <= MAX_BURST)) {
+        bail!("burst {} is not above 0 and at most {}", burst, MAX_BURST);
+    }
+
+    Ok(())
+}
+
+// classic token bucket, starting out full; fractional tokens make low rates (< 1/sec) possible
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_per_sec: f64, burst: f64) -> Self {
+        TokenBucket {
+            capacity: burst.max(1.0),
+            refill_per_sec: rate_per_sec,
+            tokens: burst.max(1.0),
+            updated: Instant::now(),
+        }
+    }
+
+    // how long until `n` tokens are available, zero if they are available right away
+    pub fn wait_time(&mut self, n: f64) -> Duration {
+        self.refill();
+
+        let missing = n - self.tokens;
+        if missing <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::try_from_secs_f64(missing / self.refill_per_sec).map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT))
+        }
+    }
+
+    // takes `n` tokens if available, otherwise tells how long to wait for them
+    pub fn try_acquire(&mut self, n: f64) -> Result<(), Duration> {
+        match self.wait_time(n) {
+            wait if wait.is_zero() => {
+                self.tokens -= n;
+                Ok(())
+            }
+            wait => Err(wait),
+        }
+    }
+
+    // takes `n` tokens right away, possibly going into debt, and tells how long to wait before using
+    // them; callers waiting this way are served in order
+    pub fn acquire_ahead(&mut self, n: f64) -> Duration {
+        let wait = self.wait_time(n);
+        self.tokens -= n;
+        wait
+    }
+
+    pub fn exceeds_capacity(&self, n: f64) -> bool {
+        n > self.capacity
+    }
+
+    // a full bucket is no different from a new one
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{advance, pause, Duration};
+
+    use crate::rate_limit::{validate, TokenBucket};
+
+    #[tokio::test]
+    async fn limits_to_rate_after_burst() {
+        pause();
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+
+        assert!(bucket.try_acquire(3.0).is_ok());
+        assert_eq!(bucket.try_acquire(1.0), Err(Duration::from_millis(500)));
+
+        advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_acquire(1.0).is_ok());
+        assert!(bucket.try_acquire(1.0).is_err());
+
+        advance(Duration::from_secs(60)).await;
+        assert!(bucket.try_acquire(3.0).is_ok(), "refill must be capped at burst size");
+        assert!(bucket.try_acquire(1.0).is_err());
+    }
+
+    #[tokio::test]
+    async fn queues_acquisitions_ahead() {
+        pause();
+        let mut bucket = TokenBucket::new(10.0, 1.0);
+
+        assert_eq!(bucket.acquire_ahead(1.0), Duration::from_millis(0));
+        assert_eq!(bucket.acquire_ahead(1.0), Duration::from_millis(100));
+        assert_eq!(bucket.acquire_ahead(1.0), Duration::from_millis(200));
+
+        advance(Duration::from_millis(200)).await;
+        assert!(bucket.try_acquire(1.0).is_err());
+    }
+
+    #[tokio::test]
+    async fn caps_waits() {
+        pause();
+        let mut bucket = TokenBucket::new(1e-300, 1.0);
+        assert!(bucket.try_acquire(1.0).is_ok());
+        assert_eq!(bucket.try_acquire(1.0), Err(Duration::from_secs(24 * 3600)));
+
+        let mut bucket = TokenBucket::new(0.0, 1.0);
+        assert!(bucket.try_acquire(1.0).is_ok());
+        assert_eq!(bucket.try_acquire(1.0), Err(Duration::from_secs(24 * 3600)));
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        assert!(validate(0.5, Some(2.0)).is_ok());
+        assert!(validate(10.0, None).is_ok());
+        for (rate, burst) in &[(0.0, None), (1e-300, None), (-1.0, None), (f64::NAN, None), (f64::INFINITY, None),
+                               (1.0, Some(0.0)), (1.0, Some(f64::NAN)), (1.0, Some(1e300))] {
+            assert!(validate(*rate, *burst).is_err(), "{} {:?} accepted", rate, burst);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::api::{OctoplexRequest, RecipeInfo};
+use crate::placeholder::{body_escape, parameters, substitute_parameters, visit_strings, Escape, InvalidUriParameter};
+
+#[derive(Error, Debug)]
+pub enum RecipeError {
+    #[error("there is no recipe {0:?}")]
+    NotFound(String),
+    #[error("recipe name {0:?} may only contain letters, digits, - and _")]
+    InvalidName(String),
+    #[error("recipe is not a valid batch: {0}")]
+    InvalidTemplate(serde_json::Error),
+    #[error("parameter {0:?} is missing")]
+    MissingParameter(String),
+    #[error("recipe has no parameter {0:?}")]
+    UnknownParameter(String),
+    #[error("parameter {0:?} cannot be substituted into a uri")]
+    InvalidUriParameter(String),
+}
+
+struct Recipe {
+    template: Value, // an OctoplexRequest with placeholders in its string values
+    params: BTreeSet<String>,
+}
+
+// batches stored under a name, which clients run with a few parameters
+#[derive(Default)]
+pub struct RecipeBook {
+    recipes: Mutex<HashMap<String, Recipe>>, // by name
+}
+
+impl RecipeBook {
+    pub fn new(recipes: HashMap<String, Value>) -> Result<Self, RecipeError> {
+        let book = RecipeBook::default();
+
+        for (name, template) in recipes {
+            book.upsert(&name, template)?;
+        }
+
+        Ok(book)
+    }
+
+    pub fn upsert(&self, name: &str, template: Value) -> Result<RecipeInfo, RecipeError> {
+        if name.is_empty() || !name.chars().all(is_name_char) {
+            return Err(RecipeError::InvalidName(name.to_string()));
+        }
+        // placeholders are only allowed where the batch takes a string anyway
+        serde_json::from_value::<OctoplexRequest>(template.clone()).map_err(RecipeError::InvalidTemplate)?;
+
+        let mut params = BTreeSet::new();
+        let mut template = template;
+        let for_each_request = take_for_each_request(&mut template);
+        visit_strings(&template, &mut |text| params.extend(parameters(text).map(str::to_string)));
+        restore_for_each_request(&mut template, for_each_request);
+
+        let recipe = Recipe { template, params };
+        let info = info_of(name, &recipe);
+        self.recipes.lock().expect("recipes poisoned").insert(name.to_string(), recipe);
+
+        Ok(info)
+    }
+
+    pub fn remove(&self, name: &str) -> Option<RecipeInfo> {
+        self.recipes.lock().expect("recipes poisoned")
+            .remove(name)
+            .map(|recipe| info_of(name, &recipe))
+    }
+
+    pub fn get(&self, name: &str) -> Option<RecipeInfo> {
+        self.recipes.lock().expect("recipes poisoned")
+            .get(name)
+            .map(|recipe| info_of(name, recipe))
+    }
+
+    pub fn list(&self) -> Vec<RecipeInfo> {
+        let recipes = self.recipes.lock().expect("recipes poisoned");
+        let mut infos = recipes.iter()
+            .map(|(name, recipe)| info_of(name, recipe))
+            .collect::<Vec<_>>();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+
+        infos
+    }
+
+    // every parameter of the recipe has to be given, and nothing else
+    pub fn expand(&self, name: &str, params: &Map<String, Value>) -> Result<OctoplexRequest, RecipeError> {
+        let mut template = {
+            let recipes = self.recipes.lock().expect("recipes poisoned");
+            let recipe = recipes.get(name).ok_or_else(|| RecipeError::NotFound(name.to_string()))?;
+
+            if let Some(unknown) = params.keys().find(|param| !recipe.params.contains(*param)) {
+                return Err(RecipeError::UnknownParameter(unknown.clone()));
+            }
+            if let Some(missing) = recipe.params.iter().find(|param| !params.contains_key(*param)) {
+                return Err(RecipeError::MissingParameter(missing.clone()));
+            }
+
+            recipe.template.clone()
+        };
+
+        let escaped = escaped_pointers(&template)?;
+        let for_each_request = take_for_each_request(&mut template);
+        substitute_parameters(&mut template, params, &escaped)
+            .map_err(|InvalidUriParameter(name)| RecipeError::InvalidUriParameter(name))?;
+        restore_for_each_request(&mut template, for_each_request);
+
+        serde_json::from_value(template).map_err(RecipeError::InvalidTemplate)
+    }
+}
+
+fn info_of(name: &str, recipe: &Recipe) -> RecipeInfo {
+    RecipeInfo {
+        name: name.to_string(),
+        params: recipe.params.iter().cloned().collect(),
+        template: recipe.template.clone(),
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+// the uris of the batch, where parameters are percent-encoded, and its bodies, which may be JSON
+fn escaped_pointers(batch: &Value) -> Result<Vec<(String, Escape)>, RecipeError> {
+    let batch: OctoplexRequest = serde_json::from_value(batch.clone()).map_err(RecipeError::InvalidTemplate)?;
+    let mut pointers = vec![
+        ("/template/uri_base".to_string(), Escape::Uri),
+        ("/callback/uri".to_string(), Escape::Uri),
+    ];
+
+    if let Some(template) = &batch.template {
+        pointers.push(("/template/body".to_string(), body_escape(&template.headers, template.body.as_deref())));
+    }
+    for (index, request) in batch.requests.iter().enumerate() {
+        pointers.push((format!("/requests/{}/uri", index), Escape::Uri));
+        pointers.push((format!("/requests/{}/body", index), body_escape(&request.headers, request.body.as_deref())));
+    }
+
+    Ok(pointers)
+}
+
+// the request of for_each has placeholders for its own params, which are left to for_each
+fn take_for_each_request(batch: &mut Value) -> Option<Value> {
+    batch.pointer_mut("/for_each/request").map(Value::take)
+}
+
+fn restore_for_each_request(batch: &mut Value, request: Option<Value>) {
+    if let (Some(slot), Some(request)) = (batch.pointer_mut("/for_each/request"), request) {
+        *slot = request;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Context;
+    use hyper::{Body, Response};
+    use serde_json::{json, Value};
+
+    use crate::api::SingleOutcome;
+    use crate::http_client::tests::MockHttpClient;
+    use crate::multiplexer::{BatchLimits, GenericMultiplexer};
+    use crate::recipes::{RecipeBook, RecipeError};
+
+    fn book() -> RecipeBook {
+        let template = json!({
+            "timeout_msec": 1000,
+            "requests": [
+                { "id": "user", "uri": "https://api.example.com/users/{{ user_id }}" },
+                { "depends_on": ["user"], "uri": "https://api.example.com/orders?user={{user_id}}&limit={{limit}}",
+                  "headers": { "ETag": "{{user.header.ETag}}" } }
+            ]
+        });
+
+        RecipeBook::new(vec![("orders".to_string(), template)].into_iter().collect()).expect("invalid recipe")
+    }
+
+    fn params(params: Value) -> serde_json::Map<String, Value> {
+        params.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn expands_recipes() {
+        let book = book();
+        assert_eq!(book.get("orders").unwrap().params, vec!["limit", "user_id"]);
+
+        let batch = book.expand("orders", &params(json!({ "user_id": "42", "limit": 10 }))).expect("not expanded");
+        assert_eq!(batch.requests[0].uri, "https://api.example.com/users/42");
+        assert_eq!(batch.requests[1].uri, "https://api.example.com/orders?user=42&limit=10");
+        assert_eq!(batch.requests[1].headers["ETag"], "{{user.header.ETag}}");
+    }
+
+    #[test]
+    fn encodes_parameters_in_uris() {
+        let book = book();
+
+        let batch = book.expand("orders", &params(json!({ "user_id": "../admin?x=", "limit": "1&all=1" })))
+            .expect("not expanded");
+        assert_eq!(batch.requests[0].uri, "https://api.example.com/users/..%2Fadmin%3Fx%3D");
+        assert_eq!(batch.requests[1].uri, "https://api.example.com/orders?user=..%2Fadmin%3Fx%3D&limit=1%26all%3D1");
+
+        assert!(matches!(book.expand("orders", &params(json!({ "user_id": "..", "limit": 1 }))),
+                         Err(RecipeError::InvalidUriParameter(_))));
+    }
+
+    #[tokio::test]
+    async fn runs_recipes_with_for_each() {
+        let template = json!({
+            "timeout_msec": 1000,
+            "template": { "uri_base": "https://api.example.com/{{tenant}}/" },
+            "requests": [
+                { "uri": "notes", "body": "{\"note\": \"{{note}}\", \"tenant\": {{tenant}}}" }
+            ],
+            "for_each": {
+                "request": { "uri": "users/{{user_id}}" },
+                "params": [{ "user_id": "{{first}}" }, { "user_id": 2 }]
+            }
+        });
+        let book = RecipeBook::new(vec![("users".to_string(), template)].into_iter().collect()).unwrap();
+        assert_eq!(book.get("users").unwrap().params, vec!["first", "note", "tenant"]);
+
+        let params = params(json!({ "tenant": "acme corp", "first": "1", "note": "\", \"admin\": true, \"x\": \"" }));
+        let batch = book.expand("users", &params).expect("not expanded");
+        let body: Value = serde_json::from_str(batch.requests[0].body.as_deref().unwrap()).expect("body broken");
+        assert_eq!(body, json!({ "note": "\", \"admin\": true, \"x\": \"", "tenant": "acme corp" }));
+
+        let mut client = MockHttpClient::new();
+        client.expect_request().times(3).returning(|req| {
+            Response::builder().status(200).body(Body::from(req.uri().to_string())).context("cannot build response")
+        });
+        let result = GenericMultiplexer::new(client)
+            .handle(batch, &BatchLimits::default()).await
+            .expect("batch failed");
+
+        let uris = result.responses.iter().map(|outcome| match outcome {
+            SingleOutcome::Success(resp) => resp.body(),
+            SingleOutcome::Failure(failure) => panic!("request failed: {:?}", failure),
+        }).collect::<Vec<_>>();
+        assert_eq!(uris, vec!["https://api.example.com/acme%20corp/notes",
+                              "https://api.example.com/acme%20corp/users/1",
+                              "https://api.example.com/acme%20corp/users/2"]);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters_and_recipes() {
+        let book = book();
+
+        assert!(matches!(book.expand("orders", &params(json!({ "user_id": "42" }))),
+                         Err(RecipeError::MissingParameter(_))));
+        assert!(matches!(book.expand("orders", &params(json!({ "user_id": "42", "limit": 1, "x": 1 }))),
+                         Err(RecipeError::UnknownParameter(_))));
+        assert!(matches!(book.expand("invoices", &params(json!({}))), Err(RecipeError::NotFound(_))));
+
+        assert!(matches!(book.upsert("broken", json!({ "requests": [] })), Err(RecipeError::InvalidTemplate(_))));
+        assert!(matches!(book.upsert("no/slash", json!({})), Err(RecipeError::InvalidName(_))));
+        assert!(RecipeBook::new(HashMap::new()).unwrap().list().is_empty());
+    }
+}
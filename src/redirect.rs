@@ -0,0 +1,125 @@
+use anyhow::{bail, Context, Result};
+use http::HeaderMap;
+use http::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, LOCATION, PROXY_AUTHORIZATION};
+use url::Url;
+
+use crate::api::{HttpMethod, RedirectPolicy, SingleHttpRequest};
+
+const MAX_HOPS: usize = 20; // XXX config
+
+pub fn validate(policy: &RedirectPolicy) -> Result<()> {
+    if policy.max_hops == 0 || policy.max_hops > MAX_HOPS {
+        bail!("max_hops has to be between 1 and {}", MAX_HOPS);
+    }
+
+    Ok(())
+}
+
+// the request to the Location of a redirect response, if it is one that is to be followed
+pub fn next_request(policy: &RedirectPolicy, request: &SingleHttpRequest, status: u16, headers: &HeaderMap)
+                    -> Result<Option<SingleHttpRequest>>
+{
+    if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+        return Ok(None);
+    }
+    let location = match headers.get(LOCATION) {
+        Some(location) => location.to_str().context("invalid Location")?,
+        None => return Ok(None),
+    };
+
+    let current = Url::parse(&request.uri).with_context(|| format!("invalid uri {}", request.uri))?;
+    let next = current.join(location).with_context(|| format!("invalid Location {}", location))?;
+    let cross_origin = origin(&current) != origin(&next);
+    if cross_origin && policy.same_origin {
+        return Ok(None);
+    }
+
+    let mut next_req = SingleHttpRequest { uri: next.to_string(), ..request.clone() };
+
+    // 303 always turns into a GET, 301 and 302 only for POST, the way browsers do, 307 and 308 never
+    let method = request.method.unwrap_or_default();
+    if status == 303 || matches!((status, method), (301 | 302, HttpMethod::POST)) {
+        next_req.method = Some(HttpMethod::GET);
+        next_req.body = None;
+        next_req.multipart = None;
+        strip_headers(&mut next_req, &[CONTENT_TYPE.as_str(), CONTENT_LENGTH.as_str()]);
+    }
+    // credentials are meant for the origin they were sent to
+    if cross_origin {
+        strip_headers(&mut next_req, &[AUTHORIZATION.as_str(), PROXY_AUTHORIZATION.as_str(), COOKIE.as_str()]);
+    }
+
+    Ok(Some(next_req))
+}
+
+// scheme, host and port, url's own origin is opaque for schemes like upstream://
+fn origin(url: &Url) -> (&str, Option<&str>, Option<u16>) {
+    (url.scheme(), url.host_str(), url.port_or_known_default())
+}
+
+fn strip_headers(request: &mut SingleHttpRequest, names: &[&str]) {
+    request.headers.retain(|name, _| !names.iter().any(|stripped| name.eq_ignore_ascii_case(stripped)));
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+
+    use crate::api::{HttpMethod, RedirectPolicy, SingleHttpRequest};
+    use crate::redirect::{next_request, validate};
+
+    fn redirect_to(location: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Location", HeaderValue::from_static(location));
+        headers
+    }
+
+    #[test]
+    fn rewrites_redirected_requests() {
+        let policy = RedirectPolicy { max_hops: 5, same_origin: false };
+        let request = SingleHttpRequest {
+            method: Some(HttpMethod::POST),
+            uri: "https://api.example.com/v1/orders".to_string(),
+            headers: [("authorization", "Bearer x"), ("Content-Type", "application/json"), ("X-Trace", "1")]
+                .iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: Some("{}".to_string()),
+            ..Default::default()
+        };
+
+        let see_other = next_request(&policy, &request, 303, &redirect_to("orders/7")).unwrap().unwrap();
+        assert_eq!(see_other.uri, "https://api.example.com/v1/orders/7");
+        assert!(matches!(see_other.method, Some(HttpMethod::GET)));
+        assert_eq!(see_other.body, None);
+        assert_eq!(see_other.headers.len(), 2);
+        assert!(see_other.headers.contains_key("authorization"));
+
+        let temporary = next_request(&policy, &request, 307, &redirect_to("https://eu.example.com/v1/orders"))
+            .unwrap().unwrap();
+        assert!(matches!(temporary.method, Some(HttpMethod::POST)));
+        assert_eq!(temporary.body.as_deref(), Some("{}"));
+        assert_eq!(temporary.headers.len(), 2);
+        assert!(!temporary.headers.contains_key("authorization"));
+
+        let put = SingleHttpRequest { method: Some(HttpMethod::PUT), ..request.clone() };
+        let moved = next_request(&policy, &put, 301, &redirect_to("/v2/orders")).unwrap().unwrap();
+        assert!(matches!(moved.method, Some(HttpMethod::PUT)));
+        assert_eq!(moved.body.as_deref(), Some("{}"));
+
+        assert!(next_request(&policy, &request, 200, &redirect_to("/v2/orders")).unwrap().is_none());
+        assert!(next_request(&policy, &request, 302, &HeaderMap::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_to_the_origin() {
+        let policy = RedirectPolicy { max_hops: 5, same_origin: true };
+        let request = SingleHttpRequest { uri: "https://api.example.com/a".to_string(), ..Default::default() };
+
+        assert!(next_request(&policy, &request, 302, &redirect_to("https://api.example.com:443/b")).unwrap().is_some());
+        assert!(next_request(&policy, &request, 302, &redirect_to("http://api.example.com/b")).unwrap().is_none());
+        assert!(next_request(&policy, &request, 302, &redirect_to("https://example.com/b")).unwrap().is_none());
+
+        assert!(validate(&policy).is_ok());
+        assert!(validate(&RedirectPolicy { max_hops: 0, same_origin: false }).is_err());
+        assert!(validate(&RedirectPolicy { max_hops: 100, same_origin: false }).is_err());
+    }
+}
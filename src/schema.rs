@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde_json::Value;
+
+// a subset of JSON Schema: type, enum, const, properties, required, additionalProperties, items,
+// minItems, maxItems, minLength, maxLength, minimum, maximum, pattern, allOf and anyOf
+const KEYWORDS: &[&str] = &[
+    "type", "enum", "const", "properties", "required", "additionalProperties", "items", "minItems", "maxItems",
+    "minLength", "maxLength", "minimum", "maximum", "pattern", "allOf", "anyOf",
+];
+// annotations, which do not affect validation
+const ANNOTATIONS: &[&str] = &["$schema", "$id", "$comment", "title", "description", "default", "examples"];
+
+// a schema with its patterns compiled
+pub struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    // any other keyword, like $ref or format, is rejected rather than ignored
+    pub fn compile(root: &Value) -> Result<Schema> {
+        let mut patterns = HashMap::new();
+        compile_patterns(root, &mut patterns)?;
+
+        Ok(Schema { root: root.clone(), patterns })
+    }
+
+    // every violation, with the JSON Pointer of where it is
+    pub fn violations(&self, value: &Value) -> Vec<String> {
+        self.violations_at(&self.root, value, "")
+    }
+
+    fn violations_at(&self, schema: &Value, value: &Value, at: &str) -> Vec<String> {
+        let mut violations = vec![];
+        self.check(schema, value, at, &mut violations);
+
+        violations
+    }
+
+    fn check(&self, schema: &Value, value: &Value, at: &str, violations: &mut Vec<String>) {
+        let location = if at.is_empty() { "/" } else { at };
+        let mut violation = |message: String| violations.push(format!("{}: {}", location, message));
+
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return violation("no value is allowed".to_string()),
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(types) = schema.get("type") {
+            let types = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect::<Vec<_>>(),
+            };
+            if !types.iter().any(|name| has_type(value, name)) {
+                violation(format!("expected {}, got {}", types.join(" or "), type_of(value)));
+            }
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            if !values.contains(value) {
+                violation(format!("{} is not one of {}", value, Value::Array(values.clone())));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                violation(format!("{} is not {}", value, expected));
+            }
+        }
+
+        let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+        let count_bound = |keyword: &str| schema.get(keyword).and_then(Value::as_u64).map(|bound| bound as usize);
+        match value {
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                if let Some(minimum) = bound("minimum").filter(|minimum| number < *minimum) {
+                    violation(format!("{} is less than {}", number, minimum));
+                }
+                if let Some(maximum) = bound("maximum").filter(|maximum| number > *maximum) {
+                    violation(format!("{} is more than {}", number, maximum));
+                }
+            }
+            Value::String(text) => {
+                let length = text.chars().count();
+                if let Some(min) = count_bound("minLength").filter(|min| length < *min) {
+                    violation(format!("length {} is less than {}", length, min));
+                }
+                if let Some(max) = count_bound("maxLength").filter(|max| length > *max) {
+                    violation(format!("length {} is more than {}", length, max));
+                }
+                let pattern = schema.get("pattern").and_then(Value::as_str)
+                    .and_then(|pattern| self.patterns.get(pattern));
+                if let Some(pattern) = pattern.filter(|pattern| !pattern.is_match(text)) {
+                    violation(format!("{:?} does not match {}", text, pattern));
+                }
+            }
+            Value::Array(values) => {
+                if let Some(min) = count_bound("minItems").filter(|min| values.len() < *min) {
+                    violation(format!("{} items are less than {}", values.len(), min));
+                }
+                if let Some(max) = count_bound("maxItems").filter(|max| values.len() > *max) {
+                    violation(format!("{} items are more than {}", values.len(), max));
+                }
+            }
+            _ => (),
+        }
+
+        if let (Some(required), Value::Object(map)) = (schema.get("required").and_then(Value::as_array), value) {
+            for name in required.iter().filter_map(Value::as_str).filter(|name| !map.contains_key(*name)) {
+                violation(format!("{} is missing", name));
+            }
+        }
+
+        for sub_schema in schema.get("allOf").and_then(Value::as_array).into_iter().flatten() {
+            self.check(sub_schema, value, at, violations);
+        }
+        if let Some(sub_schemas) = schema.get("anyOf").and_then(Value::as_array) {
+            if !sub_schemas.iter().any(|sub_schema| self.violations_at(sub_schema, value, at).is_empty()) {
+                violations.push(format!("{}: matches none of anyOf", location));
+            }
+        }
+
+        match value {
+            Value::Array(values) => {
+                if let Some(items) = schema.get("items") {
+                    for (index, item) in values.iter().enumerate() {
+                        self.check(items, item, &format!("{}/{}", at, index), violations);
+                    }
+                }
+            }
+            Value::Object(map) => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, property) in map {
+                    let at = format!("{}/{}", at, name.replace('~', "~0").replace('/', "~1"));
+                    match properties.and_then(|properties| properties.get(name)) {
+                        Some(property_schema) => self.check(property_schema, property, &at, violations),
+                        None => if let Some(additional) = schema.get("additionalProperties") {
+                            self.check(additional, property, &at, violations);
+                        },
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn compile_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) -> Result<()> {
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => bail!("a schema has to be an object or a boolean"),
+    };
+
+    if let Some(keyword) = schema.keys().find(|keyword| !KEYWORDS.contains(&keyword.as_str())
+        && !ANNOTATIONS.contains(&keyword.as_str()))
+    {
+        bail!("{} is not supported", keyword);
+    }
+
+    if let Some(pattern) = schema.get("pattern") {
+        let pattern = pattern.as_str().context("pattern has to be a string")?;
+        patterns.insert(pattern.to_string(), Regex::new(pattern).context("invalid pattern")?);
+    }
+    for keyword in &["items", "additionalProperties"] {
+        schema.get(*keyword).map(|sub_schema| compile_patterns(sub_schema, patterns)).transpose()?;
+    }
+    for property in schema.get("properties").and_then(Value::as_object).into_iter().flat_map(|map| map.values()) {
+        compile_patterns(property, patterns)?;
+    }
+    for keyword in &["allOf", "anyOf"] {
+        for sub_schema in schema.get(*keyword).and_then(Value::as_array).into_iter().flatten() {
+            compile_patterns(sub_schema, patterns)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("integer", Value::Number(number)) => number.is_i64() || number.is_u64()
+            || number.as_f64().is_some_and(|number| number.fract() == 0.0),
+        (name, value) => type_of(value) == name,
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::schema::Schema;
+
+    #[test]
+    fn reports_schema_violations() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id", "name"],
+            "properties": {
+                "id": { "type": "integer", "minimum": 1 },
+                "name": { "type": "string", "pattern": "^[a-z]+$" },
+                "tags": { "type": "array", "maxItems": 2, "items": { "enum": ["a", "b"] } },
+                "ratio": { "type": ["number", "null"] }
+            },
+            "additionalProperties": false
+        });
+        let schema = Schema::compile(&schema).expect("invalid schema");
+
+        let valid = json!({ "id": 3, "name": "octo", "tags": ["a"], "ratio": null });
+        assert!(schema.violations(&valid).is_empty(), "{:?}", schema.violations(&valid));
+
+        let invalid = json!({ "id": 0.5, "name": "Octo", "tags": ["a", "c", "b"], "x/y": 1 });
+        assert_eq!(schema.violations(&invalid), vec![
+            "/id: expected integer, got number",
+            "/id: 0.5 is less than 1",
+            r#"/name: "Octo" does not match ^[a-z]+$"#,
+            "/tags: 3 items are more than 2",
+            r#"/tags/1: "c" is not one of ["a","b"]"#,
+            "/x~1y: no value is allowed",
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        assert!(Schema::compile(&json!("string")).is_err());
+        assert!(Schema::compile(&json!({ "properties": { "name": { "pattern": "(" } } })).is_err());
+        assert!(Schema::compile(&json!({ "anyOf": [true, { "type": "string" }] })).is_ok());
+        assert!(Schema::compile(&json!({ "title": "user", "description": "a user", "type": "object" })).is_ok());
+
+        for unsupported in &["$ref", "oneOf", "not", "exclusiveMinimum", "format", "patternProperties"] {
+            let schema = json!({ "properties": { "id": { *unsupported: 1 } } });
+            assert!(Schema::compile(&schema).is_err(), "{} accepted", unsupported);
+        }
+        assert!(Schema::compile(&json!({ "items": [{ "type": "string" }] })).is_err(), "tuple items accepted");
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use hyper::Uri;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout_at, Duration, Instant};
+
+use crate::http_client::origin_of;
+use crate::rate_limit::{self, TokenBucket};
+use crate::target_policy::host_matches;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShapingConfig {
+    pub on_limit: OnLimit,
+    pub upstreams: Vec<UpstreamLimit>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnLimit {
+    #[default]
+    Wait, // as long as the batch deadline allows
+    FailFast,
+}
+
+// every origin matching the pattern gets limits of its own, the first matching entry applies
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamLimit {
+    pub origin: String, // glob, matched against scheme://host:port
+    pub requests_per_sec: Option<f64>,
+    pub burst: Option<f64>,
+    pub max_concurrency: Option<usize>,
+}
+
+impl ShapingConfig {
+    pub fn validate(&self) -> Result<()> {
+        for limit in &self.upstreams {
+            if let Some(rate) = limit.requests_per_sec {
+                rate_limit::validate(rate, limit.burst).with_context(|| format!("invalid limit of {}", limit.origin))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RateLimited {
+    #[error("rate limit of {0} exceeded")]
+    Rate(String),
+    #[error("concurrency limit of {0} reached")]
+    Concurrency(String),
+}
+
+struct OriginState {
+    bucket: Option<Mutex<TokenBucket>>,
+    slots: Option<Arc<Semaphore>>,
+}
+
+// frees the concurrency slot of the origin when dropped, so it has to be held until the response
+// has been read
+pub struct UpstreamPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+// shared by all batches, limits are enforced across them
+#[derive(Default)]
+pub struct UpstreamShaper {
+    config: ShapingConfig,
+    origins: Mutex<HashMap<String, Arc<OriginState>>>, // XXX never shrinks
+}
+
+impl UpstreamShaper {
+    pub fn new(config: ShapingConfig) -> Self {
+        UpstreamShaper {
+            config,
+            origins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn admit(&self, uri: &Uri, deadline: Instant) -> Result<UpstreamPermit, RateLimited> {
+        let (origin, state) = match self.state_for(uri) {
+            Some(found) => found,
+            None => return Ok(UpstreamPermit { _slot: None }),
+        };
+
+        // the slot is taken first, this way queued requests do not use up rate tokens
+        let slot = match &state.slots {
+            Some(slots) => Some(self.acquire_slot(slots, &origin, deadline).await?),
+            None => None,
+        };
+
+        if let Some(bucket) = &state.bucket {
+            let wait = {
+                let mut bucket = bucket.lock().expect("token bucket poisoned");
+
+                match self.config.on_limit {
+                    OnLimit::FailFast => {
+                        bucket.try_acquire(1.0).map_err(|_| RateLimited::Rate(origin.clone()))?;
+                        Duration::from_secs(0)
+                    }
+                    OnLimit::Wait => {
+                        let ready = Instant::now().checked_add(bucket.wait_time(1.0));
+                        if !matches!(ready, Some(ready) if ready <= deadline) {
+                            return Err(RateLimited::Rate(origin));
+                        }
+
+                        bucket.acquire_ahead(1.0)
+                    }
+                }
+            };
+
+            sleep(wait).await;
+        }
+
+        Ok(UpstreamPermit { _slot: slot })
+    }
+
+    async fn acquire_slot(&self, slots: &Arc<Semaphore>, origin: &str, deadline: Instant)
+                          -> Result<OwnedSemaphorePermit, RateLimited>
+    {
+        let slot = match self.config.on_limit {
+            OnLimit::FailFast => slots.clone().try_acquire_owned().ok(),
+            OnLimit::Wait => timeout_at(deadline, slots.clone().acquire_owned()).await
+                .ok()
+                .and_then(Result::ok),
+        };
+
+        slot.ok_or_else(|| RateLimited::Concurrency(origin.to_string()))
+    }
+
+    fn state_for(&self, uri: &Uri) -> Option<(String, Arc<OriginState>)> {
+        let origin = origin_of(uri)?;
+        let limit = self.config.upstreams.iter()
+            .find(|limit| host_matches(&limit.origin, &origin))?;
+
+        let mut origins = self.origins.lock().expect("origin states poisoned");
+        let state = origins.entry(origin.clone())
+            .or_insert_with(|| Arc::new(OriginState {
+                bucket: limit.requests_per_sec
+                    .map(|rate| Mutex::new(TokenBucket::new(rate, limit.burst.unwrap_or(rate)))),
+                slots: limit.max_concurrency.map(|max| Arc::new(Semaphore::new(max))),
+            }))
+            .clone();
+
+        Some((origin, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Uri;
+    use tokio::time::{pause, Duration, Instant};
+
+    use crate::shaping::{OnLimit, RateLimited, ShapingConfig, UpstreamLimit, UpstreamShaper};
+
+    fn shaper(on_limit: OnLimit) -> UpstreamShaper {
+        UpstreamShaper::new(ShapingConfig {
+            on_limit,
+            upstreams: vec![UpstreamLimit {
+                origin: "https://*.example.com:443".to_string(),
+                requests_per_sec: Some(10.0),
+                burst: Some(2.0),
+                max_concurrency: Some(1),
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn fails_fast_per_origin() {
+        pause();
+        let shaper = shaper(OnLimit::FailFast);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let api = Uri::from_static("https://api.example.com/a");
+
+        let permit = shaper.admit(&api, deadline).await.expect("first request limited");
+        assert!(matches!(shaper.admit(&api, deadline).await, Err(RateLimited::Concurrency(_))));
+        assert!(shaper.admit(&Uri::from_static("https://cdn.example.com/"), deadline).await.is_ok());
+        assert!(shaper.admit(&Uri::from_static("http://api.example.com/"), deadline).await.is_ok());
+
+        drop(permit);
+        assert!(shaper.admit(&api, deadline).await.is_ok());
+        assert!(matches!(shaper.admit(&api, deadline).await, Err(RateLimited::Rate(_))));
+    }
+
+    #[tokio::test]
+    async fn waits_within_deadline() {
+        pause();
+        let shaper = shaper(OnLimit::Wait);
+        let start = Instant::now();
+        let api = Uri::from_static("https://api.example.com/a");
+
+        for _ in 0..3 {
+            shaper.admit(&api, start + Duration::from_secs(1)).await.expect("request limited");
+        }
+        let waited = Instant::now() - start;
+        assert!(waited >= Duration::from_millis(100) && waited < Duration::from_millis(200));
+
+        let permit = shaper.admit(&api, start + Duration::from_secs(1)).await;
+        assert!(permit.is_ok());
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(matches!(shaper.admit(&api, deadline).await, Err(RateLimited::Concurrency(_))));
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::Error as AnyError;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use ipnet::IpNet;
+use thiserror::Error;
+use tokio::net::{lookup_host, TcpStream};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetPolicyConfig {
+    pub block_private_ips: bool, // on unless turned off explicitly
+    pub allow: Vec<TargetRule>, // if not empty, targets have to match at least one rule
+    pub deny: Vec<TargetRule>,
+}
+
+impl Default for TargetPolicyConfig {
+    fn default() -> Self {
+        TargetPolicyConfig {
+            block_private_ips: true,
+            allow: vec![],
+            deny: vec![],
+        }
+    }
+}
+
+// every criterion that is not empty has to match for the rule to match
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetRule {
+    pub schemes: Vec<String>,
+    pub hosts: Vec<String>, // globs, for example "*.example.com"
+    pub ports: Vec<u16>,
+    pub cidrs: Vec<IpNet>,
+}
+
+#[derive(Error, Debug)]
+#[error("target {target} is blocked: {reason}")]
+pub struct TargetBlocked {
+    target: String,
+    reason: &'static str,
+}
+
+impl TargetBlocked {
+    // the connector error is nested several levels deep inside the client error
+    pub fn is_cause_of(error: &AnyError) -> bool {
+        error.chain().any(|cause| cause.is::<TargetBlocked>())
+    }
+}
+
+#[derive(Debug)]
+pub struct Target<'a> {
+    pub scheme: &'a str,
+    pub host: &'a str,
+    pub port: u16,
+}
+
+impl<'a> Target<'a> {
+    fn of(uri: &'a Uri) -> Option<Self> {
+        let scheme = uri.scheme_str().unwrap_or("http");
+        let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16()
+            .unwrap_or(if scheme.eq_ignore_ascii_case("https") { 443 } else { 80 });
+
+        Some(Target { scheme, host, port })
+    }
+
+    fn origin(&self) -> (String, String, u16) {
+        (self.scheme.to_ascii_lowercase(), self.host.to_ascii_lowercase(), self.port)
+    }
+}
+
+pub struct TargetPolicy {
+    config: TargetPolicyConfig,
+    trusted: HashSet<(String, String, u16)>, // origins of upstream members, by scheme, host and port
+}
+
+impl TargetPolicy {
+    pub fn new(config: TargetPolicyConfig) -> Self {
+        TargetPolicy {
+            config,
+            trusted: HashSet::new(),
+        }
+    }
+
+    // the operator configured these, so they may be private addresses; the rules still apply to them
+    pub fn trusting(mut self, uris: &[Uri]) -> Self {
+        self.trusted.extend(uris.iter().filter_map(Target::of).map(|target| target.origin()));
+        self
+    }
+
+    pub fn check(&self, target: &Target, ip: IpAddr) -> Result<(), TargetBlocked> {
+        let blocked = |reason| Err(TargetBlocked {
+            target: format!("{}://{}:{} ({})", target.scheme, target.host, target.port, ip),
+            reason,
+        });
+
+        if self.config.deny.iter().any(|rule| rule.matches(target, ip)) {
+            return blocked("denied by rule");
+        }
+
+        let allowing_rule = self.config.allow.iter().find(|rule| rule.matches(target, ip));
+        if !self.config.allow.is_empty() && allowing_rule.is_none() {
+            return blocked("not allowed by any rule");
+        }
+
+        // a private address can only be opened up explicitly, by allowing its network
+        let explicitly_allowed = matches!(allowing_rule, Some(rule) if !rule.cidrs.is_empty());
+        let trusted = self.trusted.contains(&target.origin());
+        if self.config.block_private_ips && is_private(ip) && !explicitly_allowed && !trusted {
+            return blocked("private address");
+        }
+
+        Ok(())
+    }
+}
+
+impl TargetRule {
+    fn matches(&self, target: &Target, ip: IpAddr) -> bool {
+        (self.schemes.is_empty() || self.schemes.iter().any(|s| s.eq_ignore_ascii_case(target.scheme)))
+            && (self.hosts.is_empty() || self.hosts.iter().any(|h| host_matches(h, target.host)))
+            && (self.ports.is_empty() || self.ports.contains(&target.port))
+            && (self.cidrs.is_empty() || self.cidrs.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+// case-insensitive glob match, where '*' matches any (possibly empty) sequence of characters
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match host.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(), // no wildcard at all
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => // IPv4-mapped
+                is_private_v4(Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8)),
+            _ => is_private_v6(ip),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    let this_network = first == 0; // 0.0.0.0/8
+    let shared = first == 100 && (second & 0b1100_0000) == 64; // 100.64.0.0/10
+    let benchmarking = first == 198 && (second & 0b1111_1110) == 18; // 198.18.0.0/15
+    let reserved = first >= 240; // 240.0.0.0/4, including broadcast
+
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || this_network || shared || benchmarking
+        || ip.is_multicast() || reserved || ip.is_documentation()
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00; // fc00::/7
+    let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80; // fe80::/10
+    let nat64 = ip.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0]; // 64:ff9b::/96, may translate to any IPv4
+
+    ip.is_loopback() || ip.is_unspecified() || unique_local || link_local || nat64
+}
+
+// connects only to addresses the policy allows, checked after DNS resolution; the resolved address
+// is what is connected to, so a second lookup (DNS rebinding) cannot sneak in a different address
+#[derive(Clone)]
+pub struct GuardedConnector {
+    inner: HttpConnector,
+    policy: Arc<TargetPolicy>,
+}
+
+impl GuardedConnector {
+    pub fn new(inner: HttpConnector, policy: TargetPolicy) -> Self {
+        GuardedConnector {
+            inner,
+            policy: Arc::new(policy),
+        }
+    }
+}
+
+impl Service<Uri> for GuardedConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let policy = self.policy.clone();
+
+        Box::pin(async move {
+            let target = Target::of(&uri).ok_or("target URI has no host")?;
+
+            let mut last_error: Option<BoxError> = None;
+            for addr in lookup_host((target.host, target.port)).await? {
+                if let Err(error) = policy.check(&target, addr.ip()) {
+                    last_error = Some(error.into());
+                    continue;
+                }
+
+                match inner.call(address_uri(addr)?).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(error) => last_error = Some(error.into()),
+                }
+            }
+
+            Err(last_error.unwrap_or_else(|| format!("no address found for {}", target.host).into()))
+        })
+    }
+}
+
+fn address_uri(addr: SocketAddr) -> Result<Uri, BoxError> {
+    Ok(format!("http://{}", addr).parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use hyper::{Body, Request, Uri};
+
+    use crate::http_client::{make_hyper_client, HttpClient};
+    use crate::target_policy::{host_matches, Target, TargetBlocked, TargetPolicy, TargetPolicyConfig,
+                               TargetRule};
+
+    fn target(host: &str) -> Target<'_> {
+        Target {
+            scheme: "https",
+            host,
+            port: 443,
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_host_globs() {
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "A.B.Example.COM"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(host_matches("api-*.example.*", "api-eu.example.org"));
+        assert!(host_matches("localhost", "localhost"));
+        assert!(!host_matches("localhost", "localhost.evil.com"));
+    }
+
+    #[test]
+    fn blocks_private_addresses() {
+        let policy = TargetPolicy::new(TargetPolicyConfig::default());
+
+        for private in &["169.254.169.254", "127.0.0.1", "::1", "::ffff:10.0.0.1", "0.1.2.3", "198.19.0.1",
+                         "224.0.0.251", "239.255.255.250", "240.0.0.1", "255.255.255.255",
+                         "64:ff9b::a9fe:a9fe"] {
+            assert!(policy.check(&target("internal"), ip(private)).is_err(), "{} not blocked", private);
+        }
+        assert!(policy.check(&target("example.com"), ip("93.184.216.34")).is_ok());
+        assert!(policy.check(&target("example.com"), ip("198.20.0.1")).is_ok());
+
+        let open = TargetPolicy::new(TargetPolicyConfig { block_private_ips: false, ..Default::default() });
+        assert!(open.check(&target("localhost"), ip("127.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn trusts_upstream_members() {
+        let policy = TargetPolicy::new(TargetPolicyConfig {
+            deny: vec![TargetRule { ports: vec![8443], ..Default::default() }],
+            ..Default::default()
+        });
+        let policy = policy.trusting(&[Uri::from_static("https://Billing-1.internal"),
+                                       Uri::from_static("https://billing-2.internal:8443")]);
+
+        assert!(policy.check(&target("billing-1.internal"), ip("10.0.0.1")).is_ok());
+        assert!(policy.check(&Target { scheme: "http", ..target("billing-1.internal") }, ip("10.0.0.1")).is_err());
+        assert!(policy.check(&target("billing-3.internal"), ip("10.0.0.3")).is_err());
+        assert!(policy.check(&Target { port: 8443, ..target("billing-2.internal") }, ip("10.0.0.2")).is_err(),
+                "rules still apply");
+    }
+
+    #[test]
+    fn applies_allow_and_deny_rules() {
+        let policy = TargetPolicy::new(TargetPolicyConfig {
+            block_private_ips: true,
+            allow: vec![
+                TargetRule {
+                    hosts: vec!["*.example.com".to_string()],
+                    schemes: vec!["https".to_string()],
+                    ..Default::default()
+                },
+                TargetRule {
+                    cidrs: vec!["10.1.0.0/16".parse().unwrap()],
+                    ..Default::default()
+                },
+            ],
+            deny: vec![
+                TargetRule {
+                    hosts: vec!["admin.example.com".to_string()],
+                    ..Default::default()
+                },
+            ],
+        });
+
+        assert!(policy.check(&target("api.example.com"), ip("93.184.216.34")).is_ok());
+        assert!(policy.check(&target("admin.example.com"), ip("93.184.216.34")).is_err());
+        assert!(policy.check(&target("example.org"), ip("93.184.216.34")).is_err());
+        assert!(policy.check(&target("internal.example.com"), ip("10.2.0.1")).is_err());
+        assert!(policy.check(&target("billing"), ip("10.1.2.3")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn connector_rejects_blocked_target() {
+        let client = make_hyper_client(TargetPolicy::new(TargetPolicyConfig {
+            block_private_ips: true,
+            ..Default::default()
+        })).unwrap();
+
+        let req = Request::get("http://127.0.0.1:9/").body(Body::empty()).unwrap();
+        let error = client.request(req).await.expect_err("expected a blocked request");
+
+        assert!(TargetBlocked::is_cause_of(&error), "unexpected error {:?}", error);
+    }
+}
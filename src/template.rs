@@ -0,0 +1,179 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use url::Url;
+
+use crate::api::{RequestTemplate, SingleHttpRequest};
+
+// fills in everything a request leaves unspecified from the batch template
+pub fn expand_request(template: &RequestTemplate, req: SingleHttpRequest) -> Result<SingleHttpRequest> {
+    let SingleHttpRequest {
+        id, depends_on, method, uri, headers, body, body_patch, json, form, multipart, query, parse_json, extract, expect,
+        paginate, follow_redirects
+    } = req;
+
+    let uri = match &template.uri_base {
+        Some(base) => Url::parse(base)
+            .with_context(|| format!("invalid template uri_base {}", base))?
+            .join(&uri)
+            .with_context(|| format!("cannot resolve {} against {}", uri, base))?
+            .to_string(),
+        None => uri,
+    };
+
+    // header names are case-insensitive, an override has to replace the template header
+    let mut merged_headers = template.headers.clone();
+    for (name, value) in headers {
+        merged_headers.retain(|n, _| !n.eq_ignore_ascii_case(&name));
+        merged_headers.insert(name, value);
+    }
+
+    let given = [body.is_some(), body_patch.is_some(), json.is_some(), form.is_some(), multipart.is_some()];
+    if given.iter().filter(|given| **given).count() > 1 {
+        bail!("body, body_patch, json, form and multipart are mutually exclusive");
+    }
+
+    // a structured body replaces the template body as well
+    let body = match (body, body_patch) {
+        (Some(body), None) => Some(body),
+        (None, Some(patch)) => Some(patch_body(template.body.as_deref(), &patch)?),
+        _ if json.is_some() || form.is_some() || multipart.is_some() => None,
+        _ => template.body.clone(),
+    };
+
+    Ok(SingleHttpRequest {
+        id,
+        depends_on,
+        method: method.or(template.method),
+        uri,
+        headers: merged_headers,
+        body,
+        body_patch: None,
+        json,
+        form,
+        multipart,
+        query,
+        parse_json,
+        extract,
+        expect,
+        paginate,
+        follow_redirects,
+    })
+}
+
+fn patch_body(body: Option<&str>, patch: &Value) -> Result<String> {
+    let mut target = match body {
+        Some(body) => serde_json::from_str(body).context("template body is not valid JSON")?,
+        None => Value::Null,
+    };
+    merge_patch(&mut target, patch);
+
+    serde_json::to_string(&target).context("cannot serialize")
+}
+
+// JSON merge patch as defined by RFC 7386
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().expect("target must be an object");
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::api::{HttpMethod, RequestTemplate, SingleHttpRequest};
+    use crate::template::{expand_request, merge_patch};
+
+    fn bid_template() -> RequestTemplate {
+        RequestTemplate {
+            method: Some(HttpMethod::POST),
+            uri_base: Some("https://bidder.example.com/rtb/".to_string()),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())]
+                .into_iter().collect(),
+            body: Some(r#"{"id":"auction-1","imp":[{"id":"1"}],"tmax":120}"#.to_string()),
+        }
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc7386() {
+        let mut target = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        merge_patch(&mut target, &json!({"a": "z", "c": {"f": null}}));
+
+        assert_eq!(target, json!({"a": "z", "c": {"d": "e"}}));
+
+        let mut target = json!(["a", "b"]);
+        merge_patch(&mut target, &json!({"a": "c"}));
+
+        assert_eq!(target, json!({"a": "c"}));
+    }
+
+    #[test]
+    fn structured_body_replaces_template_body() {
+        let req = SingleHttpRequest {
+            uri: "bid".to_string(),
+            json: Some(json!({"id": "auction-2"})),
+            ..Default::default()
+        };
+
+        let req = expand_request(&bid_template(), req).unwrap();
+        assert_eq!(req.body, None);
+        assert_eq!(req.json, Some(json!({"id": "auction-2"})));
+
+        let req = SingleHttpRequest {
+            uri: "bid".to_string(),
+            body_patch: Some(json!({"tmax": 80})),
+            form: Some(Default::default()),
+            ..Default::default()
+        };
+        assert!(expand_request(&bid_template(), req).is_err());
+    }
+
+    #[test]
+    fn expands_request_from_template() {
+        let req = SingleHttpRequest {
+            uri: "bid?dsp=7".to_string(),
+            headers: vec![("content-type".to_string(), "application/x-json".to_string())]
+                .into_iter().collect(),
+            body_patch: Some(json!({"tmax": 80})),
+            ..Default::default()
+        };
+
+        let req = expand_request(&bid_template(), req).unwrap();
+
+        assert_eq!(req.method.unwrap().as_ref(), "POST");
+        assert_eq!(req.uri, "https://bidder.example.com/rtb/bid?dsp=7");
+        assert_eq!(req.headers.len(), 1);
+        assert_eq!(req.headers["content-type"], "application/x-json");
+        let body: serde_json::Value = serde_json::from_str(req.body.as_ref().unwrap()).unwrap();
+        assert_eq!(body, json!({"id": "auction-1", "imp": [{"id": "1"}], "tmax": 80}));
+    }
+
+    #[test]
+    fn rejects_body_with_body_patch() {
+        let req = SingleHttpRequest {
+            uri: "bid".to_string(),
+            body: Some("{}".to_string()),
+            body_patch: Some(json!({"tmax": 80})),
+            ..Default::default()
+        };
+
+        assert!(expand_request(&bid_template(), req).is_err());
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::join_all;
+use hyper::{Body, Request, Uri};
+use hyper::body::to_bytes;
+use tokio::time::{interval, timeout, Duration, Instant};
+
+use crate::api::{MemberHealth, SingleHttpRequest, UpstreamHealth};
+use crate::http_client::HttpClient;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub members: Vec<MemberConfig>,
+    #[serde(default)]
+    pub balancing: Balancing,
+    #[serde(default)]
+    pub hash_header: Option<String>, // for consistent hashing, the path is hashed without it
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemberConfig {
+    pub uri: String, // scheme://host[:port], request paths are appended
+    #[serde(default = "default_weight")]
+    pub weight: u32, // 0 takes the member out of selection
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balancing {
+    #[default]
+    RoundRobin,
+    LeastLoaded,
+    ConsistentHash,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_expected_status")]
+    pub expected_status: u16,
+    #[serde(default = "default_max_latency_msec")]
+    pub max_latency_msec: u64, // slower responses count as failed probes
+    #[serde(default = "default_interval_sec")]
+    pub interval_sec: u64,
+    #[serde(default = "default_threshold")]
+    pub unhealthy_threshold: u32, // consecutive failed probes until a member is ejected
+    #[serde(default = "default_threshold")]
+    pub healthy_threshold: u32, // consecutive successful probes until it is selected again
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+// the uris of all members, as far as they parse; the pools reject the others
+pub fn member_uris(config: &HashMap<String, UpstreamConfig>) -> Vec<Uri> {
+    config.values()
+        .flat_map(|upstream| &upstream.members)
+        .filter_map(|member| member.uri.parse().ok())
+        .collect()
+}
+
+fn default_path() -> String {
+    "/healthz".to_string()
+}
+
+fn default_expected_status() -> u16 {
+    200
+}
+
+fn default_max_latency_msec() -> u64 {
+    1_000
+}
+
+fn default_interval_sec() -> u64 {
+    10
+}
+
+fn default_threshold() -> u32 {
+    2
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    ejected: bool,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+    latency: Option<Duration>,
+    error: Option<String>,
+}
+
+struct Member {
+    uri: String,
+    weight: u32,
+    in_flight: AtomicUsize,
+    health: Mutex<HealthState>,
+}
+
+impl Member {
+    fn is_ejected(&self) -> bool {
+        self.health.lock().expect("member health poisoned").ejected
+    }
+}
+
+struct Pool {
+    members: Vec<Member>,
+    balancing: Balancing,
+    hash_header: Option<String>,
+    health_check: Option<HealthCheckConfig>,
+    next: AtomicUsize, // round robin position
+}
+
+// where an upstream:// request goes, before a member has been picked
+#[derive(Debug)]
+pub struct UpstreamTarget {
+    pub pool: String,
+    path: String,
+    hash_key: u64,
+}
+
+// counts as load on the member until dropped
+pub struct MemberLease<'a> {
+    pub index: usize,
+    member: &'a Member,
+}
+
+impl MemberLease<'_> {
+    pub fn uri_for(&self, target: &UpstreamTarget) -> String {
+        format!("{}{}", self.member.uri, target.path)
+    }
+}
+
+impl Drop for MemberLease<'_> {
+    fn drop(&mut self) {
+        self.member.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+const UPSTREAM_SCHEME: &str = "upstream";
+
+#[derive(Default)]
+pub struct UpstreamPools {
+    pools: BTreeMap<String, Pool>, // by name
+}
+
+impl UpstreamPools {
+    pub fn new(config: HashMap<String, UpstreamConfig>) -> Result<Self> {
+        let mut pools = BTreeMap::new();
+
+        for (name, upstream) in config {
+            if upstream.members.is_empty() {
+                bail!("upstream {} has no members", name);
+            }
+
+            let members = upstream.members.into_iter()
+                .map(|member| {
+                    let uri: Uri = member.uri.parse()
+                        .with_context(|| format!("invalid member uri {} of upstream {}", member.uri, name))?;
+                    if uri.scheme().is_none() || uri.host().is_none() {
+                        bail!("member uri {} of upstream {} is not absolute", member.uri, name);
+                    }
+
+                    Ok(Member {
+                        uri: member.uri.trim_end_matches('/').to_string(),
+                        weight: member.weight,
+                        in_flight: AtomicUsize::new(0),
+                        health: Mutex::new(HealthState::default()),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            pools.insert(name, Pool {
+                members,
+                balancing: upstream.balancing,
+                hash_header: upstream.hash_header,
+                health_check: upstream.health_check,
+                next: AtomicUsize::new(0),
+            });
+        }
+
+        Ok(UpstreamPools { pools })
+    }
+
+    // None for requests which do not target an upstream
+    pub fn target_of(&self, req: &SingleHttpRequest) -> Result<Option<UpstreamTarget>> {
+        let uri: Uri = match req.uri.parse() {
+            Ok(uri) => uri,
+            Err(_) => return Ok(None), // left to the request validation
+        };
+        if uri.scheme_str() != Some(UPSTREAM_SCHEME) {
+            return Ok(None);
+        }
+
+        let name = uri.host().unwrap_or_default();
+        let pool = self.pools.get(name)
+            .ok_or_else(|| anyhow!("unknown upstream {}", name))?;
+        let path = uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+
+        let hash_value = pool.hash_header.as_ref()
+            .and_then(|header| req.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case(header)))
+            .map(|(_, value)| value.as_str())
+            .unwrap_or(path);
+        let mut hasher = DefaultHasher::new();
+        hash_value.hash(&mut hasher);
+
+        Ok(Some(UpstreamTarget {
+            pool: name.to_string(),
+            path: path.to_string(),
+            hash_key: hasher.finish(),
+        }))
+    }
+
+    // healthy members are preferred, ejected ones are only picked when no healthy one is left
+    pub fn select(&self, target: &UpstreamTarget, tried: &[usize]) -> Option<MemberLease<'_>> {
+        let pool = self.pools.get(&target.pool)?;
+        let untried = pool.members.iter().enumerate()
+            .filter(|(index, member)| member.weight > 0 && !tried.contains(index))
+            .collect::<Vec<_>>();
+        let healthy = untried.iter()
+            .filter(|(_, member)| !member.is_ejected())
+            .cloned()
+            .collect::<Vec<_>>();
+        let candidates = match healthy.is_empty() {
+            true => untried,
+            false => healthy,
+        };
+
+        let (index, member) = match pool.balancing {
+            Balancing::RoundRobin => {
+                let total_weight = candidates.iter().map(|(_, member)| member.weight as usize).sum::<usize>();
+                let mut position = pool.next.fetch_add(1, Ordering::Relaxed) % total_weight.max(1);
+
+                candidates.into_iter().find(|(_, member)| {
+                    let weight = member.weight as usize;
+                    match position < weight {
+                        true => true,
+                        false => {
+                            position -= weight;
+                            false
+                        }
+                    }
+                })
+            }
+            Balancing::LeastLoaded => candidates.into_iter()
+                .min_by(|(_, a), (_, b)| {
+                    let load = |member: &Member| member.in_flight.load(Ordering::Relaxed) as f64 / f64::from(member.weight);
+                    load(a).partial_cmp(&load(b)).unwrap_or(std::cmp::Ordering::Equal)
+                }),
+            Balancing::ConsistentHash => candidates.into_iter()
+                .max_by(|(_, a), (_, b)| {
+                    let score_a = rendezvous_score(target.hash_key, a);
+                    let score_b = rendezvous_score(target.hash_key, b);
+                    score_a.partial_cmp(&score_b).unwrap_or(std::cmp::Ordering::Equal)
+                }),
+        }?;
+
+        member.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        Some(MemberLease { index, member })
+    }
+
+    pub fn health(&self) -> Vec<UpstreamHealth> {
+        self.pools.iter()
+            .map(|(name, pool)| UpstreamHealth {
+                name: name.clone(),
+                members: pool.members.iter()
+                    .map(|member| {
+                        let health = member.health.lock().expect("member health poisoned");
+
+                        MemberHealth {
+                            uri: member.uri.clone(),
+                            healthy: !health.ejected,
+                            in_flight: member.in_flight.load(Ordering::Relaxed),
+                            consecutive_failures: health.consecutive_failures,
+                            latency_msec: health.latency,
+                            error: health.error.clone(),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // one task per pool with a health check, for as long as the process runs
+    pub fn spawn_health_checks<C>(self: &Arc<Self>, client: C)
+        where C: HttpClient + Clone + Send + Sync + 'static
+    {
+        for (name, pool) in &self.pools {
+            let check_interval = match &pool.health_check {
+                Some(check) => Duration::from_secs(check.interval_sec.max(1)),
+                None => continue,
+            };
+            let pools = self.clone();
+            let client = client.clone();
+            let name = name.clone();
+
+            tokio::spawn(async move {
+                let mut ticks = interval(check_interval);
+
+                loop {
+                    ticks.tick().await;
+                    pools.check_pool(&name, &client).await;
+                }
+            });
+        }
+    }
+
+    async fn check_pool<C: HttpClient>(&self, name: &str, client: &C) {
+        let (pool, check) = match self.pools.get(name) {
+            Some(pool) => match &pool.health_check {
+                Some(check) => (pool, check),
+                None => return,
+            },
+            None => return,
+        };
+
+        let probes = pool.members.iter()
+            .map(|member| async move {
+                let outcome = probe(client, &format!("{}{}", member.uri, check.path), check).await;
+                record_probe(name, member, check, outcome);
+            });
+
+        join_all(probes).await;
+    }
+}
+
+// weighted rendezvous hashing, only the keys of a member which is gone move to other members
+fn rendezvous_score(hash_key: u64, member: &Member) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    hash_key.hash(&mut hasher);
+    member.uri.hash(&mut hasher);
+    let unit = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64; // [0, 1)
+
+    f64::from(member.weight) / -(1.0 - unit).ln()
+}
+
+async fn probe<C: HttpClient>(client: &C, uri: &str, check: &HealthCheckConfig) -> Result<Duration> {
+    let req = Request::get(uri).body(Body::empty()).context("cannot build probe")?;
+    let max_latency = Duration::from_millis(check.max_latency_msec);
+    let start_time = Instant::now();
+
+    let status = timeout(max_latency, async {
+        let resp = client.request(req).await?;
+        let status = resp.status();
+        to_bytes(resp.into_body()).await?;
+
+        Ok::<_, anyhow::Error>(status)
+    }).await
+        .map_err(|_| anyhow!("no response within {}ms", check.max_latency_msec))??;
+
+    if status.as_u16() != check.expected_status {
+        bail!("unexpected status {}", status);
+    }
+
+    Ok(start_time.elapsed())
+}
+
+fn record_probe(pool: &str, member: &Member, check: &HealthCheckConfig, outcome: Result<Duration>) {
+    let mut health = member.health.lock().expect("member health poisoned");
+
+    match outcome {
+        Ok(latency) => {
+            health.consecutive_failures = 0;
+            health.consecutive_successes += 1;
+            health.latency = Some(latency);
+            health.error = None;
+
+            if health.ejected && health.consecutive_successes >= check.healthy_threshold {
+                health.ejected = false;
+                info!("member {} of upstream {} recovered", member.uri, pool);
+            }
+        }
+        Err(error) => {
+            health.consecutive_successes = 0;
+            health.consecutive_failures += 1;
+            health.latency = None;
+            health.error = Some(error.to_string());
+
+            if !health.ejected && health.consecutive_failures >= check.unhealthy_threshold {
+                health.ejected = true;
+                warn!("member {} of upstream {} ejected: {}", member.uri, pool, error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, Result};
+    use hyper::{Body, Response};
+
+    use crate::api::SingleHttpRequest;
+    use crate::http_client::tests::MockHttpClient;
+    use crate::upstream::{Balancing, HealthCheckConfig, MemberConfig, UpstreamConfig, UpstreamPools, UpstreamTarget};
+
+    fn status_response(status: u16) -> Result<Response<Body>> {
+        Response::builder()
+            .status(status)
+            .body(Body::from("ok"))
+            .context("cannot build response")
+    }
+
+    fn member(uri: &str) -> MemberConfig {
+        MemberConfig {
+            uri: uri.to_string(),
+            weight: 1,
+        }
+    }
+
+    fn pools(balancing: Balancing, members: Vec<MemberConfig>) -> UpstreamPools {
+        let config = UpstreamConfig {
+            members,
+            balancing,
+            hash_header: Some("X-Customer".to_string()),
+            health_check: None,
+        };
+
+        UpstreamPools::new(vec![("billing".to_string(), config)].into_iter().collect())
+            .expect("invalid config")
+    }
+
+    fn target(pools: &UpstreamPools, uri: &str, customer: &str) -> UpstreamTarget {
+        let req = SingleHttpRequest {
+            uri: uri.to_string(),
+            headers: vec![("x-customer".to_string(), customer.to_string())].into_iter().collect(),
+            ..Default::default()
+        };
+
+        pools.target_of(&req).expect("invalid target").expect("no upstream target")
+    }
+
+    #[test]
+    fn resolves_upstream_targets() {
+        let pools = pools(Balancing::RoundRobin, vec![member("https://billing-1.internal:8443/")]);
+
+        let target = target(&pools, "upstream://billing/v1/price?currency=EUR", "acme");
+        let lease = pools.select(&target, &[]).expect("no member selected");
+        assert_eq!(lease.uri_for(&target), "https://billing-1.internal:8443/v1/price?currency=EUR");
+
+        let req = SingleHttpRequest { uri: "https://billing.example.com/".to_string(), ..Default::default() };
+        assert!(pools.target_of(&req).unwrap().is_none());
+        let req = SingleHttpRequest { uri: "upstream://shipping/".to_string(), ..Default::default() };
+        assert!(pools.target_of(&req).is_err());
+    }
+
+    #[test]
+    fn balances_by_weight_and_load() {
+        let members = vec![
+            MemberConfig { uri: "https://billing-1.internal".to_string(), weight: 2 },
+            member("https://billing-2.internal"),
+            MemberConfig { uri: "https://billing-3.internal".to_string(), weight: 0 },
+        ];
+
+        let pools = pools(Balancing::RoundRobin, members.clone());
+        let target = target(&pools, "upstream://billing/", "acme");
+        let picks = (0..6)
+            .map(|_| pools.select(&target, &[]).unwrap().index)
+            .collect::<Vec<_>>();
+        assert_eq!(picks, vec![0, 0, 1, 0, 0, 1]);
+        assert_eq!(pools.select(&target, &[0]).unwrap().index, 1, "tried members are skipped");
+        assert!(pools.select(&target, &[0, 1]).is_none());
+
+        let pools = self::pools(Balancing::LeastLoaded, members);
+        let first = pools.select(&target, &[]).unwrap();
+        let second = pools.select(&target, &[]).unwrap();
+        let third = pools.select(&target, &[]).unwrap();
+        assert_eq!((first.index, second.index, third.index), (0, 1, 0));
+        drop(second);
+        assert_eq!(pools.select(&target, &[]).unwrap().index, 1);
+    }
+
+    #[test]
+    fn hashes_consistently() {
+        let members = (1..=5)
+            .map(|n| member(&format!("https://billing-{}.internal", n)))
+            .collect::<Vec<_>>();
+        let pools = pools(Balancing::ConsistentHash, members);
+
+        let picks = (0..50)
+            .map(|n| {
+                let target = target(&pools, "upstream://billing/", &format!("customer-{}", n));
+                let first = pools.select(&target, &[]).unwrap().index;
+                assert_eq!(pools.select(&target, &[]).unwrap().index, first);
+                first
+            })
+            .collect::<Vec<_>>();
+        assert!(picks.iter().any(|index| *index != picks[0]), "keys are spread over the members");
+
+        // without member 0 only its keys move
+        for (n, pick) in picks.iter().enumerate() {
+            let target = target(&pools, "upstream://billing/", &format!("customer-{}", n));
+            let repick = pools.select(&target, &[0]).unwrap().index;
+            assert!(*pick == 0 || repick == *pick);
+        }
+    }
+
+    #[tokio::test]
+    async fn ejects_and_readmits_members() {
+        let mut client = MockHttpClient::new();
+        let mut down = true;
+        client.expect_request().returning(move |req| {
+            assert_eq!(req.uri().path(), "/status");
+
+            match req.uri().host().unwrap() {
+                "billing-2.internal" if down => {
+                    down = false;
+                    status_response(503)
+                }
+                _ => status_response(200),
+            }
+        });
+
+        let config = UpstreamConfig {
+            members: vec![member("https://billing-1.internal/"), member("https://billing-2.internal")],
+            balancing: Balancing::RoundRobin,
+            hash_header: None,
+            health_check: Some(HealthCheckConfig {
+                path: "/status".to_string(),
+                expected_status: 200,
+                max_latency_msec: 1_000,
+                interval_sec: 10,
+                unhealthy_threshold: 1,
+                healthy_threshold: 1,
+            }),
+        };
+        let pools = UpstreamPools::new(vec![("billing".to_string(), config)].into_iter().collect())
+            .expect("invalid config");
+
+        pools.check_pool("billing", &client).await;
+        let health = pools.health();
+        assert_eq!(health[0].members[0].uri, "https://billing-1.internal");
+        assert!(health[0].members[0].healthy);
+        assert!(!health[0].members[1].healthy);
+        assert_eq!(health[0].members[1].error.as_deref(), Some("unexpected status 503 Service Unavailable"));
+
+        pools.check_pool("billing", &client).await;
+        assert!(pools.health()[0].members.iter().all(|member| member.healthy));
+    }
+
+    #[tokio::test]
+    async fn ejects_slow_members() {
+        let mut client = MockHttpClient::new();
+        client.expect_request().returning(|_req| status_response(200));
+
+        let config = UpstreamConfig {
+            members: vec![member("https://billing-1.internal")],
+            balancing: Balancing::RoundRobin,
+            hash_header: None,
+            health_check: Some(HealthCheckConfig {
+                path: "/".to_string(),
+                expected_status: 200,
+                max_latency_msec: 10,
+                interval_sec: 10,
+                unhealthy_threshold: 1,
+                healthy_threshold: 1,
+            }),
+        };
+        let pools = UpstreamPools::new(vec![("billing".to_string(), config)].into_iter().collect())
+            .expect("invalid config");
+
+        pools.check_pool("billing", &client).await;
+        assert!(!pools.health()[0].members[0].healthy);
+    }
+}